@@ -0,0 +1,290 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use gtk::{
+    ApplicationWindow,
+    Button,
+    CellLayoutExt,
+    CellRendererText,
+    ContainerExt,
+    Dialog,
+    DialogExt,
+    DialogFlags,
+    Entry,
+    EntryExt,
+    Label,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ScrolledWindow,
+    SelectionMode,
+    TreeModelExt,
+    TreeSelectionExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewExt,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::{Horizontal, Vertical};
+
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use crate::library::{Bookmark, Library};
+use crate::millis_to_minutes;
+
+const TEXT_COLUMN: u32 = 0;
+const TIME_COLUMN: u32 = 1;
+const ID_COLUMN: u32 = 2;
+const PANEL_WIDTH: i32 = 220;
+/// How often the position of the playing file gets saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Positions this close to the start are not worth resuming.
+const MIN_RESUME_MILLIS: u64 = 10_000;
+
+/// The file playing, identified by its path and size so that a replaced file starts over.
+struct Playing {
+    path: String,
+    size: i64,
+    time: Option<u64>,
+    /// Set once the file played to its end, until it starts again.
+    finished: bool,
+}
+
+fn file_size(path: &str) -> Option<i64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len() as i64)
+}
+
+/// Audiobook mode: remembers where each file was left to resume it there, and lists the
+/// named bookmarks of the playing file. Clicking a bookmark seeks to it.
+pub struct BookmarkList {
+    pub add_button: Button,
+    pub remove_button: Button,
+    bookmarks: RefCell<Vec<Bookmark>>,
+    enabled: Cell<bool>,
+    last_saved: Cell<Instant>,
+    library: Library,
+    model: ListStore,
+    panel: gtk::Box,
+    path: RefCell<Option<String>>,
+    playing: RefCell<Option<Playing>>,
+    treeview: TreeView,
+}
+
+impl BookmarkList {
+    pub fn new(enabled: bool) -> Self {
+        let model = ListStore::new(&[Type::String, Type::U64, Type::I64]);
+
+        let treeview = TreeView::new_with_model(&model);
+        treeview.set_headers_visible(false);
+        treeview.set_activate_on_single_click(true);
+        treeview.get_selection().set_mode(SelectionMode::Single);
+        let view_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        view_column.pack_start(&cell, true);
+        view_column.add_attribute(&cell, "text", TEXT_COLUMN as i32);
+        treeview.append_column(&view_column);
+
+        let scrolled_window = ScrolledWindow::new(None, None);
+        scrolled_window.set_vexpand(true);
+        scrolled_window.add(&treeview);
+
+        let add_button = Button::new_with_label("Add");
+        add_button.set_tooltip_text("Bookmark the current position");
+        let remove_button = Button::new_with_label("Remove");
+        let button_box = gtk::Box::new(Horizontal, 5);
+        button_box.add(&add_button);
+        button_box.add(&remove_button);
+
+        let panel = gtk::Box::new(Vertical, 5);
+        panel.set_size_request(PANEL_WIDTH, -1);
+        panel.add(&Label::new("Bookmarks"));
+        panel.add(&scrolled_window);
+        panel.add(&button_box);
+        // Only shown in audiobook mode, whatever shows the window.
+        panel.show_all();
+        panel.set_visible(enabled);
+        panel.set_no_show_all(true);
+
+        BookmarkList {
+            add_button,
+            remove_button,
+            bookmarks: RefCell::new(Vec::new()),
+            enabled: Cell::new(enabled),
+            last_saved: Cell::new(Instant::now()),
+            library: Library::open(),
+            model,
+            panel,
+            path: RefCell::new(None),
+            playing: RefCell::new(None),
+            treeview,
+        }
+    }
+
+    pub fn view(&self) -> &gtk::Box {
+        &self.panel
+    }
+
+    /// Calls `seek` with the position of a clicked bookmark.
+    pub fn connect_seek<F: Fn(u64) + 'static>(&self, seek: F) {
+        let model = self.model.clone();
+        self.treeview.connect_row_activated(move |_, path, _| {
+            if let Some(iter) = model.get_iter(path) {
+                if let Some(position) = model.get_value(&iter, TIME_COLUMN as i32).get::<u64>() {
+                    seek(position);
+                }
+            }
+        });
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if !enabled {
+            self.save();
+        }
+        self.enabled.set(enabled);
+        self.panel.set_visible(enabled);
+    }
+
+    /// Where to start playing `path`: the position it was left at, in audiobook mode.
+    pub fn resume_position(&self, path: &str) -> Option<u64> {
+        if !self.enabled.get() {
+            return None;
+        }
+        self.library.resume_position(path, file_size(path)?)
+            .filter(|&position| position >= MIN_RESUME_MILLIS)
+    }
+
+    /// Follows the file playing, saving the position of the previous one and listing the
+    /// bookmarks of `path` unless they already are. Returns whether they changed.
+    pub fn load(&self, path: Option<&str>) -> bool {
+        if self.path.borrow().as_deref() == path {
+            return false;
+        }
+        self.save();
+        *self.path.borrow_mut() = path.map(str::to_string);
+        *self.playing.borrow_mut() = path.and_then(|path| {
+            Some(Playing {
+                path: path.to_string(),
+                size: file_size(path)?,
+                time: None,
+                finished: false,
+            })
+        });
+        self.last_saved.set(Instant::now());
+        self.refresh();
+        true
+    }
+
+    /// Records the position of the playing file, saving it every few seconds.
+    pub fn update(&self, time: u64) {
+        if let Some(playing) = self.playing.borrow_mut().as_mut() {
+            if playing.finished && time < MIN_RESUME_MILLIS {
+                playing.finished = false;
+            }
+            if !playing.finished {
+                playing.time = Some(time);
+            }
+        }
+        if self.last_saved.get().elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    /// Saves the position of the playing file in audiobook mode.
+    pub fn save(&self) {
+        self.last_saved.set(Instant::now());
+        if !self.enabled.get() {
+            return;
+        }
+        if let Some(Playing { path, size, time: Some(time), .. }) = self.playing.borrow().as_ref() {
+            self.library.set_resume_position(path, *size, Some(*time));
+        }
+    }
+
+    /// Forgets the position of the playing file once it played to its end.
+    pub fn finish(&self) {
+        if let Some(playing) = self.playing.borrow_mut().as_mut() {
+            playing.time = None;
+            playing.finished = true;
+            if self.enabled.get() {
+                self.library.set_resume_position(&playing.path, playing.size, None);
+            }
+        }
+    }
+
+    /// Bookmarks `time` in the playing file as `name`.
+    pub fn add(&self, name: &str, time: u64) {
+        if let Some(playing) = self.playing.borrow().as_ref() {
+            let _ = self.library.add_bookmark(&playing.path, playing.size, time, name);
+        }
+        self.refresh();
+    }
+
+    pub fn remove_selection(&self) {
+        let selection = self.treeview.get_selection();
+        if let Some((_, iter)) = selection.get_selected() {
+            if let Some(id) = self.model.get_value(&iter, ID_COLUMN as i32).get::<i64>() {
+                self.library.remove_bookmark(id);
+            }
+        }
+        self.refresh();
+    }
+
+    /// The positions and names of the bookmarks of the playing file.
+    pub fn marks(&self) -> Vec<(u64, String)> {
+        self.bookmarks.borrow().iter()
+            .map(|bookmark| (bookmark.position, bookmark.name.clone()))
+            .collect()
+    }
+
+    fn refresh(&self) {
+        let bookmarks = match self.playing.borrow().as_ref() {
+            Some(playing) => self.library.bookmarks(&playing.path, playing.size),
+            None => Vec::new(),
+        };
+        self.model.clear();
+        for bookmark in &bookmarks {
+            let text = format!("{}  {}", millis_to_minutes(bookmark.position), bookmark.name);
+            let iter = self.model.append();
+            self.model.set(&iter, &[TEXT_COLUMN, TIME_COLUMN, ID_COLUMN], &[&text, &bookmark.position, &bookmark.id]);
+        }
+        *self.bookmarks.borrow_mut() = bookmarks;
+    }
+}
+
+/// Asks for the name of a bookmark at `time`.
+pub fn show_bookmark_dialog(parent: &ApplicationWindow, time: u64) -> Option<String> {
+    let dialog = Dialog::new_with_buttons(Some("Add bookmark"), Some(parent), DialogFlags::MODAL,
+                                          &[("Cancel", GTK_RESPONSE_CANCEL), ("Add", GTK_RESPONSE_ACCEPT)]);
+    dialog.set_default_response(GTK_RESPONSE_ACCEPT);
+    let content = gtk::Box::new(Vertical, 5);
+    content.set_property_margin(10);
+    dialog.get_content_area().add(&content);
+
+    let name_entry = Entry::new();
+    name_entry.set_placeholder_text("Name");
+    name_entry.set_activates_default(true);
+    content.add(&Label::new(format!("Bookmark at {}", millis_to_minutes(time)).as_str()));
+    content.add(&name_entry);
+
+    dialog.show_all();
+    let mut result = None;
+    if dialog.run() == GTK_RESPONSE_ACCEPT {
+        let name = name_entry.get_text().unwrap_or_default();
+        result = Some(if name.trim().is_empty() {
+            format!("Bookmark at {}", millis_to_minutes(time))
+        } else {
+            name
+        });
+    }
+
+    dialog.destroy();
+    result
+}
+
+/// Escapes `text` for the Pango markup of scale marks.
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
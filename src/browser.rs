@@ -0,0 +1,367 @@
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
+
+use gtk::{
+    Button,
+    ButtonExt,
+    Cast,
+    CellLayoutExt,
+    CellRendererText,
+    ComboBoxExt,
+    ComboBoxText,
+    ComboBoxTextExt,
+    ContainerExt,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ScrolledWindow,
+    SelectionMode,
+    TreeModelExt,
+    TreeSelectionExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewColumnExt,
+    TreeViewExt,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::{Horizontal, Vertical};
+
+use crate::library::{Library, Selection};
+use crate::smart::SmartPlaylist;
+
+const LABEL_COLUMN: u32 = 0;
+const VALUE_COLUMN: u32 = 1;
+const PANEL_WIDTH: i32 = 260;
+/// How many tracks the "Recently played" and "Most played" views list.
+const HISTORY_LIMIT: u32 = 100;
+const HISTORY_VIEWS: &[(&str, &str)] = &[
+    ("recent", "Recently played"),
+    ("most", "Most played"),
+    ("never", "Never played"),
+];
+
+/// The library pane: genre and year facets above Artist → Album → Track lists.
+///
+/// Without a selection a list shows everything allowed by the lists above it, so
+/// `selected_paths` can send a whole artist or album to the playlist.
+pub struct Browser {
+    pub add_button: Button,
+    pub delete_smart_button: Button,
+    pub edit_smart_button: Button,
+    pub folders_button: Button,
+    pub new_smart_button: Button,
+    pub open_history_button: Button,
+    pub open_smart_button: Button,
+    album_view: TreeView,
+    artist_view: TreeView,
+    genre_combo: ComboBoxText,
+    history_combo: ComboBoxText,
+    library: Library,
+    library_changed: Cell<bool>,
+    open_smart_playlist: RefCell<Option<(String, u64)>>,
+    panel: gtk::Box,
+    smart_playlists: RefCell<Vec<SmartPlaylist>>,
+    smart_view: TreeView,
+    track_view: TreeView,
+    updating: Cell<bool>,
+    year_combo: ComboBoxText,
+}
+
+impl Browser {
+    pub fn new(library: Library) -> Rc<Self> {
+        let genre_combo = ComboBoxText::new();
+        let year_combo = ComboBoxText::new();
+        let facets = gtk::Box::new(Horizontal, 5);
+        facets.add(&genre_combo);
+        facets.add(&year_combo);
+
+        let (artist_view, artist_window) = create_list("Artist", SelectionMode::Single);
+        let (album_view, album_window) = create_list("Album", SelectionMode::Single);
+        let (track_view, track_window) = create_list("Track", SelectionMode::Multiple);
+
+        let add_button = Button::new_with_label("Add to playlist");
+        let folders_button = Button::new_with_label("Add music folder…");
+        let buttons = gtk::Box::new(Horizontal, 5);
+        buttons.add(&add_button);
+        buttons.add(&folders_button);
+
+        let (smart_view, smart_window) = create_list("Smart playlist", SelectionMode::Single);
+        let open_smart_button = Button::new_with_label("Open");
+        let new_smart_button = Button::new_with_label("New…");
+        let edit_smart_button = Button::new_with_label("Edit…");
+        let delete_smart_button = Button::new_with_label("Delete");
+        let smart_buttons = gtk::Box::new(Horizontal, 5);
+        smart_buttons.add(&open_smart_button);
+        smart_buttons.add(&new_smart_button);
+        smart_buttons.add(&edit_smart_button);
+        smart_buttons.add(&delete_smart_button);
+
+        let history_combo = ComboBoxText::new();
+        for &(id, label) in HISTORY_VIEWS {
+            history_combo.append(Some(id), label);
+        }
+        history_combo.set_active(0);
+        history_combo.set_hexpand(true);
+        let open_history_button = Button::new_with_label("Open");
+        let history_box = gtk::Box::new(Horizontal, 5);
+        history_box.add(&history_combo);
+        history_box.add(&open_history_button);
+
+        let panel = gtk::Box::new(Vertical, 5);
+        panel.set_size_request(PANEL_WIDTH, -1);
+        panel.add(&facets);
+        panel.add(&artist_window);
+        panel.add(&album_window);
+        panel.add(&track_window);
+        panel.add(&buttons);
+        panel.add(&smart_window);
+        panel.add(&smart_buttons);
+        panel.add(&history_box);
+
+        let browser = Rc::new(Browser {
+            add_button,
+            delete_smart_button,
+            edit_smart_button,
+            folders_button,
+            new_smart_button,
+            open_history_button,
+            open_smart_button,
+            album_view,
+            artist_view,
+            genre_combo,
+            history_combo,
+            library,
+            library_changed: Cell::new(false),
+            open_smart_playlist: RefCell::new(None),
+            panel,
+            smart_playlists: RefCell::new(Vec::new()),
+            smart_view,
+            track_view,
+            updating: Cell::new(false),
+            year_combo,
+        });
+        browser.refresh();
+        browser.connect_events();
+        browser
+    }
+
+    fn connect_events(self: &Rc<Self>) {
+        let on_change = |browser: &Weak<Self>, refresh: fn(&Self)| {
+            let browser = browser.clone();
+            move || {
+                if let Some(browser) = browser.upgrade() {
+                    if !browser.updating.get() {
+                        refresh(&browser);
+                    }
+                }
+            }
+        };
+
+        let browser = Rc::downgrade(self);
+        let refresh = on_change(&browser, Self::refresh_artists);
+        self.genre_combo.connect_changed(move |_| refresh());
+        let refresh = on_change(&browser, Self::refresh_artists);
+        self.year_combo.connect_changed(move |_| refresh());
+        let refresh = on_change(&browser, Self::refresh_albums);
+        self.artist_view.get_selection().connect_changed(move |_| refresh());
+        let refresh = on_change(&browser, Self::refresh_tracks);
+        self.album_view.get_selection().connect_changed(move |_| refresh());
+
+        let add_button = self.add_button.clone();
+        self.track_view.connect_row_activated(move |_, _, _| {
+            add_button.clicked();
+        });
+
+        let open_smart_button = self.open_smart_button.clone();
+        self.smart_view.connect_row_activated(move |_, _, _| {
+            open_smart_button.clicked();
+        });
+    }
+
+    pub fn view(&self) -> &gtk::Box {
+        &self.panel
+    }
+
+    /// Reloads everything from the library, keeping the current choices that still exist.
+    pub fn refresh(&self) {
+        self.library_changed.set(true);
+        self.updating.set(true);
+        let genre = self.genre_combo.get_active_id();
+        self.genre_combo.remove_all();
+        self.genre_combo.append(None, "All genres");
+        for genre in self.library.genres() {
+            let label = if genre.is_empty() { "Unknown genre" } else { &genre };
+            self.genre_combo.append(Some(genre.as_str()), label);
+        }
+        if !genre.map(|genre| self.genre_combo.set_active_id(Some(genre.as_str()))).unwrap_or(false) {
+            self.genre_combo.set_active(0);
+        }
+
+        let year = self.year_combo.get_active_id();
+        self.year_combo.remove_all();
+        self.year_combo.append(None, "All years");
+        for year in self.library.years() {
+            let year = year.to_string();
+            self.year_combo.append(Some(year.as_str()), &year);
+        }
+        if !year.map(|year| self.year_combo.set_active_id(Some(year.as_str()))).unwrap_or(false) {
+            self.year_combo.set_active(0);
+        }
+        self.updating.set(false);
+
+        self.refresh_artists();
+    }
+
+    fn selection(&self) -> Selection {
+        Selection {
+            genre: self.genre_combo.get_active_id(),
+            year: self.year_combo.get_active_id().and_then(|year| year.parse().ok()),
+            artist: selected_value(&self.artist_view),
+            album: selected_value(&self.album_view),
+        }
+    }
+
+    fn refresh_artists(&self) {
+        let artists = self.library.artists(&self.selection());
+        self.fill(&self.artist_view, &artists, "Unknown artist");
+        self.refresh_albums();
+    }
+
+    fn refresh_albums(&self) {
+        let albums = self.library.albums(&self.selection());
+        self.fill(&self.album_view, &albums, "Unknown album");
+        self.refresh_tracks();
+    }
+
+    fn refresh_tracks(&self) {
+        let model = list_model(&self.track_view);
+        model.clear();
+        for (title, path) in self.library.tracks(&self.selection()) {
+            let iter = model.append();
+            model.set(&iter, &[LABEL_COLUMN, VALUE_COLUMN], &[&title, &path]);
+        }
+    }
+
+    /// Replaces the rows of `view`, reselecting the previously selected value if still listed.
+    fn fill(&self, view: &TreeView, values: &[String], unknown: &str) {
+        self.updating.set(true);
+        let selected = selected_value(view);
+        let model = list_model(view);
+        model.clear();
+        for value in values {
+            let label = if value.is_empty() { unknown } else { value };
+            let iter = model.append();
+            model.set(&iter, &[LABEL_COLUMN, VALUE_COLUMN], &[&label, value]);
+            if selected.as_ref() == Some(value) {
+                view.get_selection().select_iter(&iter);
+            }
+        }
+        self.updating.set(false);
+    }
+
+    /// The selected tracks, or every listed track when none is selected.
+    pub fn selected_paths(&self) -> Vec<PathBuf> {
+        let model = list_model(&self.track_view);
+        let (paths, _) = self.track_view.get_selection().get_selected_rows();
+        let mut rows: Vec<_> = paths.iter().filter_map(|path| model.get_iter(path)).collect();
+        if rows.is_empty() {
+            if let Some(iter) = model.get_iter_first() {
+                rows.push(iter.clone());
+                while model.iter_next(&iter) {
+                    rows.push(iter.clone());
+                }
+            }
+        }
+        rows.iter()
+            .filter_map(|iter| model.get_value(iter, VALUE_COLUMN as i32).get::<String>())
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    pub fn set_smart_playlists(&self, smart_playlists: &[SmartPlaylist]) {
+        *self.smart_playlists.borrow_mut() = smart_playlists.to_vec();
+        let names: Vec<_> = smart_playlists.iter().map(|smart| smart.name.clone()).collect();
+        self.fill(&self.smart_view, &names, "");
+        // The open one may have been edited.
+        self.library_changed.set(true);
+    }
+
+    pub fn selected_smart_playlist(&self) -> Option<SmartPlaylist> {
+        let name = selected_value(&self.smart_view)?;
+        self.smart_playlists.borrow().iter().find(|smart| smart.name == name).cloned()
+    }
+
+    /// Evaluates the selected smart playlist and keeps it open, so that
+    /// `reevaluate_smart_playlist` follows library changes.
+    pub fn open_smart_playlist(&self) -> Option<Vec<PathBuf>> {
+        let smart = self.selected_smart_playlist()?;
+        let seed = rand::random();
+        *self.open_smart_playlist.borrow_mut() = Some((smart.name.clone(), seed));
+        self.library_changed.set(false);
+        Some(smart.evaluate(&self.library, seed))
+    }
+
+    /// Stops following the open smart playlist, e.g. once other tracks are added by hand.
+    pub fn close_smart_playlist(&self) {
+        *self.open_smart_playlist.borrow_mut() = None;
+    }
+
+    /// The name and tracks of the chosen play history view.
+    pub fn history_view(&self) -> Option<(String, Vec<PathBuf>)> {
+        let id = self.history_combo.get_active_id()?;
+        let paths = match id.as_str() {
+            "recent" => self.library.recently_played(HISTORY_LIMIT),
+            "most" => self.library.most_played(HISTORY_LIMIT),
+            _ => self.library.never_played(),
+        };
+        let name = self.history_combo.get_active_text()?;
+        Some((name, paths.into_iter().map(PathBuf::from).collect()))
+    }
+
+    /// Evaluates the open smart playlist again if the library or its definition changed.
+    pub fn reevaluate_smart_playlist(&self) -> Option<Vec<PathBuf>> {
+        if !self.library_changed.replace(false) {
+            return None;
+        }
+        let (name, seed) = self.open_smart_playlist.borrow().clone()?;
+        let smart_playlists = self.smart_playlists.borrow();
+        match smart_playlists.iter().find(|smart| smart.name == name) {
+            Some(smart) => Some(smart.evaluate(&self.library, seed)),
+            None => {
+                self.close_smart_playlist();
+                None
+            },
+        }
+    }
+}
+
+fn create_list(title: &str, mode: SelectionMode) -> (TreeView, ScrolledWindow) {
+    let model = ListStore::new(&[Type::String, Type::String]);
+    let treeview = TreeView::new_with_model(&model);
+    treeview.get_selection().set_mode(mode);
+    let view_column = TreeViewColumn::new();
+    view_column.set_title(title);
+    let cell = CellRendererText::new();
+    view_column.pack_start(&cell, true);
+    view_column.add_attribute(&cell, "text", LABEL_COLUMN as i32);
+    treeview.append_column(&view_column);
+
+    let scrolled_window = ScrolledWindow::new(None, None);
+    scrolled_window.set_vexpand(true);
+    scrolled_window.add(&treeview);
+    (treeview, scrolled_window)
+}
+
+fn list_model(view: &TreeView) -> ListStore {
+    view.get_model()
+        .and_then(|model| model.downcast::<ListStore>().ok())
+        .expect("browser lists use a ListStore")
+}
+
+fn selected_value(view: &TreeView) -> Option<String> {
+    let (model, iter) = view.get_selection().get_selected()?;
+    model.get_value(&iter, VALUE_COLUMN as i32).get::<String>()
+}
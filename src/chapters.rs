@@ -0,0 +1,290 @@
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use gtk::{
+    CellLayoutExt,
+    CellRendererText,
+    ContainerExt,
+    Label,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ScrolledWindow,
+    SelectionMode,
+    TreeModelExt,
+    TreePath,
+    TreeSelectionExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewExt,
+    ToValue,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::Vertical;
+
+use id3::Tag;
+
+use crate::metadata::split_id3_string;
+use crate::millis_to_minutes;
+
+const TEXT_COLUMN: u32 = 0;
+const TIME_COLUMN: u32 = 1;
+const WEIGHT_COLUMN: u32 = 2;
+const PANEL_WIDTH: i32 = 220;
+const WEIGHT_NORMAL: i32 = 400;
+const WEIGHT_BOLD: i32 = 700;
+/// How far into a chapter going back restarts it rather than going to the previous one.
+const RESTART_MILLIS: u64 = 3000;
+const CTOC_TOP_LEVEL: u8 = 0x02;
+
+pub struct Chapter {
+    pub title: String,
+    /// The start time in milliseconds.
+    pub start: u64,
+}
+
+/// Reads the chapters of `path` from its CHAP frames, in the order of the top-level CTOC
+/// frame if there is one, by start time otherwise.
+pub fn read_chapters(path: &Path) -> Vec<Chapter> {
+    let tag = match Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(_) => return Vec::new(),
+    };
+    // Sub-frame sizes are synchsafe integers from ID3v2.4 on.
+    let synchsafe = major_version(path).map(|version| version >= 4).unwrap_or(false);
+    let mut chapters: Vec<(String, Chapter)> = tag.frames()
+        .filter(|frame| frame.id() == "CHAP")
+        .filter_map(|frame| frame.content().unknown())
+        .filter_map(|data| parse_chap(data, synchsafe))
+        .collect();
+
+    let order = tag.frames()
+        .filter(|frame| frame.id() == "CTOC")
+        .filter_map(|frame| frame.content().unknown())
+        .filter_map(parse_ctoc)
+        .find(|(top_level, _)| *top_level)
+        .map(|(_, children)| children);
+    match order {
+        Some(children) => {
+            let mut ordered = Vec::new();
+            for child in children {
+                if let Some(index) = chapters.iter().position(|(id, _)| *id == child) {
+                    ordered.push(chapters.remove(index).1);
+                }
+            }
+            ordered
+        },
+        None => {
+            chapters.sort_by_key(|(_, chapter)| chapter.start);
+            chapters.into_iter().map(|(_, chapter)| chapter).collect()
+        },
+    }
+}
+
+/// The major version of the ID3v2 tag heading `path`, which the parsed tag no longer tells.
+fn major_version(path: &Path) -> Option<u8> {
+    let mut header = [0; 4];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[..3] == b"ID3" { Some(header[3]) } else { None }
+}
+
+/// Parses a CHAP frame into its element ID and the chapter, titled by its TIT2 sub-frame.
+fn parse_chap(data: &[u8], synchsafe: bool) -> Option<(String, Chapter)> {
+    let id_end = data.iter().position(|&byte| byte == 0)?;
+    let id = String::from_utf8_lossy(&data[..id_end]).into_owned();
+    let times = data.get(id_end + 1..id_end + 17)?;
+    let start = u64::from(u32::from_be_bytes(times[..4].try_into().unwrap()));
+    let title = sub_frames(&data[id_end + 17..], synchsafe)
+        .find(|(frame_id, _)| frame_id == "TIT2")
+        .and_then(|(_, content)| {
+            let (&encoding, text) = content.split_first()?;
+            Some(split_id3_string(text, encoding).0)
+        })
+        .filter(|title| !title.trim().is_empty())
+        .unwrap_or(id.clone());
+    Some((id, Chapter { title, start }))
+}
+
+/// Parses a CTOC frame into whether it is the top-level one and its child element IDs.
+fn parse_ctoc(data: &[u8]) -> Option<(bool, Vec<String>)> {
+    let id_end = data.iter().position(|&byte| byte == 0)?;
+    let flags = *data.get(id_end + 1)?;
+    let count = *data.get(id_end + 2)?;
+    let mut rest = data.get(id_end + 3..)?;
+    let mut children = Vec::new();
+    for _ in 0..count {
+        let end = rest.iter().position(|&byte| byte == 0)?;
+        children.push(String::from_utf8_lossy(&rest[..end]).into_owned());
+        rest = &rest[end + 1..];
+    }
+    Some((flags & CTOC_TOP_LEVEL != 0, children))
+}
+
+/// Iterates over the frames embedded in a CHAP or CTOC frame, as IDs and contents.
+fn sub_frames(mut data: &[u8], synchsafe: bool) -> impl Iterator<Item = (String, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = data.get(..10)?;
+        if header[0] == 0 {
+            return None;
+        }
+        let id = String::from_utf8_lossy(&header[..4]).into_owned();
+        let size = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let size = if synchsafe {
+            (size & 0x7f) | (size & 0x7f00) >> 1 | (size & 0x7f_0000) >> 2 | (size & 0x7f00_0000) >> 3
+        } else {
+            size
+        } as usize;
+        let content = data.get(10..10 + size)?;
+        data = &data[10 + size..];
+        Some((id, content))
+    })
+}
+
+/// Lists the chapters of the playing track and follows the current one.
+/// Clicking a chapter seeks to it.
+pub struct ChapterList {
+    chapters: RefCell<Vec<Chapter>>,
+    current_chapter: Cell<Option<usize>>,
+    model: ListStore,
+    panel: gtk::Box,
+    path: RefCell<Option<String>>,
+    treeview: TreeView,
+}
+
+impl ChapterList {
+    pub fn new() -> Self {
+        let model = ListStore::new(&[Type::String, Type::U64, Type::I32]);
+
+        let treeview = TreeView::new_with_model(&model);
+        treeview.set_headers_visible(false);
+        treeview.set_activate_on_single_click(true);
+        treeview.get_selection().set_mode(SelectionMode::None);
+        let view_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        view_column.pack_start(&cell, true);
+        view_column.add_attribute(&cell, "text", TEXT_COLUMN as i32);
+        view_column.add_attribute(&cell, "weight", WEIGHT_COLUMN as i32);
+        treeview.append_column(&view_column);
+
+        let scrolled_window = ScrolledWindow::new(None, None);
+        scrolled_window.set_vexpand(true);
+        scrolled_window.add(&treeview);
+
+        let panel = gtk::Box::new(Vertical, 5);
+        panel.set_size_request(PANEL_WIDTH, -1);
+        panel.add(&Label::new("Chapters"));
+        panel.add(&scrolled_window);
+        // Hidden until a track with chapters plays, whatever shows the window.
+        panel.show_all();
+        panel.hide();
+        panel.set_no_show_all(true);
+
+        ChapterList {
+            chapters: RefCell::new(Vec::new()),
+            current_chapter: Cell::new(None),
+            model,
+            panel,
+            path: RefCell::new(None),
+            treeview,
+        }
+    }
+
+    pub fn view(&self) -> &gtk::Box {
+        &self.panel
+    }
+
+    /// Calls `seek` with the start of a clicked chapter.
+    pub fn connect_seek<F: Fn(u64) + 'static>(&self, seek: F) {
+        let model = self.model.clone();
+        self.treeview.connect_row_activated(move |_, path, _| {
+            if let Some(iter) = model.get_iter(path) {
+                if let Some(start) = model.get_value(&iter, TIME_COLUMN as i32).get::<u64>() {
+                    seek(start);
+                }
+            }
+        });
+    }
+
+    /// Lists the chapters of `path` unless they already are, returning whether they changed.
+    /// The list is only shown for tracks with chapters.
+    pub fn load(&self, path: Option<&str>) -> bool {
+        if self.path.borrow().as_deref() == path {
+            return false;
+        }
+        *self.path.borrow_mut() = path.map(str::to_string);
+
+        let chapters = path.map(|path| read_chapters(Path::new(path))).unwrap_or_default();
+        self.model.clear();
+        self.current_chapter.set(None);
+        for chapter in &chapters {
+            let text = format!("{}  {}", millis_to_minutes(chapter.start), chapter.title);
+            let iter = self.model.append();
+            self.model.set(&iter, &[TEXT_COLUMN, TIME_COLUMN, WEIGHT_COLUMN], &[&text, &chapter.start, &WEIGHT_NORMAL]);
+        }
+        self.panel.set_visible(!chapters.is_empty());
+        *self.chapters.borrow_mut() = chapters;
+        true
+    }
+
+    /// The start times of the chapters.
+    pub fn starts(&self) -> Vec<u64> {
+        self.chapters.borrow().iter().map(|chapter| chapter.start).collect()
+    }
+
+    fn chapter_at(&self, time: u64) -> Option<usize> {
+        self.chapters.borrow().partition_point(|chapter| chapter.start <= time).checked_sub(1)
+    }
+
+    /// Highlights the chapter playing at `time`, returning whether it changed.
+    pub fn update(&self, time: u64) -> bool {
+        let chapter = self.chapter_at(time);
+        if chapter == self.current_chapter.get() {
+            return false;
+        }
+        if let Some(previous) = self.current_chapter.replace(chapter) {
+            self.set_weight(previous, WEIGHT_NORMAL);
+        }
+        if let Some(chapter) = chapter {
+            self.set_weight(chapter, WEIGHT_BOLD);
+            let path = TreePath::new_from_string(&chapter.to_string());
+            self.treeview.scroll_to_cell(Some(&path), None, false, 0.0, 0.0);
+        }
+        true
+    }
+
+    /// The title of the current chapter.
+    pub fn title(&self) -> Option<String> {
+        let chapters = self.chapters.borrow();
+        chapters.get(self.current_chapter.get()?).map(|chapter| chapter.title.clone())
+    }
+
+    /// The start of the chapter after the one playing at `time`.
+    pub fn next_start(&self, time: u64) -> Option<u64> {
+        let next = self.chapter_at(time).map(|chapter| chapter + 1).unwrap_or(0);
+        self.chapters.borrow().get(next).map(|chapter| chapter.start)
+    }
+
+    /// The start of the chapter playing at `time`, or of the one before it when `time` is
+    /// close to that start.
+    pub fn previous_start(&self, time: u64) -> Option<u64> {
+        let chapters = self.chapters.borrow();
+        let current = self.chapter_at(time)?;
+        if time - chapters[current].start < RESTART_MILLIS && current > 0 {
+            Some(chapters[current - 1].start)
+        } else {
+            Some(chapters[current].start)
+        }
+    }
+
+    fn set_weight(&self, chapter: usize, weight: i32) {
+        let path = TreePath::new_from_string(&chapter.to_string());
+        if let Some(iter) = self.model.get_iter(&path) {
+            self.model.set_value(&iter, WEIGHT_COLUMN, &weight.to_value());
+        }
+    }
+}
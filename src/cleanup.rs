@@ -0,0 +1,361 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use gtk::{
+    ApplicationWindow,
+    CellLayoutExt,
+    CellRendererText,
+    CellRendererToggle,
+    CellRendererToggleExt,
+    Continue,
+    ContainerExt,
+    Dialog,
+    DialogExt,
+    DialogFlags,
+    GtkWindowExt,
+    Label,
+    LabelExt,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ProgressBar,
+    ProgressBarExt,
+    ScrolledWindow,
+    ToValue,
+    TreeModelExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewColumnExt,
+    TreeViewExt,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::Vertical;
+
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use crate::mp3::Mp3Decoder;
+use crate::scanner::collect_files;
+
+const APPLY_COLUMN: u32 = 0;
+const ACTION_COLUMN: u32 = 1;
+const PROBLEM_COLUMN: u32 = 2;
+const PATH_COLUMN: u32 = 3;
+/// The index of the finding in the list of the check.
+const INDEX_COLUMN: u32 = 4;
+/// How many samples are decoded between two checks for cancellation.
+const CANCEL_CHECK_SAMPLES: u64 = 1 << 16;
+
+/// What is wrong with a playlist row.
+pub enum Problem {
+    /// An earlier row has the same file.
+    Duplicate,
+    /// An earlier row has a file with the same decoded audio, e.g. a retagged copy.
+    SameAudio(String),
+    /// The file does not exist, and may have been found at the given path.
+    Missing(Option<PathBuf>),
+}
+
+/// What was measured of a file before: its size in bytes and its duration in milliseconds.
+pub struct Measurement {
+    pub size: u64,
+    pub duration: u64,
+}
+
+pub struct Finding {
+    /// The position of the row in the playlist.
+    pub row: usize,
+    pub path: String,
+    pub problem: Problem,
+}
+
+impl Finding {
+    /// Whether fixing the row moves it to another file rather than removing it.
+    pub fn relocation(&self) -> Option<&Path> {
+        match self.problem {
+            Problem::Missing(Some(ref new_path)) => Some(new_path),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> String {
+        match self.problem {
+            Problem::Duplicate => "Duplicate".to_string(),
+            Problem::SameAudio(ref original) => format!("Same audio as {}", file_name(original)),
+            Problem::Missing(None) => "Missing".to_string(),
+            Problem::Missing(Some(ref new_path)) => format!("Missing, found at {}", new_path.display()),
+        }
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// A background check of the files of a playlist: duplicate rows, files with the same
+/// audio and missing files, looked for in the music folders by name and size.
+struct Check {
+    cancelled: Arc<AtomicBool>,
+    checked: Arc<AtomicUsize>,
+    findings: Arc<Mutex<Option<Vec<Finding>>>>,
+    /// The rows, then the files to decode too once known.
+    total: Arc<AtomicUsize>,
+}
+
+impl Check {
+    /// Checks `paths`, the rows of a playlist in order. `measured` holds what was measured
+    /// of the files before, to recognize them once moved and to compare the audio of only
+    /// those of the same duration.
+    fn start(paths: Vec<String>, measured: HashMap<String, Measurement>, folders: Vec<PathBuf>) -> Self {
+        let check = Check {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            checked: Arc::new(AtomicUsize::new(0)),
+            findings: Arc::new(Mutex::new(None)),
+            total: Arc::new(AtomicUsize::new(paths.len())),
+        };
+        let cancelled = check.cancelled.clone();
+        let checked = check.checked.clone();
+        let findings = check.findings.clone();
+        let total = check.total.clone();
+        thread::spawn(move || {
+            let result = find_problems(&paths, &measured, &folders, &checked, &total, &cancelled);
+            *findings.lock().unwrap() = Some(result);
+        });
+        check
+    }
+
+    fn progress(&self) -> f64 {
+        self.checked.load(Ordering::SeqCst) as f64 / self.total.load(Ordering::SeqCst).max(1) as f64
+    }
+
+    fn take_findings(&self) -> Option<Vec<Finding>> {
+        self.findings.lock().unwrap().take()
+    }
+}
+
+impl Drop for Check {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+fn find_problems(paths: &[String], measured: &HashMap<String, Measurement>, folders: &[PathBuf],
+                 checked: &AtomicUsize, total: &AtomicUsize, cancelled: &AtomicBool) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut seen = HashMap::new();
+    let mut moved_files = None;
+    // Only files of the same duration can have the same audio.
+    let mut by_duration: HashMap<Option<u64>, Vec<(usize, &String)>> = HashMap::new();
+    for (row, path) in paths.iter().enumerate() {
+        if cancelled.load(Ordering::SeqCst) {
+            return findings;
+        }
+        checked.store(row, Ordering::SeqCst);
+        let problem = if seen.insert(path.clone(), row).is_some() {
+            Some(Problem::Duplicate)
+        } else if !Path::new(path).exists() {
+            // The music folders only get walked once a file is missing.
+            let files = moved_files.get_or_insert_with(|| files_by_name(folders));
+            let size = measured.get(path).map(|measurement| measurement.size);
+            Some(Problem::Missing(find_moved(path, size, files)))
+        } else {
+            let duration = measured.get(path).map(|measurement| measurement.duration);
+            by_duration.entry(duration).or_default().push((row, path));
+            None
+        };
+        if let Some(problem) = problem {
+            findings.push(Finding {
+                row,
+                path: path.clone(),
+                problem,
+            });
+        }
+    }
+
+    // The files alone in their duration are not decoded.
+    let groups: Vec<_> = by_duration.into_values().filter(|rows| rows.len() > 1).collect();
+    total.store(paths.len() + groups.iter().map(Vec::len).sum::<usize>(), Ordering::SeqCst);
+    let mut decoded = paths.len();
+    for rows in groups {
+        let mut hashes: HashMap<u64, &String> = HashMap::new();
+        for (row, path) in rows {
+            let hash = audio_hash(Path::new(path), cancelled);
+            if cancelled.load(Ordering::SeqCst) {
+                return findings;
+            }
+            decoded += 1;
+            checked.store(decoded, Ordering::SeqCst);
+            if let Some(hash) = hash {
+                match hashes.get(&hash) {
+                    Some(original) => findings.push(Finding {
+                        row,
+                        path: path.clone(),
+                        problem: Problem::SameAudio((*original).clone()),
+                    }),
+                    None => {
+                        hashes.insert(hash, path);
+                    },
+                }
+            }
+        }
+    }
+    findings.sort_by_key(|finding| finding.row);
+    findings
+}
+
+/// Hashes the decoded samples of the MP3 file at `path`, so that files differing only by
+/// their tags hash the same. Gives up once `cancelled`.
+fn audio_hash(path: &Path, cancelled: &AtomicBool) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    let decoder = Mp3Decoder::new(BufReader::new(file)).ok()?;
+    let mut hasher = DefaultHasher::new();
+    let mut samples = 0_u64;
+    for sample in decoder {
+        hasher.write_i16(sample);
+        samples += 1;
+        if samples.is_multiple_of(CANCEL_CHECK_SAMPLES) && cancelled.load(Ordering::SeqCst) {
+            return None;
+        }
+    }
+    if samples == 0 {
+        return None;
+    }
+    Some(hasher.finish())
+}
+
+fn files_by_name(folders: &[PathBuf]) -> HashMap<String, Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for folder in folders {
+        collect_files(folder, &mut files);
+    }
+    let mut by_name: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for file in files {
+        if let Some(name) = file.file_name() {
+            by_name.entry(name.to_string_lossy().to_string()).or_default().push(file);
+        }
+    }
+    by_name
+}
+
+/// The file with the name of the missing `path`, and its size when known.
+fn find_moved(path: &str, size: Option<u64>, files: &HashMap<String, Vec<PathBuf>>) -> Option<PathBuf> {
+    let candidates = files.get(&file_name(path))?;
+    match size {
+        Some(size) => candidates.iter()
+            .find(|candidate| fs::metadata(candidate).map(|metadata| metadata.len() == size).unwrap_or(false))
+            .cloned(),
+        None if candidates.len() == 1 => Some(candidates[0].clone()),
+        None => None,
+    }
+}
+
+/// Checks `paths`, the rows of a playlist, and lists the problems found for review.
+/// Returns the findings to fix, each by removing its row or relocating it.
+pub fn show_cleanup_dialog(parent: &ApplicationWindow, paths: Vec<String>, measured: HashMap<String, Measurement>,
+                           folders: Vec<PathBuf>) -> Vec<Finding> {
+    let dialog = Dialog::new_with_buttons(Some("Duplicates and missing files"), Some(parent), DialogFlags::MODAL,
+                                          &[("Cancel", GTK_RESPONSE_CANCEL), ("Fix", GTK_RESPONSE_ACCEPT)]);
+    dialog.set_default_size(700, 400);
+    dialog.set_response_sensitive(GTK_RESPONSE_ACCEPT, false);
+    let content = gtk::Box::new(Vertical, 5);
+    content.set_property_margin(10);
+    dialog.get_content_area().add(&content);
+
+    let status_label = Label::new("Checking the files…");
+    status_label.set_halign(gtk::Align::Start);
+    content.add(&status_label);
+    let progress = ProgressBar::new();
+    content.add(&progress);
+
+    let model = ListStore::new(&[Type::Bool, Type::String, Type::String, Type::String, Type::U32]);
+    let treeview = TreeView::new_with_model(&model);
+    let toggle = CellRendererToggle::new();
+    {
+        let model = model.clone();
+        toggle.connect_toggled(move |_, path| {
+            if let Some(iter) = model.get_iter(&path) {
+                let active = model.get_value(&iter, APPLY_COLUMN as i32).get::<bool>().unwrap_or(false);
+                model.set_value(&iter, APPLY_COLUMN, &(!active).to_value());
+            }
+        });
+    }
+    let view_column = TreeViewColumn::new();
+    view_column.pack_start(&toggle, false);
+    view_column.add_attribute(&toggle, "active", APPLY_COLUMN as i32);
+    treeview.append_column(&view_column);
+    for &(title, column) in &[("Fix", ACTION_COLUMN), ("Problem", PROBLEM_COLUMN), ("File", PATH_COLUMN)] {
+        let view_column = TreeViewColumn::new();
+        view_column.set_title(title);
+        view_column.set_resizable(true);
+        let cell = CellRendererText::new();
+        view_column.pack_start(&cell, true);
+        view_column.add_attribute(&cell, "text", column as i32);
+        treeview.append_column(&view_column);
+    }
+    let scrolled_window = ScrolledWindow::new(None, None);
+    scrolled_window.set_vexpand(true);
+    scrolled_window.add(&treeview);
+    content.add(&scrolled_window);
+
+    let findings = Rc::new(RefCell::new(Vec::new()));
+    let check = Check::start(paths, measured, folders);
+    {
+        let dialog = dialog.clone();
+        let findings = findings.clone();
+        let model = model.clone();
+        gtk::timeout_add(100, move || {
+            progress.set_fraction(check.progress());
+            let found = match check.take_findings() {
+                Some(found) => found,
+                None => return Continue(dialog.get_visible()),
+            };
+            progress.hide();
+            status_label.set_text(&match found.len() {
+                0 => "No duplicates or missing files.".to_string(),
+                count => format!("{} problems found, the checked ones get fixed.", count),
+            });
+            for (index, finding) in found.iter().enumerate() {
+                let action = if finding.relocation().is_some() { "Relocate" } else { "Remove" };
+                let iter = model.append();
+                model.set(&iter, &[APPLY_COLUMN, ACTION_COLUMN, PROBLEM_COLUMN, PATH_COLUMN, INDEX_COLUMN],
+                          &[&true, &action, &finding.description(), &finding.path, &(index as u32)]);
+            }
+            dialog.set_response_sensitive(GTK_RESPONSE_ACCEPT, !found.is_empty());
+            *findings.borrow_mut() = found;
+            Continue(false)
+        });
+    }
+
+    dialog.show_all();
+    let mut result = Vec::new();
+    if dialog.run() == GTK_RESPONSE_ACCEPT {
+        let mut found: Vec<_> = findings.borrow_mut().drain(..).map(Some).collect();
+        if let Some(iter) = model.get_iter_first() {
+            loop {
+                let apply = model.get_value(&iter, APPLY_COLUMN as i32).get::<bool>().unwrap_or(false);
+                let index = model.get_value(&iter, INDEX_COLUMN as i32).get::<u32>().unwrap_or(0) as usize;
+                if apply {
+                    result.extend(found.get_mut(index).and_then(Option::take));
+                }
+                if !model.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+    }
+
+    dialog.destroy();
+    result
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::smart::SmartPlaylist;
+use crate::xdg;
+
+const CONFIG_FILE: &str = "config.json";
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ColumnLayout {
+    pub id: String,
+    pub visible: bool,
+}
+
+/// User settings, kept as JSON in `$XDG_CONFIG_HOME/rusic/config.json`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Whether files resume where they were left, see `BookmarkList`.
+    pub audiobook_mode: bool,
+    /// The playlist columns in display order, empty for the default layout.
+    pub columns: Vec<ColumnLayout>,
+    /// The code page of tags not written in Unicode, e.g. `windows-1251`, guessed if unset.
+    pub legacy_encoding: Option<String>,
+    /// The folders indexed into the library.
+    pub music_folders: Vec<PathBuf>,
+    /// The email of the ID3 POPM frame the ratings are kept in, `rusic` if unset.
+    pub rating_email: Option<String>,
+    /// Whether the track of the restored session is loaded paused at its position.
+    pub resume_session: bool,
+    /// Whether listens get logged for scrobbling, see `Scrobbler`.
+    pub scrobble: bool,
+    /// The ListenBrainz compatible `submit-listens` URL the logged listens are sent to,
+    /// e.g. `http://localhost:8080/1/submit-listens`.
+    pub scrobble_endpoint: Option<String>,
+    /// The user token sent along with the listens.
+    pub scrobble_token: Option<String>,
+    /// Accelerators by action name, replacing the defaults of `SHORTCUT_GROUPS`, e.g.
+    /// `"play-pause": ["<Primary>p"]`. An empty list leaves the action without a shortcut.
+    pub shortcuts: BTreeMap<String, Vec<String>>,
+    pub smart_playlists: Vec<SmartPlaylist>,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        File::open(xdg::config_file(CONFIG_FILE)).ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Ok(file) = File::create(xdg::config_file(CONFIG_FILE)) {
+            let _ = serde_json::to_writer_pretty(file, self);
+        }
+    }
+}
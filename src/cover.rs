@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gdk_pixbuf::{Colorspace, InterpType, Pixbuf, PixbufLoader};
+
+use id3::frame::{Picture, PictureType};
+use id3::Tag;
+
+use crate::library::fnv1a;
+use crate::xdg;
+
+const COVER_NAMES: &[&str] = &["cover", "folder", "front"];
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+const CACHE_DIR: &str = "covers";
+const COLORSPACE_RGB: Colorspace = 0;
+const INTERP_HYPER: InterpType = 3;
+const HEADER_SIZE: usize = 16;
+pub const IMAGE_SIZE: i32 = 256;
+pub const THUMBNAIL_SIZE: i32 = 64;
+
+/// How well a picture type serves as the cover, lower being better.
+fn picture_rank(picture_type: PictureType) -> u8 {
+    match picture_type {
+        PictureType::CoverFront => 0,
+        PictureType::Other => 1,
+        PictureType::Media | PictureType::Leaflet => 2,
+        PictureType::Icon | PictureType::OtherIcon => 4,
+        _ => 3,
+    }
+}
+
+/// The embedded picture that best serves as the cover, the front cover first.
+pub fn embedded_cover(tag: &Tag) -> Option<&Picture> {
+    tag.pictures().min_by_key(|picture| picture_rank(picture.picture_type))
+}
+
+/// An image lying next to `track`, e.g. `cover.jpg`, `folder.png` or `front.jpeg`.
+pub fn folder_cover(track: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<(usize, PathBuf)> = track.parent()?.read_dir().ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+            let extension = path.extension()?.to_string_lossy().to_lowercase();
+            if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let rank = COVER_NAMES.iter().position(|name| *name == stem)?;
+            Some((rank, path))
+        })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next().map(|(_, path)| path)
+}
+
+/// The cover of `track`: its `embedded` picture, or else an image in its folder.
+pub fn read_cover(track: &Path, embedded: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    embedded.or_else(|| fs::read(folder_cover(track)?).ok())
+}
+
+/// Reads an image file as a front cover picture to embed in a tag.
+pub fn read_picture(path: &Path) -> Option<Picture> {
+    let data = fs::read(path).ok()?;
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    let mime_type = if extension == "png" { "image/png" } else { "image/jpeg" };
+    Some(Picture {
+        mime_type: mime_type.to_string(),
+        picture_type: PictureType::CoverFront,
+        description: String::new(),
+        data,
+    })
+}
+
+/// The scaled covers of the playlist, by hash of the picture data.
+///
+/// Decoding and scaling a cover is slow, and every track of an album usually carries the same
+/// one, so the results are kept in memory and, as raw pixels, in the XDG cache directory.
+pub struct CoverCache {
+    covers: RefCell<HashMap<u64, (Pixbuf, Pixbuf)>>,
+    dir: PathBuf,
+}
+
+impl CoverCache {
+    pub fn new() -> Self {
+        let dir = xdg::cache_file(CACHE_DIR);
+        let _ = fs::create_dir_all(&dir);
+        CoverCache {
+            covers: RefCell::new(HashMap::new()),
+            dir,
+        }
+    }
+
+    /// The cover image and its thumbnail for `picture`, `None` if it cannot be decoded.
+    pub fn get(&self, picture: &[u8]) -> Option<(Pixbuf, Pixbuf)> {
+        let hash = fnv1a(picture);
+        if let Some(cover) = self.covers.borrow().get(&hash) {
+            return Some(cover.clone());
+        }
+
+        let image_file = self.dir.join(format!("{:016x}-{}", hash, IMAGE_SIZE));
+        let thumbnail_file = self.dir.join(format!("{:016x}-{}", hash, THUMBNAIL_SIZE));
+        let cover = match (load_pixels(&image_file), load_pixels(&thumbnail_file)) {
+            (Some(image), Some(thumbnail)) => (image, thumbnail),
+            _ => {
+                let image = decode(picture)?;
+                let thumbnail = image.scale_simple(THUMBNAIL_SIZE, THUMBNAIL_SIZE, INTERP_HYPER).ok()?;
+                let _ = save_pixels(&image, &image_file);
+                let _ = save_pixels(&thumbnail, &thumbnail_file);
+                (image, thumbnail)
+            },
+        };
+        self.covers.borrow_mut().insert(hash, cover.clone());
+        Some(cover)
+    }
+}
+
+fn decode(picture: &[u8]) -> Option<Pixbuf> {
+    let pixbuf_loader = PixbufLoader::new();
+    pixbuf_loader.set_size(IMAGE_SIZE, IMAGE_SIZE);
+    let written = pixbuf_loader.loader_write(picture).is_ok();
+    let _ = pixbuf_loader.close();
+    if written {
+        pixbuf_loader.get_pixbuf()
+    } else {
+        None
+    }
+}
+
+/// Writes the pixels of `pixbuf` after a header of its width, height, row stride and alpha flag.
+fn save_pixels(pixbuf: &Pixbuf, file: &Path) -> std::io::Result<()> {
+    if pixbuf.get_bits_per_sample() != 8 {
+        return Ok(());
+    }
+    let header = [pixbuf.get_width(), pixbuf.get_height(), pixbuf.get_rowstride(), pixbuf.get_has_alpha() as i32];
+    let mut data: Vec<u8> = header.iter().flat_map(|value| value.to_le_bytes()).collect();
+    // The pixels are only read, and copied before the pixbuf can change.
+    data.extend_from_slice(unsafe { pixbuf.get_pixels() });
+    fs::write(file, data)
+}
+
+fn load_pixels(file: &Path) -> Option<Pixbuf> {
+    let data = fs::read(file).ok()?;
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+    let value = |index: usize| i32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+    let (width, height, rowstride, has_alpha) = (value(0), value(1), value(2), value(3) != 0);
+    let channels = if has_alpha { 4 } else { 3 };
+    if width <= 0 || height <= 0 || rowstride < width * channels {
+        return None;
+    }
+    let pixels = data[HEADER_SIZE..].to_vec();
+    if pixels.len() != ((height - 1) * rowstride + width * channels) as usize {
+        return None;
+    }
+    Some(Pixbuf::new_from_vec(pixels, COLORSPACE_RGB, has_alpha, 8, width, height, rowstride))
+}
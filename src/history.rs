@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::library::{Library, Play};
+use crate::player::State;
+use crate::scrobble::{ListenedTrack, Scrobbler};
+
+/// Time moving further than this between two updates was a seek, not listening.
+const MAX_STEP_MILLIS: u64 = 2_000;
+
+/// The play of the file playing, recorded once another file plays or it ends.
+struct Current {
+    path: String,
+    played_at: i64,
+    listened: u64,
+    time: Option<u64>,
+    track: Option<ListenedTrack>,
+}
+
+/// Records each play in the library, with how much of the file was heard, and keeps the
+/// play statistics of the shared state up to date. Listens also go to the `scrobbler`.
+pub struct PlayHistory {
+    current: RefCell<Option<Current>>,
+    library: Library,
+    scrobbler: Option<Scrobbler>,
+    state: Arc<Mutex<State>>,
+}
+
+impl PlayHistory {
+    pub(crate) fn new(state: Arc<Mutex<State>>, scrobbler: Option<Scrobbler>) -> Self {
+        let library = Library::open();
+        state.lock().unwrap().plays = library.play_stats();
+        PlayHistory {
+            current: RefCell::new(None),
+            library,
+            scrobbler,
+            state,
+        }
+    }
+
+    /// Follows the file playing, recording the play of the previous one. The tags listens
+    /// are submitted with are only asked to `track` once it changed. Returns the path
+    /// whose statistics changed, if any.
+    pub fn load<F: FnOnce() -> Option<ListenedTrack>>(&self, path: Option<&str>, track: F) -> Option<String> {
+        if self.current.borrow().as_ref().map(|current| current.path.as_str()) == path {
+            return None;
+        }
+        let recorded = self.record(false);
+        *self.current.borrow_mut() = path.map(|path| Current {
+            path: path.to_string(),
+            played_at: now(),
+            listened: 0,
+            time: None,
+            track: track(),
+        });
+        recorded
+    }
+
+    /// Counts the time heard since the last update.
+    pub fn update(&self) {
+        let (time, stopped) = {
+            let state = self.state.lock().unwrap();
+            (state.current_time, state.stopped)
+        };
+        if let Some(current) = self.current.borrow_mut().as_mut() {
+            if let Some(previous) = current.time {
+                if !stopped && time > previous && time - previous <= MAX_STEP_MILLIS {
+                    current.listened += time - previous;
+                }
+            }
+            current.time = Some(time);
+        }
+    }
+
+    /// Records the play of the file playing once it played to its end. Returns its path.
+    pub fn finish(&self) -> Option<String> {
+        self.record(true)
+    }
+
+    fn record(&self, completed: bool) -> Option<String> {
+        let current = self.current.borrow_mut().take()?;
+        // A file loaded but never heard, e.g. restored paused, was not played.
+        if current.listened == 0 {
+            return None;
+        }
+        let duration = self.state.lock().unwrap().duration(&current.path);
+        let play = Play {
+            skipped: !completed && duration.map(|duration| current.listened * 2 < duration).unwrap_or(false),
+            path: current.path,
+            played_at: current.played_at,
+            listened: current.listened,
+            completed,
+        };
+        if let (Some(scrobbler), Some(track)) = (self.scrobbler.as_ref(), current.track.as_ref()) {
+            scrobbler.log(&play, duration, track);
+        }
+        self.library.record_play(&play).ok()?;
+        let stats = self.library.play_stats_of(&play.path);
+        let mut state = self.state.lock().unwrap();
+        match stats {
+            Some(stats) => state.plays.insert(play.path.clone(), stats),
+            None => state.plays.remove(&play.path),
+        };
+        Some(play.path)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() as i64).unwrap_or(0)
+}
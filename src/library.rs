@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::scanner::{collect_files, TrackInfo};
+use crate::xdg;
+
+const LIBRARY_FILE: &str = "library.db";
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const COMMIT_BATCH_SIZE: usize = 200;
+
+/// Schema changes, applied in order; `PRAGMA user_version` counts the applied ones.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE IF NOT EXISTS tracks (
+        path TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        genre TEXT NOT NULL,
+        year INTEGER,
+        track INTEGER,
+        total_tracks INTEGER,
+        modified INTEGER NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tracks_artist ON tracks (artist, album);
+    CREATE INDEX IF NOT EXISTS tracks_genre ON tracks (genre);
+", "
+    ALTER TABLE tracks ADD COLUMN hash INTEGER;
+    CREATE INDEX tracks_hash ON tracks (hash);
+", "
+    CREATE TABLE positions (
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (path, size)
+    );
+    CREATE TABLE bookmarks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL
+    );
+    CREATE INDEX bookmarks_file ON bookmarks (path, size);
+", "
+    CREATE TABLE plays (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL,
+        played_at INTEGER NOT NULL,
+        listened INTEGER NOT NULL,
+        skipped INTEGER NOT NULL,
+        completed INTEGER NOT NULL
+    );
+    CREATE INDEX plays_path ON plays (path);
+", "
+    ALTER TABLE tracks ADD COLUMN rating INTEGER;
+    -- Has the next sync read every file again, for its rating.
+    UPDATE tracks SET modified = 0;
+"];
+
+const HASH_SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Restricts library queries, `None` meaning any value. Unknown tags are empty strings.
+#[derive(Clone, Default)]
+pub struct Selection {
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+/// A named position in a file, in milliseconds.
+pub struct Bookmark {
+    pub id: i64,
+    pub position: u64,
+    pub name: String,
+}
+
+/// A play of a file: when it started, in seconds since the epoch, and how much of it was
+/// heard, in milliseconds.
+pub struct Play {
+    pub path: String,
+    pub played_at: i64,
+    pub listened: u64,
+    /// Set when less than half of the file was heard.
+    pub skipped: bool,
+    /// Set when the file played to its end.
+    pub completed: bool,
+}
+
+/// How often a file was played, not counting skips, and when last.
+#[derive(Clone, Copy)]
+pub struct PlayStats {
+    pub count: u32,
+    pub last_played: i64,
+}
+
+const SELECTION_CLAUSE: &str = "
+    (?1 IS NULL OR genre = ?1) AND (?2 IS NULL OR year = ?2)
+    AND (?3 IS NULL OR artist = ?3) AND (?4 IS NULL OR album = ?4)";
+
+/// The indexed local library, an SQLite database in `$XDG_DATA_HOME/rusic/library.db`
+/// filled from the music folders set in the config.
+pub struct Library {
+    connection: Connection,
+}
+
+impl Library {
+    /// Opens the library database, or an empty in-memory one for the session if the file
+    /// cannot be opened.
+    pub fn open() -> Self {
+        let connection = Connection::open(xdg::data_file(LIBRARY_FILE))
+            .and_then(migrate)
+            .or_else(|_| Connection::open_in_memory().and_then(migrate))
+            .expect("Library initialization failed");
+        // The GTK thread reads while a rescan writes from another connection.
+        let _ = connection.busy_timeout(BUSY_TIMEOUT);
+        Library {
+            connection,
+        }
+    }
+
+    fn distinct(&self, column: &str, selection: &Selection) -> Vec<String> {
+        let sql = format!("SELECT DISTINCT {0} FROM tracks WHERE {1} ORDER BY {0} COLLATE NOCASE",
+                          column, SELECTION_CLAUSE);
+        let mut statement = match self.connection.prepare(&sql) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new(),
+        };
+        let params = params![selection.genre, selection.year, selection.artist, selection.album];
+        statement.query_map(params, |row| row.get(0))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn genres(&self) -> Vec<String> {
+        self.distinct("genre", &Selection::default())
+    }
+
+    pub fn years(&self) -> Vec<i32> {
+        self.distinct("year", &Selection::default()).iter()
+            .filter_map(|year| year.parse().ok())
+            .collect()
+    }
+
+    /// The artists within the genre and year of `selection`.
+    pub fn artists(&self, selection: &Selection) -> Vec<String> {
+        let selection = Selection {
+            artist: None,
+            album: None,
+            ..selection.clone()
+        };
+        self.distinct("artist", &selection)
+    }
+
+    /// The albums within the genre, year and artist of `selection`.
+    pub fn albums(&self, selection: &Selection) -> Vec<String> {
+        let selection = Selection {
+            album: None,
+            ..selection.clone()
+        };
+        self.distinct("album", &selection)
+    }
+
+    /// Returns the `(title, path)` of the tracks matching `selection`, in album order.
+    pub fn tracks(&self, selection: &Selection) -> Vec<(String, String)> {
+        let sql = format!("SELECT title, path FROM tracks WHERE {}
+                           ORDER BY artist COLLATE NOCASE, album COLLATE NOCASE, track, path",
+                          SELECTION_CLAUSE);
+        let mut statement = match self.connection.prepare(&sql) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new(),
+        };
+        let params = params![selection.genre, selection.year, selection.artist, selection.album];
+        statement.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the paths of the tracks matching an SQL condition, e.g. built by a smart playlist.
+    pub fn select_paths(&self, clause: &str, params: Vec<Value>, order_by: &str, limit: Option<u32>) -> Vec<String> {
+        let mut sql = format!("SELECT path FROM tracks WHERE {} ORDER BY {}", clause, order_by);
+        if let Some(limit) = limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        let mut statement = match self.connection.prepare(&sql) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new(),
+        };
+        statement.query_map(params_from_iter(params), |row| row.get(0))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    fn known_files(&self) -> HashMap<String, (i64, i64)> {
+        let mut statement = match self.connection.prepare("SELECT path, modified, size FROM tracks") {
+            Ok(statement) => statement,
+            Err(_) => return HashMap::new(),
+        };
+        statement.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    fn store(connection: &Connection, path: &Path, (modified, size): (i64, i64)) -> rusqlite::Result<()> {
+        let info = TrackInfo::read(path);
+        let title = info.title.clone().unwrap_or_else(|| info.filename());
+        connection.execute("INSERT OR REPLACE INTO tracks
+                            (path, title, artist, album, genre, year, track, total_tracks, modified, size, hash, rating)
+                            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                           params![info.path, title, info.artist.as_deref().unwrap_or_default(),
+                                   info.album.as_deref().unwrap_or_default(),
+                                   info.genre.as_deref().unwrap_or_default(),
+                                   info.year, info.track, info.total_tracks, modified, size,
+                                   content_hash(path), info.rating])?;
+        Ok(())
+    }
+
+    /// Reads `path` again, e.g. after it was added or retagged.
+    pub fn update_file(&self, path: &Path) -> rusqlite::Result<()> {
+        Self::store(&self.connection, path, file_stamp(path))
+    }
+
+    /// Drops `path`, or everything under it for a folder, and returns the content hashes
+    /// of the dropped tracks by path.
+    pub fn remove_path(&self, path: &Path) -> Vec<(i64, PathBuf)> {
+        let path = path.to_string_lossy();
+        let removed = self.connection.prepare(&format!("SELECT hash, path FROM tracks WHERE {}", UNDER_PATH_CLAUSE))
+            .and_then(|mut statement| {
+                statement.query_map([&path], |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, String>(1)?)))
+                    .map(|rows| rows.filter_map(Result::ok)
+                        .filter_map(|(hash, path)| Some((hash?, PathBuf::from(path))))
+                        .collect())
+            })
+            .unwrap_or_default();
+        let _ = self.connection.execute(&format!("DELETE FROM tracks WHERE {}", UNDER_PATH_CLAUSE), [&path]);
+        removed
+    }
+
+    /// Moves the tracks at `from`, or under it for a folder, to `to`, along with everything
+    /// kept about them: plays, resume positions and bookmarks.
+    pub fn rename_path(&self, from: &Path, to: &Path) -> rusqlite::Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        for table in &["tracks", "plays", "positions", "bookmarks"] {
+            transaction.execute(&format!("UPDATE OR REPLACE {} SET path = ?2 || substr(path, length(?1) + 1) WHERE {}",
+                                         table, UNDER_PATH_CLAUSE),
+                                [from.to_string_lossy(), to.to_string_lossy()])?;
+        }
+        transaction.commit()
+    }
+
+    /// The position playback of the file at `path` with `size` bytes was left at.
+    pub fn resume_position(&self, path: &str, size: i64) -> Option<u64> {
+        self.connection.query_row("SELECT position FROM positions WHERE path = ?1 AND size = ?2",
+                                  params![path, size], |row| row.get::<_, i64>(0))
+            .ok()
+            .map(|position| position as u64)
+    }
+
+    /// Remembers where playback of a file was left, or forgets it for `None`.
+    pub fn set_resume_position(&self, path: &str, size: i64, position: Option<u64>) {
+        let _ = match position {
+            Some(position) => self.connection.execute("INSERT OR REPLACE INTO positions (path, size, position)
+                                                       VALUES (?1, ?2, ?3)",
+                                                      params![path, size, position as i64]),
+            None => self.connection.execute("DELETE FROM positions WHERE path = ?1 AND size = ?2",
+                                            params![path, size]),
+        };
+    }
+
+    /// The bookmarks of a file, in time order.
+    pub fn bookmarks(&self, path: &str, size: i64) -> Vec<Bookmark> {
+        let mut statement = match self.connection.prepare("SELECT id, position, name FROM bookmarks
+                                                           WHERE path = ?1 AND size = ?2 ORDER BY position") {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new(),
+        };
+        statement.query_map(params![path, size], |row| {
+            Ok(Bookmark {
+                id: row.get(0)?,
+                position: row.get::<_, i64>(1)? as u64,
+                name: row.get(2)?,
+            })
+        })
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn add_bookmark(&self, path: &str, size: i64, position: u64, name: &str) -> rusqlite::Result<()> {
+        self.connection.execute("INSERT INTO bookmarks (path, size, position, name) VALUES (?1, ?2, ?3, ?4)",
+                                params![path, size, position as i64, name])?;
+        Ok(())
+    }
+
+    pub fn remove_bookmark(&self, id: i64) {
+        let _ = self.connection.execute("DELETE FROM bookmarks WHERE id = ?1", [id]);
+    }
+
+    pub fn record_play(&self, play: &Play) -> rusqlite::Result<()> {
+        self.connection.execute("INSERT INTO plays (path, played_at, listened, skipped, completed)
+                                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                                params![play.path, play.played_at, play.listened as i64, play.skipped, play.completed])?;
+        Ok(())
+    }
+
+    /// The statistics of every file played at least once without skipping.
+    pub fn play_stats(&self) -> HashMap<String, PlayStats> {
+        self.query_play_stats("", [])
+    }
+
+    pub fn play_stats_of(&self, path: &str) -> Option<PlayStats> {
+        self.query_play_stats("AND path = ?1", [path]).remove(path)
+    }
+
+    fn query_play_stats<P: rusqlite::Params>(&self, clause: &str, params: P) -> HashMap<String, PlayStats> {
+        let sql = format!("SELECT path, COUNT(*), MAX(played_at) FROM plays WHERE NOT skipped {} GROUP BY path", clause);
+        let mut statement = match self.connection.prepare(&sql) {
+            Ok(statement) => statement,
+            Err(_) => return HashMap::new(),
+        };
+        statement.query_map(params, |row| Ok((row.get(0)?, PlayStats { count: row.get(1)?, last_played: row.get(2)? })))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    /// The files played last, skips included, most recent first.
+    pub fn recently_played(&self, limit: u32) -> Vec<String> {
+        self.history_paths("SELECT path FROM plays GROUP BY path ORDER BY MAX(played_at) DESC LIMIT ?1", limit)
+    }
+
+    /// The files played the most, not counting skips.
+    pub fn most_played(&self, limit: u32) -> Vec<String> {
+        self.history_paths("SELECT path FROM plays WHERE NOT skipped GROUP BY path
+                            ORDER BY COUNT(*) DESC, MAX(played_at) DESC LIMIT ?1", limit)
+    }
+
+    /// The library tracks never played without skipping, in album order.
+    pub fn never_played(&self) -> Vec<String> {
+        self.select_paths("path NOT IN (SELECT path FROM plays WHERE NOT skipped)", Vec::new(),
+                          "artist COLLATE NOCASE, album COLLATE NOCASE, track, path", None)
+    }
+
+    fn history_paths(&self, sql: &str, limit: u32) -> Vec<String> {
+        let mut statement = match self.connection.prepare(sql) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new(),
+        };
+        statement.query_map([limit], |row| row.get(0))
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    /// Brings the library in line with the files in `folders`: new and modified files are
+    /// (re)read, files that are gone are dropped. `changed` is set after every committed batch.
+    fn sync(&mut self, folders: &[PathBuf], changed: &AtomicBool) {
+        let mut known = self.known_files();
+        let mut stale = Vec::new();
+        for folder in folders {
+            let mut files = Vec::new();
+            collect_files(folder, &mut files);
+            for file in files {
+                let path = file.to_string_lossy().to_string();
+                let stamp = file_stamp(&file);
+                if known.remove(&path) != Some(stamp) {
+                    stale.push((file, stamp));
+                }
+            }
+        }
+
+        for batch in stale.chunks(COMMIT_BATCH_SIZE) {
+            let transaction = match self.connection.transaction() {
+                Ok(transaction) => transaction,
+                Err(_) => return,
+            };
+            for (file, stamp) in batch {
+                let _ = Self::store(&transaction, file, *stamp);
+            }
+            if transaction.commit().is_ok() {
+                changed.store(true, Ordering::SeqCst);
+            }
+        }
+
+        if let Ok(transaction) = self.connection.transaction() {
+            for path in known.keys() {
+                let _ = transaction.execute("DELETE FROM tracks WHERE path = ?1", [path]);
+            }
+            if !known.is_empty() && transaction.commit().is_ok() {
+                changed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Matches `?1` and, for a folder, every path under it.
+const UNDER_PATH_CLAUSE: &str = "path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'";
+
+fn migrate(connection: Connection) -> rusqlite::Result<Connection> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        connection.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, index + 1))?;
+    }
+    Ok(connection)
+}
+
+/// A cheap fingerprint of a file, used to recognize it after a move: FNV-1a over its size and
+/// its first and last 64 KiB.
+pub fn content_hash(path: &Path) -> Option<i64> {
+    let mut file = fs::File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut sample = Vec::new();
+    (&mut file).take(HASH_SAMPLE_SIZE).read_to_end(&mut sample).ok()?;
+    if size > HASH_SAMPLE_SIZE {
+        file.seek(SeekFrom::Start(size.saturating_sub(HASH_SAMPLE_SIZE).max(HASH_SAMPLE_SIZE))).ok()?;
+        file.take(HASH_SAMPLE_SIZE).read_to_end(&mut sample).ok()?;
+    }
+
+    Some(fnv1a(size.to_le_bytes().iter().chain(&sample)) as i64)
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The modification time, in seconds, and size of a file, used to skip unchanged files.
+fn file_stamp(path: &Path) -> (i64, i64) {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let modified = metadata.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0);
+            (modified, metadata.len() as i64)
+        },
+        Err(_) => (0, 0),
+    }
+}
+
+/// A background rescan of the music folders into the library.
+pub struct Rescan {
+    changed: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Rescan {
+    pub fn start(folders: Vec<PathBuf>) -> Self {
+        let changed = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        {
+            let changed = changed.clone();
+            let finished = finished.clone();
+            thread::spawn(move || {
+                Library::open().sync(&folders, &changed);
+                finished.store(true, Ordering::SeqCst);
+            });
+        }
+        Rescan {
+            changed,
+            finished,
+        }
+    }
+
+    /// Whether the library changed since the last call.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,298 @@
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use gtk::{
+    CellLayoutExt,
+    CellRendererText,
+    ContainerExt,
+    Label,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ScrolledWindow,
+    SelectionMode,
+    TreeModelExt,
+    TreePath,
+    TreeSelectionExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewExt,
+    ToValue,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::Vertical;
+
+use id3::Tag;
+
+use crate::metadata::split_id3_string;
+
+const TEXT_COLUMN: u32 = 0;
+const TIME_COLUMN: u32 = 1;
+const WEIGHT_COLUMN: u32 = 2;
+const PANEL_WIDTH: i32 = 260;
+const WEIGHT_NORMAL: i32 = 400;
+const WEIGHT_BOLD: i32 = 700;
+/// The SYLT time stamp format counting milliseconds, rather than MPEG frames.
+const SYLT_MILLISECONDS: u8 = 2;
+
+pub enum Lyrics {
+    Plain(String),
+    /// Lines with their start time in milliseconds, in order.
+    Synced(Vec<(u64, String)>),
+}
+
+/// Reads the lyrics of `path`, preferring synchronised ones: a SYLT frame or a `.lrc` file
+/// next to the track, then a USLT frame or a `.txt` file.
+pub fn read_lyrics(path: &Path) -> Option<Lyrics> {
+    let tag = Tag::read_from_path(path).ok();
+    let sidecar = |extension: &str| {
+        fs::read(path.with_extension(extension)).ok()
+            .map(|data| String::from_utf8_lossy(&data).into_owned())
+    };
+
+    tag.as_ref()
+        .and_then(|tag| tag.get("SYLT"))
+        .and_then(|frame| frame.content().unknown())
+        .and_then(parse_sylt)
+        .filter(|lines| !lines.is_empty())
+        .or_else(|| sidecar("lrc").map(|text| parse_lrc(&text)).filter(|lines| !lines.is_empty()))
+        .map(Lyrics::Synced)
+        .or_else(|| {
+            tag.as_ref()
+                .and_then(|tag| tag.lyrics().next())
+                .map(|lyrics| lyrics.text.clone())
+                .or_else(|| sidecar("txt"))
+                .filter(|text| !text.trim().is_empty())
+                .map(Lyrics::Plain)
+        })
+}
+
+/// Parses LRC lyrics: lines prefixed by one or more `[mm:ss.xx]` time tags, shifted by an
+/// `[offset:ms]` tag. Word times of the enhanced format, `<mm:ss.xx>`, are dropped.
+fn parse_lrc(text: &str) -> Vec<(u64, String)> {
+    let mut offset: i64 = 0;
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        while rest.starts_with('[') {
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => break,
+            };
+            let tag = &rest[1..end];
+            if let Some(time) = parse_time(tag) {
+                times.push(time);
+            } else if let Some(value) = tag.strip_prefix("offset:") {
+                offset = value.trim().parse().unwrap_or(0);
+            }
+            rest = &rest[end + 1..];
+        }
+        let text = strip_word_times(rest);
+        lines.extend(times.into_iter().map(|time| (time, text.clone())));
+    }
+    // A positive offset shows the lyrics sooner.
+    for (time, _) in &mut lines {
+        *time = (*time as i64 - offset).max(0) as u64;
+    }
+    lines.sort_by_key(|(time, _)| *time);
+    lines
+}
+
+/// Parses `mm:ss`, `mm:ss.xx` or `mm:ss:xx` into milliseconds.
+fn parse_time(tag: &str) -> Option<u64> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    let seconds: f64 = seconds.trim().replacen(':', ".", 1).parse().ok()?;
+    if seconds < 0.0 {
+        return None;
+    }
+    Some(minutes * 60_000 + (seconds * 1000.0).round() as u64)
+}
+
+fn strip_word_times(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_time(&rest[start + 1..start + end]).is_some() => {
+                result.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            },
+            _ => {
+                result.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            },
+        }
+    }
+    result.push_str(rest);
+    result.trim().to_string()
+}
+
+/// Parses the content of a SYLT frame timed in milliseconds. Entries starting with a line
+/// break begin a line and the others are appended to it, so that both line-timed and
+/// syllable-timed frames give lines.
+fn parse_sylt(data: &[u8]) -> Option<Vec<(u64, String)>> {
+    let (&encoding, rest) = data.split_first()?;
+    // Language, time stamp format and content type.
+    if rest.len() < 5 || rest[3] != SYLT_MILLISECONDS {
+        return None;
+    }
+    let (_, mut rest) = split_id3_string(&rest[5..], encoding);
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let (text, after) = split_id3_string(rest, encoding);
+        if after.len() < 4 {
+            break;
+        }
+        let time = u32::from_be_bytes(after[..4].try_into().unwrap());
+        entries.push((u64::from(time), text));
+        rest = &after[4..];
+    }
+
+    let by_syllable = entries.iter().any(|(_, text)| text.starts_with(['\n', '\r']));
+    let mut lines: Vec<(u64, String)> = Vec::new();
+    for (time, text) in entries {
+        match lines.last_mut() {
+            Some((_, line)) if by_syllable && !text.starts_with(['\n', '\r']) => line.push_str(&text),
+            _ => lines.push((time, text.trim_start().to_string())),
+        }
+    }
+    Some(lines)
+}
+
+/// Shows the lyrics of the playing track, following synchronised lyrics as it plays.
+/// Clicking a synchronised line seeks to it.
+pub struct LyricsPane {
+    current_line: Cell<Option<usize>>,
+    model: ListStore,
+    panel: gtk::Box,
+    path: RefCell<Option<String>>,
+    synced: Rc<Cell<bool>>,
+    times: RefCell<Vec<u64>>,
+    treeview: TreeView,
+}
+
+impl LyricsPane {
+    pub fn new() -> Self {
+        let model = ListStore::new(&[Type::String, Type::U64, Type::I32]);
+
+        let treeview = TreeView::new_with_model(&model);
+        treeview.set_headers_visible(false);
+        treeview.set_activate_on_single_click(true);
+        treeview.get_selection().set_mode(SelectionMode::None);
+        let view_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        view_column.pack_start(&cell, true);
+        view_column.add_attribute(&cell, "text", TEXT_COLUMN as i32);
+        view_column.add_attribute(&cell, "weight", WEIGHT_COLUMN as i32);
+        treeview.append_column(&view_column);
+
+        let scrolled_window = ScrolledWindow::new(None, None);
+        scrolled_window.set_vexpand(true);
+        scrolled_window.add(&treeview);
+
+        let panel = gtk::Box::new(Vertical, 5);
+        panel.set_size_request(PANEL_WIDTH, -1);
+        panel.add(&Label::new("Lyrics"));
+        panel.add(&scrolled_window);
+
+        LyricsPane {
+            current_line: Cell::new(None),
+            model,
+            panel,
+            path: RefCell::new(None),
+            synced: Rc::new(Cell::new(false)),
+            times: RefCell::new(Vec::new()),
+            treeview,
+        }
+    }
+
+    pub fn view(&self) -> &gtk::Box {
+        &self.panel
+    }
+
+    /// Calls `seek` with the time of a clicked synchronised line.
+    pub fn connect_seek<F: Fn(u64) + 'static>(&self, seek: F) {
+        let model = self.model.clone();
+        let synced = self.synced.clone();
+        self.treeview.connect_row_activated(move |_, path, _| {
+            if !synced.get() {
+                return;
+            }
+            if let Some(iter) = model.get_iter(path) {
+                if let Some(time) = model.get_value(&iter, TIME_COLUMN as i32).get::<u64>() {
+                    seek(time);
+                }
+            }
+        });
+    }
+
+    /// Shows the lyrics of `path` unless they already are.
+    pub fn load(&self, path: Option<&str>) {
+        if self.path.borrow().as_deref() == path {
+            return;
+        }
+        *self.path.borrow_mut() = path.map(str::to_string);
+
+        self.model.clear();
+        self.current_line.set(None);
+        let mut times = self.times.borrow_mut();
+        times.clear();
+        self.synced.set(false);
+        match path.and_then(|path| read_lyrics(Path::new(path))) {
+            Some(Lyrics::Synced(lines)) => {
+                self.synced.set(true);
+                for (time, text) in lines {
+                    let iter = self.model.append();
+                    self.model.set(&iter, &[TEXT_COLUMN, TIME_COLUMN, WEIGHT_COLUMN], &[&text, &time, &WEIGHT_NORMAL]);
+                    times.push(time);
+                }
+            },
+            Some(Lyrics::Plain(text)) => {
+                for line in text.lines() {
+                    let iter = self.model.append();
+                    self.model.set(&iter, &[TEXT_COLUMN, WEIGHT_COLUMN], &[&line, &WEIGHT_NORMAL]);
+                }
+            },
+            None => {
+                if path.is_some() {
+                    let iter = self.model.append();
+                    self.model.set(&iter, &[TEXT_COLUMN, WEIGHT_COLUMN], &[&"(no lyrics)", &WEIGHT_NORMAL]);
+                }
+            },
+        }
+    }
+
+    /// Highlights the synchronised line sung at `time` and scrolls to it.
+    pub fn update(&self, time: u64) {
+        if !self.synced.get() {
+            return;
+        }
+        let line = self.times.borrow().partition_point(|&start| start <= time).checked_sub(1);
+        if line == self.current_line.get() {
+            return;
+        }
+        if let Some(previous) = self.current_line.replace(line) {
+            self.set_weight(previous, WEIGHT_NORMAL);
+        }
+        if let Some(line) = line {
+            self.set_weight(line, WEIGHT_BOLD);
+            let path = TreePath::new_from_string(&line.to_string());
+            self.treeview.scroll_to_cell(Some(&path), None, true, 0.5, 0.0);
+        }
+    }
+
+    fn set_weight(&self, line: usize, weight: i32) {
+        let path = TreePath::new_from_string(&line.to_string());
+        if let Some(iter) = self.model.get_iter(&path) {
+            self.model.set_value(&iter, WEIGHT_COLUMN, &weight.to_value());
+        }
+    }
+}
@@ -1,28 +1,83 @@
 mod toolbar;
+mod cover;
+mod watcher;
+mod bookmarks;
+mod browser;
+mod chapters;
+mod cleanup;
+mod config;
+mod history;
+mod library;
+mod lyrics;
+mod menu;
+mod metadata;
 mod playlist;
 mod mp3;
 mod player;
+mod queue;
+mod rating;
+mod scanner;
+mod scrobble;
+mod search;
+mod session;
+mod shortcuts;
+mod smart;
+mod stretch;
+mod tabs;
+mod tag_editor;
+mod xdg;
 
 extern crate gio;
+extern crate gdk;
 extern crate gtk;
+extern crate glib;
 extern crate gdk_pixbuf;
 extern crate id3;
 extern crate gtk_sys;
 extern crate crossbeam;
 extern crate pulse_simple;
 extern crate simplemad;
+extern crate simplemad_sys;
 extern crate m3u;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+extern crate rusqlite;
+extern crate inotify;
+extern crate encoding_rs;
+extern crate chardetng;
 
-use toolbar::{MusicToolbar, show_open_dialog, show_save_dialog};
+use toolbar::{MusicToolbar, show_error_dialog, show_folder_dialog, show_image_dialog, show_open_dialog, show_save_dialog};
 use playlist::Playlist;
+use tabs::PlaylistTabs;
+use bookmarks::{BookmarkList, escape_markup, show_bookmark_dialog};
+use browser::Browser;
+use chapters::ChapterList;
+use cleanup::{show_cleanup_dialog, Measurement};
+use library::{Library, Rescan};
+use lyrics::LyricsPane;
+use watcher::{Change, Watcher};
+use menu::PlaylistMenu;
+use queue::Queue;
+use config::Config;
+use history::PlayHistory;
+use scrobble::Scrobbler;
+use session::{SavedPlaylist, Session};
+use smart::show_smart_playlist_dialog;
+use cover::read_picture;
+use tag_editor::{show_tag_dialog, write_tags, TagChanges, TagEdit};
 
 use std::env;
-use std::rc::Rc;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
-use gio::{ApplicationExt, ApplicationExtManual, ApplicationFlags};
+use gdk::{DragAction, ModifierType};
+use gio::{ApplicationExt, ApplicationExtManual, ApplicationFlags, FileExt};
 use gtk::{
     Application,
     ApplicationWindow,
@@ -30,42 +85,86 @@ use gtk::{
     GtkWindowExt,
     ContainerExt,
     ToolButtonExt,
+    ButtonExt,
     Label,
     LabelExt,
 };
 
 use gtk::{
     Adjustment,
+    Align,
     Image,
+    PositionType,
     Scale,
     ScaleExt,
     AdjustmentExt,
     Continue,
-    ButtonsType,
-    DialogFlags,
-    DialogExt,
-    MessageDialog,
-    MessageType,
+    ProgressBar,
+    ProgressBarExt,
+    SearchEntry,
+    SearchEntryExt,
+    Entry,
+    EntryExt,
+    ScaleButtonExt,
+    VolumeButton,
+    SpinButton,
+    SpinButtonExt,
+};
+
+use gtk::{
+    Cast,
+    DestDefaults,
+    Inhibit,
+    MenuExtManual,
+    MenuItemExt,
+    ToggleToolButtonExt,
+    TargetEntry,
+    TargetFlags,
+    WidgetExtManual,
 };
 
 use crate::toolbar::set_cover;
 use crate::player::State;
+use crate::scanner::{is_playlist, is_supported, Scan};
+use crate::shortcuts::{add_action, set_accels, show_shortcuts_window};
 
 use gtk::Orientation::{Horizontal, Vertical};
 
 
 const PLAY_STOCK: &str = "gtk-media-play";
 const PAUSE_STOCK: &str = "gtk-media-pause";
+const IMPORT_BATCH_SIZE: usize = 200;
+const LIBRARY_POLL_MILLIS: u32 = 500;
+const URI_LIST_TARGET: u32 = 0;
+const ROWS_TARGET: u32 = 1;
+const SPEED_STEP: f64 = 0.05;
+const SESSION_SAVE_SECONDS: u32 = 30;
+const SEEK_STEP_MILLIS: u64 = 5_000;
+const VOLUME_STEP: f64 = 0.05;
 
 
 struct App {
     adjustment: Adjustment,
+    bookmarks: Rc<BookmarkList>,
+    browser: Rc<Browser>,
+    chapters: Rc<ChapterList>,
+    config: Rc<RefCell<Config>>,
     cover: Image,
     current_time_label: Label,
     duration_label: Label,
-    playlist: Rc<Playlist>,
+    footer_label: Label,
+    history: Rc<PlayHistory>,
+    import_progress: ProgressBar,
+    lyrics: Rc<LyricsPane>,
+    menu: PlaylistMenu,
+    scale: Scale,
+    search_entry: SearchEntry,
+    speed_button: SpinButton,
     state: Arc<Mutex<State>>,
+    tabs: Rc<PlaylistTabs>,
     toolbar: MusicToolbar,
+    volume_button: VolumeButton,
+    watcher: Watcher,
     window: ApplicationWindow,
 }
 
@@ -81,15 +180,54 @@ impl App {
         vbox.add(toolbar.toolbar());
 
         let current_time = 0;
-        let durations = HashMap::new();
+        let properties = HashMap::new();
         let state = Arc::new(Mutex::new(State {
             current_time,
-            durations,
+            ended: false,
+            plays: HashMap::new(),
+            properties,
             stopped: true,
         }));
 
-        let playlist = Rc::new(Playlist::new(state.clone()));
-        vbox.add(playlist.view());
+        let search_entry = SearchEntry::new();
+        search_entry.set_placeholder_text("Search, e.g. artist:davis year:>1960");
+        vbox.add(&search_entry);
+
+        let playlist_box = gtk::Box::new(Horizontal, 10);
+        vbox.add(&playlist_box);
+
+        let config = Rc::new(RefCell::new(Config::load()));
+        metadata::set_legacy_encoding(config.borrow().legacy_encoding.as_deref());
+        rating::set_rating_email(config.borrow().rating_email.as_deref());
+        let scrobbler = if config.borrow().scrobble {
+            let config = config.borrow();
+            let scrobbler = Scrobbler::new(config.scrobble_endpoint.clone(), config.scrobble_token.clone());
+            // Listens logged while offline go out at the next start.
+            scrobbler.submit();
+            Some(scrobbler)
+        } else {
+            None
+        };
+        let history = Rc::new(PlayHistory::new(state.clone(), scrobbler));
+        let browser = Browser::new(Library::open());
+        browser.set_smart_playlists(&config.borrow().smart_playlists);
+        let queue = Rc::new(Queue::new());
+        let tabs = PlaylistTabs::new(&window, state.clone(), queue.clone(), config.clone());
+        playlist_box.add(browser.view());
+        playlist_box.add(tabs.view());
+        playlist_box.add(queue.view());
+        let chapters = Rc::new(ChapterList::new());
+        playlist_box.add(chapters.view());
+        let bookmarks = Rc::new(BookmarkList::new(config.borrow().audiobook_mode));
+        playlist_box.add(bookmarks.view());
+        toolbar.audiobook_button.set_active(config.borrow().audiobook_mode);
+        let lyrics = Rc::new(LyricsPane::new());
+        playlist_box.add(lyrics.view());
+
+        let footer_label = Label::new(None);
+        footer_label.set_halign(Align::Start);
+        footer_label.set_margin_left(10);
+        vbox.add(&footer_label);
 
         let cover = Image::new();
         vbox.add(&cover);
@@ -110,38 +248,265 @@ impl App {
         hbox.add(&slash_label);
 
         let duration_label = Label::new(None);
-        duration_label.set_margin_right(10);
         hbox.add(&duration_label);
 
+        let volume_button = VolumeButton::new();
+        volume_button.set_value(1.0);
+        hbox.add(&volume_button);
+
+        let speed_button = SpinButton::new_with_range(stretch::MIN_SPEED, stretch::MAX_SPEED, SPEED_STEP);
+        speed_button.set_digits(2);
+        speed_button.set_value(1.0);
+        speed_button.set_tooltip_text("Playback speed");
+        speed_button.set_margin_right(10);
+        hbox.add(&speed_button);
+
+        let import_progress = ProgressBar::new();
+        import_progress.set_show_text(true);
+        vbox.add(&import_progress);
+
         window.show_all();
+        import_progress.hide();
 
         let app = App {
             adjustment,
+            bookmarks,
+            browser,
+            chapters,
+            config,
             cover,
             current_time_label,
             duration_label,
-            playlist,
+            footer_label,
+            history,
+            import_progress,
+            lyrics,
+            menu: PlaylistMenu::new(),
+            scale,
+            search_entry,
+            speed_button,
             state,
+            tabs,
             toolbar,
+            volume_button,
+            watcher: Watcher::new(),
             window,
         };
 
+        app.connect_tab_events();
         app.connect_events();
+        app.connect_bookmark_events();
+        app.connect_browser_events();
+        app.connect_search_events();
+        app.connect_menu_events();
         app.connect_toolbar_events();
+        app.connect_shortcuts(&application);
+        let folders = app.config.borrow().music_folders.clone();
+        for folder in &folders {
+            app.watcher.watch(folder.clone());
+        }
+        rescan(&app.browser, folders);
+        app.restore_session(Session::load());
         app
     }
 
+    /// Restores the modes, playlists, selections and current track of the last session, then
+    /// saves the session periodically and on quit. Nothing gets saved before the playlists
+    /// are back, which would lose the part not imported yet.
+    fn restore_session(&self, session: Session) {
+        self.toolbar.shuffle_button.set_active(session.shuffle);
+        self.toolbar.repeat_button.set_active(session.repeat);
+        self.speed_button.set_value(session.speed.unwrap_or(1.0));
+        self.volume_button.set_value(session.volume.unwrap_or(1.0));
+
+        let mut saved_playlists = session.playlists;
+        if saved_playlists.is_empty() {
+            saved_playlists.push(SavedPlaylist {
+                name: "Playlist".to_string(),
+                ..SavedPlaylist::default()
+            });
+        }
+        let playlists: Vec<_> = saved_playlists.iter().map(|saved| self.tabs.add(&saved.name)).collect();
+        self.tabs.set_current_index(session.current_tab);
+        if let Some(playing) = session.playing_tab.and_then(|index| playlists.get(index)) {
+            self.tabs.set_playing(playing);
+        }
+
+        let restored = Rc::new(Cell::new(false));
+        let remaining = Rc::new(Cell::new(playlists.len()));
+        let cover = self.cover.clone();
+        let resume = self.config.borrow().resume_session;
+        let speed_button = self.speed_button.clone();
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        let volume_button = self.volume_button.clone();
+        let current = session.current;
+        let position = session.position;
+        let all_restored = restored.clone();
+        let finished = Rc::new(move || {
+            remaining.set(remaining.get() - 1);
+            if remaining.get() > 0 {
+                return;
+            }
+            if let Some(current) = current.as_ref() {
+                let playlist = tabs.playing();
+                playlist.restore_current(current, if resume { Some(position) } else { None });
+                if resume {
+                    set_cover(&cover, &playlist);
+                }
+            }
+            all_restored.set(true);
+            let speed_button = speed_button.clone();
+            let state = state.clone();
+            let tabs = tabs.clone();
+            let volume_button = volume_button.clone();
+            gtk::timeout_add_seconds(SESSION_SAVE_SECONDS, move || {
+                save_session(&tabs, &speed_button, &volume_button, &state);
+                Continue(true)
+            });
+        });
+        for (playlist, saved) in playlists.iter().zip(saved_playlists) {
+            let files: Vec<_> = saved.tracks.iter().map(PathBuf::from).collect();
+            let finished = finished.clone();
+            let selected = saved.selected;
+            let selecting = playlist.clone();
+            let done = move || {
+                selecting.select_paths(&selected);
+                finished();
+            };
+            if files.is_empty() {
+                done();
+            } else {
+                let scan = Scan::start(files, self.state.clone());
+                import_then(playlist, &self.import_progress, scan, None, done);
+            }
+        }
+
+        let speed_button = self.speed_button.clone();
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        let volume_button = self.volume_button.clone();
+        self.window.connect_destroy(move |_| {
+            if restored.get() {
+                save_session(&tabs, &speed_button, &volume_button, &state);
+            }
+        });
+    }
+
+    /// Connects the events of each playlist as its tab opens.
+    fn connect_tab_events(&self) {
+        let bookmarks = self.bookmarks.clone();
+        let browser = self.browser.clone();
+        let config = self.config.clone();
+        let cover = self.cover.clone();
+        let import_progress = self.import_progress.clone();
+        let menu = self.menu.menu.clone();
+        let parent = self.window.clone();
+        let repeat_button = self.toolbar.repeat_button.clone();
+        let search_entry = self.search_entry.clone();
+        let shuffle_button = self.toolbar.shuffle_button.clone();
+        let state = self.state.clone();
+        let redo_item = self.menu.redo_item.clone();
+        let undo_item = self.menu.undo_item.clone();
+        let tabs = Rc::downgrade(&self.tabs);
+        self.tabs.connect_added(move |playlist| {
+            let bookmarks = bookmarks.clone();
+            playlist.connect_resume_position(move |path| bookmarks.resume_position(path));
+            playlist.set_filter(&search_entry.get_text().unwrap_or_default());
+            playlist.set_shuffle(shuffle_button.get_active());
+            playlist.set_repeat(repeat_button.get_active());
+
+            let config = config.clone();
+            let weak = Rc::downgrade(playlist);
+            playlist.connect_layout_changed(move || {
+                if let Some(playlist) = weak.upgrade() {
+                    let mut config = config.borrow_mut();
+                    config.columns = playlist.column_layout();
+                    config.save();
+                }
+            });
+
+            let cover = cover.clone();
+            let parent = parent.clone();
+            let tabs = tabs.clone();
+            let weak = Rc::downgrade(playlist);
+            playlist.connect_rating_changed(move |path, stars| {
+                if let (Some(playlist), Some(tabs)) = (weak.upgrade(), tabs.upgrade()) {
+                    let paths = [path];
+                    let (edit, errors) = write_tags(&paths, &TagChanges::rating(stars));
+                    record_tag_edit(&playlist, edit, &tabs, &cover, &parent);
+                    update_files(&tabs, &cover, &paths);
+                    if !errors.is_empty() {
+                        show_error_dialog(&parent, &format!("Could not write the rating of:\n{}", errors.join("\n")));
+                    }
+                }
+            });
+
+            let menu = menu.clone();
+            let redo_item = redo_item.clone();
+            let undo_item = undo_item.clone();
+            let weak = Rc::downgrade(playlist);
+            playlist.view().connect_button_press_event(move |_, event| {
+                if event.get_button() == 3 {
+                    if let Some(playlist) = weak.upgrade() {
+                        let (x, y) = event.get_position();
+                        playlist.select_at(x as i32, y as i32);
+                        undo_item.set_sensitive(playlist.can_undo());
+                        redo_item.set_sensitive(playlist.can_redo());
+                    }
+                    menu.popup_easy(event.get_button(), event.get_time());
+                    return Inhibit(true);
+                }
+                Inhibit(false)
+            });
+
+            connect_drop_events(playlist, &browser, &import_progress, &state);
+        });
+    }
+
     fn connect_events(&self) {
         let current_time_label = self.current_time_label.clone();
         let duration_label = self.duration_label.clone();
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
         let adjustment = self.adjustment.clone();
         let state = self.state.clone();
         let play_button = self.toolbar.play_button.clone();
+        let cover = self.cover.clone();
+        let footer_label = self.footer_label.clone();
+        let lyrics = self.lyrics.clone();
+        let chapters = self.chapters.clone();
+        let bookmarks = self.bookmarks.clone();
+        let history = self.history.clone();
+        let scale = self.scale.clone();
+        let window = self.window.clone();
         gtk::timeout_add(100, move || {
+            let playlist = tabs.playing();
+            let ended = mem::replace(&mut state.lock().unwrap().ended, false);
+            if ended {
+                bookmarks.finish();
+                if let Some(path) = history.finish() {
+                    update_plays(&tabs, &path);
+                }
+                if playlist.next() {
+                    set_cover(&cover, &playlist);
+                }
+            }
+
+            for (_, playlist) in tabs.playlists() {
+                playlist.refresh_properties();
+            }
+            if let Some(summary) = tabs.current().changed_summary() {
+                footer_label.set_text(&summary);
+            }
+
+            if let Some(path) = history.load(playlist.path().as_deref(), || playlist.current_track()) {
+                update_plays(&tabs, &path);
+            }
+            history.update();
+
             let state = state.lock().unwrap();
             if let Some(path) = playlist.path() {
-                if let Some(&duration) = state.durations.get(&path) {
+                if let Some(duration) = state.duration(&path) {
                     adjustment.set_upper(duration as f64);
                     duration_label.set_text(&millis_to_minutes(duration));
                 }
@@ -156,8 +521,388 @@ impl App {
             }
 
             adjustment.set_value(state.current_time as f64);
+            lyrics.load(playlist.path().as_deref());
+            lyrics.update(state.current_time);
+
+            let loaded = chapters.load(playlist.path().as_deref());
+            if bookmarks.load(playlist.path().as_deref()) || loaded {
+                set_marks(&scale, &chapters, &bookmarks);
+            }
+            bookmarks.update(state.current_time);
+            if chapters.update(state.current_time) || loaded {
+                match chapters.title() {
+                    Some(title) => window.set_title(&format!("{} — Rusic", title)),
+                    None => window.set_title("Rusic"),
+                }
+            }
             Continue(true)
         });
+
+        let tabs = self.tabs.clone();
+        self.lyrics.connect_seek(move |time| {
+            tabs.playing().seek(time);
+        });
+
+        let tabs = self.tabs.clone();
+        self.chapters.connect_seek(move |start| {
+            tabs.playing().seek(start);
+        });
+    }
+
+    fn connect_bookmark_events(&self) {
+        let tabs = self.tabs.clone();
+        self.bookmarks.connect_seek(move |position| {
+            tabs.playing().seek(position);
+        });
+
+        let bookmarks = self.bookmarks.clone();
+        let config = self.config.clone();
+        self.toolbar.audiobook_button.connect_toggled(move |button| {
+            bookmarks.set_enabled(button.get_active());
+            let mut config = config.borrow_mut();
+            config.audiobook_mode = button.get_active();
+            config.save();
+        });
+
+        let bookmarks = Rc::downgrade(&self.bookmarks);
+        let chapters = self.chapters.clone();
+        let parent = self.window.clone();
+        let scale = self.scale.clone();
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        self.bookmarks.add_button.connect_clicked(move |_| {
+            let bookmarks = match bookmarks.upgrade() {
+                Some(bookmarks) => bookmarks,
+                None => return,
+            };
+            if tabs.playing().path().is_none() {
+                return;
+            }
+            let current_time = state.lock().unwrap().current_time;
+            if let Some(name) = show_bookmark_dialog(&parent, current_time) {
+                bookmarks.add(&name, current_time);
+                set_marks(&scale, &chapters, &bookmarks);
+            }
+        });
+
+        let bookmarks = Rc::downgrade(&self.bookmarks);
+        let chapters = self.chapters.clone();
+        let scale = self.scale.clone();
+        self.bookmarks.remove_button.connect_clicked(move |_| {
+            if let Some(bookmarks) = bookmarks.upgrade() {
+                bookmarks.remove_selection();
+                set_marks(&scale, &chapters, &bookmarks);
+            }
+        });
+
+        // Quitting keeps the position reached since the last periodic save.
+        let bookmarks = self.bookmarks.clone();
+        self.window.connect_destroy(move |_| {
+            bookmarks.save();
+        });
+    }
+
+    fn connect_browser_events(&self) {
+        let browser = Rc::downgrade(&self.browser);
+        let tabs = self.tabs.clone();
+        let import_progress = self.import_progress.clone();
+        let state = self.state.clone();
+        self.browser.add_button.connect_clicked(move |_| {
+            if let Some(browser) = browser.upgrade() {
+                let files = browser.selected_paths();
+                if !files.is_empty() {
+                    browser.close_smart_playlist();
+                    import(&tabs.current(), &import_progress, Scan::start(files, state.clone()), None);
+                }
+            }
+        });
+
+        // The playlist a smart playlist was opened in, kept in sync with it.
+        let smart_playlist: Rc<RefCell<Weak<Playlist>>> = Rc::new(RefCell::new(Weak::new()));
+
+        let browser = Rc::downgrade(&self.browser);
+        let tabs = self.tabs.clone();
+        let import_progress = self.import_progress.clone();
+        let smart = smart_playlist.clone();
+        let state = self.state.clone();
+        self.browser.open_smart_button.connect_clicked(move |_| {
+            if let Some(files) = browser.upgrade().and_then(|browser| browser.open_smart_playlist()) {
+                let playlist = tabs.current();
+                playlist.clear();
+                *smart.borrow_mut() = Rc::downgrade(&playlist);
+                // Undone with the clearing, as one edit.
+                let weak = Rc::downgrade(&playlist);
+                import_then(&playlist, &import_progress, Scan::start(files, state.clone()), None, move || {
+                    if let Some(playlist) = weak.upgrade() {
+                        playlist.record_insertion(0, playlist.len(), true);
+                    }
+                });
+            }
+        });
+
+        let browser = Rc::downgrade(&self.browser);
+        let import_progress = self.import_progress.clone();
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        self.browser.open_history_button.connect_clicked(move |_| {
+            if let Some((name, files)) = browser.upgrade().and_then(|browser| browser.history_view()) {
+                let playlist = tabs.add(&name);
+                tabs.set_current_index(tabs.len() - 1);
+                import_then(&playlist, &import_progress, Scan::start(files, state.clone()), None, || {});
+            }
+        });
+
+        let browser = Rc::downgrade(&self.browser);
+        let config = self.config.clone();
+        let parent = self.window.clone();
+        self.browser.new_smart_button.connect_clicked(move |_| {
+            if let (Some(browser), Some(smart)) = (browser.upgrade(), show_smart_playlist_dialog(&parent, None)) {
+                let mut config = config.borrow_mut();
+                config.smart_playlists.retain(|other| other.name != smart.name);
+                config.smart_playlists.push(smart);
+                config.save();
+                browser.set_smart_playlists(&config.smart_playlists);
+            }
+        });
+
+        let browser = Rc::downgrade(&self.browser);
+        let config = self.config.clone();
+        let parent = self.window.clone();
+        self.browser.edit_smart_button.connect_clicked(move |_| {
+            let browser = match browser.upgrade() {
+                Some(browser) => browser,
+                None => return,
+            };
+            if let Some(old) = browser.selected_smart_playlist() {
+                if let Some(smart) = show_smart_playlist_dialog(&parent, Some(&old)) {
+                    let mut config = config.borrow_mut();
+                    config.smart_playlists.retain(|other| other.name != smart.name);
+                    match config.smart_playlists.iter().position(|other| other.name == old.name) {
+                        Some(index) => config.smart_playlists[index] = smart,
+                        None => config.smart_playlists.push(smart),
+                    }
+                    config.save();
+                    browser.set_smart_playlists(&config.smart_playlists);
+                }
+            }
+        });
+
+        let browser = Rc::downgrade(&self.browser);
+        let config = self.config.clone();
+        self.browser.delete_smart_button.connect_clicked(move |_| {
+            if let Some(browser) = browser.upgrade() {
+                if let Some(smart) = browser.selected_smart_playlist() {
+                    let mut config = config.borrow_mut();
+                    config.smart_playlists.retain(|other| other.name != smart.name);
+                    config.save();
+                    browser.set_smart_playlists(&config.smart_playlists);
+                }
+            }
+        });
+
+        let browser = Rc::downgrade(&self.browser);
+        let config = self.config.clone();
+        let parent = self.window.clone();
+        let watcher = self.watcher.clone();
+        self.browser.folders_button.connect_clicked(move |_| {
+            if let (Some(browser), Some(folder)) = (browser.upgrade(), show_folder_dialog(&parent)) {
+                let mut config = config.borrow_mut();
+                if !config.music_folders.contains(&folder) {
+                    watcher.watch(folder.clone());
+                    config.music_folders.push(folder);
+                    config.save();
+                }
+                rescan(&browser, config.music_folders.clone());
+            }
+        });
+
+        // The watcher already updated the library, only the views are left.
+        let browser = self.browser.clone();
+        let tabs = self.tabs.clone();
+        let import_progress = self.import_progress.clone();
+        let state = self.state.clone();
+        let watcher = self.watcher.clone();
+        gtk::timeout_add(LIBRARY_POLL_MILLIS, move || {
+            let mut changed = false;
+            while let Some(change) = watcher.next_change() {
+                for (_, playlist) in tabs.playlists() {
+                    match change {
+                        Change::Updated(ref path) => playlist.update_file(path, false),
+                        Change::Removed(ref path) => playlist.mark_missing(path),
+                        Change::Renamed(ref from, ref to) => playlist.rename_file(from, to),
+                    }
+                }
+                changed = true;
+            }
+            if changed {
+                browser.refresh();
+            }
+
+            if let Some(files) = browser.reevaluate_smart_playlist() {
+                if let Some(playlist) = smart_playlist.borrow().upgrade() {
+                    let missing = playlist.sync_paths(&files);
+                    if !missing.is_empty() {
+                        // Puts the imported rows in their place.
+                        let weak = Rc::downgrade(&playlist);
+                        import_then(&playlist, &import_progress, Scan::start(missing, state.clone()), None, move || {
+                            if let Some(playlist) = weak.upgrade() {
+                                playlist.sync_paths(&files);
+                            }
+                        });
+                    }
+                }
+            }
+            Continue(true)
+        });
+    }
+
+    fn connect_search_events(&self) {
+        let tabs = self.tabs.clone();
+        self.search_entry.connect_search_changed(move |entry| {
+            let text = entry.get_text().unwrap_or_default();
+            for (_, playlist) in tabs.playlists() {
+                playlist.set_filter(&text);
+            }
+        });
+    }
+
+    fn connect_menu_events(&self) {
+        let tabs = self.tabs.clone();
+        self.menu.play_next_item.connect_activate(move |_| {
+            tabs.current().queue_selection(true);
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.add_to_queue_item.connect_activate(move |_| {
+            tabs.current().queue_selection(false);
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.cut_item.connect_activate(move |_| {
+            tabs.current().cut_selection();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.copy_item.connect_activate(move |_| {
+            tabs.current().copy_selection();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.paste_item.connect_activate(move |_| {
+            tabs.current().paste();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.delete_item.connect_activate(move |_| {
+            tabs.current().remove_selection();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.move_top_item.connect_activate(move |_| {
+            tabs.current().move_selection_to_top();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.move_bottom_item.connect_activate(move |_| {
+            tabs.current().move_selection_to_bottom();
+        });
+
+        let cover = self.cover.clone();
+        let parent = self.window.clone();
+        let tabs = self.tabs.clone();
+        self.menu.edit_tags_item.connect_activate(move |_| {
+            let playlist = tabs.current();
+            let paths = playlist.selected_paths();
+            if paths.is_empty() {
+                return;
+            }
+            if let Some(changes) = show_tag_dialog(&parent, &paths) {
+                let (edit, errors) = write_tags(&paths, &changes);
+                record_tag_edit(&playlist, edit, &tabs, &cover, &parent);
+                update_files(&tabs, &cover, &paths);
+                if !errors.is_empty() {
+                    show_error_dialog(&parent, &format!("Could not write the tags of:\n{}", errors.join("\n")));
+                }
+            }
+        });
+
+        let cover = self.cover.clone();
+        let parent = self.window.clone();
+        let tabs = self.tabs.clone();
+        self.menu.set_cover_item.connect_activate(move |_| {
+            let playlist = tabs.current();
+            let paths = playlist.selected_paths();
+            if paths.is_empty() {
+                return;
+            }
+            let file = match show_image_dialog(&parent) {
+                Some(file) => file,
+                None => return,
+            };
+            match read_picture(&file) {
+                Some(picture) => {
+                    let (edit, errors) = write_tags(&paths, &TagChanges::cover(picture));
+                    record_tag_edit(&playlist, edit, &tabs, &cover, &parent);
+                    update_files(&tabs, &cover, &paths);
+                    if !errors.is_empty() {
+                        show_error_dialog(&parent, &format!("Could not write the cover of:\n{}", errors.join("\n")));
+                    }
+                },
+                None => show_error_dialog(&parent, &format!("Cannot read {}", file.display())),
+            }
+        });
+
+        let config = self.config.clone();
+        let parent = self.window.clone();
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        self.menu.cleanup_item.connect_activate(move |_| {
+            let playlist = tabs.current();
+            let paths = playlist.paths();
+            let measured = {
+                let state = state.lock().unwrap();
+                paths.iter()
+                    .filter_map(|path| {
+                        let properties = state.properties.get(path)?;
+                        Some((path.clone(), Measurement { size: properties.size, duration: to_millis(properties.duration) }))
+                    })
+                    .collect()
+            };
+            let folders = config.borrow().music_folders.clone();
+            let findings = show_cleanup_dialog(&parent, paths.clone(), measured, folders);
+            if findings.is_empty() {
+                return;
+            }
+            if playlist.paths() != paths {
+                show_error_dialog(&parent, "The playlist changed during the check, nothing was fixed.");
+                return;
+            }
+            let (relocated, removed): (Vec<_>, Vec<_>) = findings.iter()
+                .partition(|finding| finding.relocation().is_some());
+            let relocated: Vec<_> = relocated.iter()
+                .filter_map(|finding| Some((finding.row, finding.relocation()?.to_path_buf())))
+                .collect();
+            let removed: Vec<_> = removed.iter().map(|finding| finding.row).collect();
+            playlist.fix_rows(&removed, &relocated);
+            // The history, resume positions and bookmarks of the moved files follow them.
+            let library = Library::open();
+            for finding in &findings {
+                if let Some(new_path) = finding.relocation() {
+                    let _ = library.rename_path(Path::new(&finding.path), new_path);
+                }
+            }
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.undo_item.connect_activate(move |_| {
+            tabs.current().undo();
+        });
+
+        let tabs = self.tabs.clone();
+        self.menu.redo_item.connect_activate(move |_| {
+            tabs.current().redo();
+        });
+
     }
 
     pub fn connect_toolbar_events(&self) {
@@ -166,7 +911,7 @@ impl App {
             window.destroy();
         });
 
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
         let cover = self.cover.clone();
         let state = self.state.clone();
 
@@ -174,82 +919,401 @@ impl App {
         self.toolbar.play_button.connect_clicked( move |_| {
 
             if state.lock().unwrap().stopped {
+                let playlist = tabs.current();
                 if playlist.play() {
+                    tabs.set_playing(&playlist);
                     play_button.set_stock_id(PAUSE_STOCK);
                     set_cover(&cover, &playlist);
                 } 
             } else {
-                playlist.pause();
+                tabs.playing().pause();
                 play_button.set_stock_id(PLAY_STOCK);
             }
         });
 
+        let browser = self.browser.clone();
         let parent = self.window.clone();
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
+        let import_progress = self.import_progress.clone();
+        let state = self.state.clone();
         self.toolbar.open_button.connect_clicked(move |_| {
-            let file = show_open_dialog(&parent);
-            if let Some(file) = file {
-                if let Some(ext) = file.extension() {
-                    match ext.to_str().unwrap() {
-                        "mp3" => playlist.add(&file),
-                        "m3u" => playlist.load(&file),
-                        extension => {
-                            let dialog = MessageDialog::new(Some(&parent), DialogFlags::empty(), 
-                                                            MessageType::Error,
-                                                            ButtonsType::Ok, 
-                                                            &format!("Cannot open file with extension .{}", extension));
-                            dialog.run();
-                            dialog.destroy();
-                        }
-                    }
+            let mut files = Vec::new();
+            for file in show_open_dialog(&parent) {
+                if is_supported(&file) || is_playlist(&file) {
+                    files.push(file);
+                } else {
+                    let extension = file.extension().unwrap_or_default().to_string_lossy();
+                    show_error_dialog(&parent, &format!("Cannot open file with extension .{}", extension));
                 }
             }
+
+            if !files.is_empty() {
+                browser.close_smart_playlist();
+                import(&tabs.current(), &import_progress, Scan::start(files, state.clone()), None);
+            }
         });
 
+        let browser = self.browser.clone();
         let parent = self.window.clone();
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
+        let import_progress = self.import_progress.clone();
+        let state = self.state.clone();
+        self.toolbar.add_folder_button.connect_clicked(move |_| {
+            if let Some(folder) = show_folder_dialog(&parent) {
+                browser.close_smart_playlist();
+                let scan = Scan::start(vec![folder], state.clone());
+                import(&tabs.current(), &import_progress, scan, None);
+            }
+        });
+
+        let parent = self.window.clone();
+        let tabs = self.tabs.clone();
         self.toolbar.save_button.connect_clicked(move |_| {
             let file = show_save_dialog(&parent);
             if let Some(file) = file {
-                playlist.save(&file);
+                tabs.current().save(&file);
             }
         });
 
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
         self.toolbar.remove_button.connect_clicked(move |_| {
-            playlist.remove_selection();
+            tabs.current().remove_selection();
         });
 
         let current_time_label = self.current_time_label.clone();
         let duration_label = self.current_time_label.clone();
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
         let cover = self.cover.clone();
         let play_button = self.toolbar.play_button.clone();
         self.toolbar.stop_button.connect_clicked(move |_| {
             current_time_label.set_text("");
             duration_label.set_text("");
-            playlist.stop();
+            tabs.playing().stop();
             cover.hide();
             play_button.set_stock_id(PLAY_STOCK);
         });
 
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
         let cover = self.cover.clone();
         let play_button = self.toolbar.play_button.clone();
         self.toolbar.next_button.connect_clicked(move |_| {
+            let playlist = tabs.playing();
             if playlist.next() {
                 play_button.set_stock_id(PAUSE_STOCK);
                 set_cover(&cover, &playlist);
             }
         });
 
-        let playlist = self.playlist.clone();
+        let tabs = self.tabs.clone();
+        self.toolbar.shuffle_button.connect_toggled(move |button| {
+            for (_, playlist) in tabs.playlists() {
+                playlist.set_shuffle(button.get_active());
+            }
+        });
+
+        let tabs = self.tabs.clone();
+        self.speed_button.connect_property_value_notify(move |button| {
+            tabs.playing().set_speed(button.get_value());
+        });
+
+        let tabs = self.tabs.clone();
+        self.volume_button.connect_value_changed(move |_, volume| {
+            tabs.playing().set_volume(volume);
+        });
+
+        let tabs = self.tabs.clone();
+        self.toolbar.repeat_button.connect_toggled(move |button| {
+            for (_, playlist) in tabs.playlists() {
+                playlist.set_repeat(button.get_active());
+            }
+        });
+
+        let tabs = self.tabs.clone();
         let cover = self.cover.clone();
         self.toolbar.previous_button.connect_clicked(move |_| {
+            let playlist = tabs.playing();
             if playlist.previous() {
                 set_cover(&cover, &playlist);
             }
         });
+
+        let chapters = self.chapters.clone();
+        let tabs = self.tabs.clone();
+        let state = self.state.clone();
+        self.toolbar.next_chapter_button.connect_clicked(move |_| {
+            let current_time = state.lock().unwrap().current_time;
+            if let Some(start) = chapters.next_start(current_time) {
+                tabs.playing().seek(start);
+            }
+        });
+
+        let chapters = self.chapters.clone();
+        let tabs = self.tabs.clone();
+        let state = self.state.clone();
+        self.toolbar.previous_chapter_button.connect_clicked(move |_| {
+            let current_time = state.lock().unwrap().current_time;
+            if let Some(start) = chapters.previous_start(current_time) {
+                tabs.playing().seek(start);
+            }
+        });
+    }
+
+    /// Registers the application actions with their keyboard shortcuts, see `SHORTCUT_GROUPS`.
+    fn connect_shortcuts(&self, application: &Application) {
+        let play_button = self.toolbar.play_button.clone();
+        add_action(application, "play-pause", move || play_button.emit_clicked());
+
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        add_action(application, "seek-backward", move || seek_by(&tabs, &state, false));
+
+        let state = self.state.clone();
+        let tabs = self.tabs.clone();
+        add_action(application, "seek-forward", move || seek_by(&tabs, &state, true));
+
+        let volume_button = self.volume_button.clone();
+        add_action(application, "volume-up", move || {
+            volume_button.set_value(volume_button.get_value() + VOLUME_STEP);
+        });
+
+        let volume_button = self.volume_button.clone();
+        add_action(application, "volume-down", move || {
+            volume_button.set_value(volume_button.get_value() - VOLUME_STEP);
+        });
+
+        let open_button = self.toolbar.open_button.clone();
+        add_action(application, "open", move || open_button.emit_clicked());
+
+        let save_button = self.toolbar.save_button.clone();
+        add_action(application, "save", move || save_button.emit_clicked());
+
+        let remove_button = self.toolbar.remove_button.clone();
+        add_action(application, "remove", move || remove_button.emit_clicked());
+
+        let tabs = self.tabs.clone();
+        add_action(application, "undo", move || tabs.current().undo());
+
+        let tabs = self.tabs.clone();
+        add_action(application, "redo", move || tabs.current().redo());
+
+        let search_entry = self.search_entry.clone();
+        add_action(application, "search", move || search_entry.grab_focus());
+
+        let window = self.window.clone();
+        add_action(application, "shortcuts", move || show_shortcuts_window(&window));
+
+        let quit_button = self.toolbar.quit_button.clone();
+        add_action(application, "quit", move || quit_button.emit_clicked());
+
+        set_accels(application, &self.config.borrow().shortcuts);
+
+        // Shortcuts come before the focused widget, so an entry gets the keys first, not to
+        // lose the spaces or deletions typed in it.
+        self.window.connect_key_press_event(|window, event| {
+            let typing = window.get_focus().map(|widget| widget.is::<Entry>()).unwrap_or(false);
+            Inhibit(typing && window.propagate_key_event(event))
+        });
+    }
+}
+
+/// Lets files be dropped on `playlist` and its rows be moved by dragging.
+fn connect_drop_events(playlist: &Rc<Playlist>, browser: &Rc<Browser>, import_progress: &ProgressBar,
+                   state: &Arc<Mutex<State>>) {
+    let treeview = playlist.view();
+    let rows_target = TargetEntry::new("application/x-rusic-rows", TargetFlags::SAME_WIDGET, ROWS_TARGET);
+    let targets = [
+        TargetEntry::new("text/uri-list", TargetFlags::OTHER_APP, URI_LIST_TARGET),
+        rows_target.clone(),
+    ];
+    treeview.drag_source_set(ModifierType::BUTTON1_MASK, &[rows_target], DragAction::MOVE);
+    treeview.drag_dest_set(DestDefaults::ALL, &targets, DragAction::COPY | DragAction::MOVE);
+
+    // The moved rows are the selected ones, the payload is only there to complete the drag.
+    treeview.connect_drag_data_get(|_, _, selection_data, _, _| {
+        selection_data.set_text("rows");
+    });
+
+    let browser = browser.clone();
+    let playlist = Rc::downgrade(playlist);
+    let import_progress = import_progress.clone();
+    let state = state.clone();
+    treeview.connect_drag_data_received(move |_, _, x, y, selection_data, info, _| {
+        let playlist = match playlist.upgrade() {
+            Some(playlist) => playlist,
+            None => return,
+        };
+        let position = playlist.drop_position(x, y);
+        if info == ROWS_TARGET {
+            playlist.move_selection_to(position);
+            return;
+        }
+
+        let files: Vec<_> = selection_data.get_uris().iter()
+            .filter_map(|uri| gio::File::new_for_uri(uri).get_path())
+            .collect();
+        if !files.is_empty() {
+            browser.close_smart_playlist();
+            import(&playlist, &import_progress, Scan::start(files, state.clone()), position);
+        }
+    });
+}
+
+/// Inserts the tracks found by `scan` into the playlist in batches from a GTK timeout,
+/// so that importing a large folder never blocks the main loop.
+///
+/// Tracks are appended, or inserted one after the other from `position` when given.
+///
+/// The import can be undone as a whole.
+fn import(playlist: &Rc<Playlist>, progress: &ProgressBar, scan: Scan, position: Option<i32>) {
+    let count = playlist.len();
+    let start = position.map(|position| (position as usize).min(count)).unwrap_or(count);
+    let weak = Rc::downgrade(playlist);
+    import_then(playlist, progress, scan, position, move || {
+        if let Some(playlist) = weak.upgrade() {
+            playlist.record_insertion(start, playlist.len().saturating_sub(count), false);
+        }
+    });
+}
+
+/// Imports like `import`, calling `finished` once every track is in.
+fn import_then<F: FnOnce() + 'static>(playlist: &Rc<Playlist>, progress: &ProgressBar, mut scan: Scan,
+                                      mut position: Option<i32>, finished: F) {
+    let playlist = playlist.clone();
+    let mut finished = Some(finished);
+    let progress = progress.clone();
+    progress.set_fraction(0.0);
+    progress.set_text("Scanning…");
+    progress.show();
+    gtk::timeout_add(50, move || {
+        for info in scan.next_batch(IMPORT_BATCH_SIZE) {
+            playlist.insert(&info, position);
+            position = position.map(|position| position + 1);
+        }
+
+        if scan.is_finished() {
+            progress.hide();
+            if let Some(finished) = finished.take() {
+                finished();
+            }
+            return Continue(false);
+        }
+
+        if !scan.is_walking() {
+            let total = scan.total();
+            progress.set_fraction(scan.added() as f64 / total as f64);
+            progress.set_text(format!("Added {} / {}", scan.added(), total).as_str());
+        } else {
+            progress.pulse();
+        }
+        Continue(true)
+    });
+}
+
+/// Rescans the music folders into the library in the background, refreshing the browser
+/// as batches get committed.
+fn rescan(browser: &Rc<Browser>, folders: Vec<PathBuf>) {
+    let browser = browser.clone();
+    let rescan = Rescan::start(folders);
+    gtk::timeout_add(LIBRARY_POLL_MILLIS, move || {
+        let finished = rescan.is_finished();
+        if rescan.take_changed() {
+            browser.refresh();
+        }
+        Continue(!finished)
+    });
+}
+
+fn save_session(tabs: &PlaylistTabs, speed_button: &SpinButton, volume_button: &VolumeButton, state: &Mutex<State>) {
+    let playing = tabs.playing();
+    let current = playing.path();
+    let position = if current.is_some() { state.lock().unwrap().current_time } else { 0 };
+    let session = Session {
+        playlists: tabs.playlists().into_iter()
+            .map(|(name, playlist)| SavedPlaylist {
+                name,
+                tracks: playlist.paths(),
+                selected: playlist.selected_paths().iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+            })
+            .collect(),
+        current_tab: tabs.current_index(),
+        playing_tab: tabs.playing_index(),
+        current,
+        position,
+        repeat: playing.repeat(),
+        shuffle: playing.shuffle(),
+        speed: Some(speed_button.get_value()),
+        volume: Some(volume_button.get_value()),
+    };
+    session.save();
+}
+
+/// Moves the playing track `SEEK_STEP_MILLIS` forward or backward, staying within it.
+fn seek_by(tabs: &PlaylistTabs, state: &Mutex<State>, forward: bool) {
+    let playlist = tabs.playing();
+    let path = match playlist.path() {
+        Some(path) => path,
+        None => return,
+    };
+    let (time, duration) = {
+        let state = state.lock().unwrap();
+        (state.current_time, state.duration(&path))
+    };
+    let time = if forward { time + SEEK_STEP_MILLIS } else { time.saturating_sub(SEEK_STEP_MILLIS) };
+    playlist.seek(duration.map(|duration| time.min(duration)).unwrap_or(time));
+}
+
+/// Marks the chapter starts below the progress bar and the named bookmarks above it.
+fn set_marks(scale: &Scale, chapters: &ChapterList, bookmarks: &BookmarkList) {
+    scale.clear_marks();
+    for start in chapters.starts() {
+        scale.add_mark(start as f64, PositionType::Bottom, None);
+    }
+    for (position, name) in bookmarks.marks() {
+        scale.add_mark(position as f64, PositionType::Top, Some(escape_markup(&name).as_str()));
+    }
+}
+
+/// Reads retagged files again in every playlist, along with the shown cover in case it changed.
+fn update_files(tabs: &PlaylistTabs, cover: &Image, paths: &[PathBuf]) {
+    for (_, playlist) in tabs.playlists() {
+        for path in paths {
+            playlist.update_file(path, true);
+        }
+    }
+    if cover.get_visible() {
+        set_cover(cover, &tabs.playing());
+    }
+}
+
+/// Puts the tag `edit` on the undo stack of `playlist`.
+fn record_tag_edit(playlist: &Playlist, edit: TagEdit, tabs: &Rc<PlaylistTabs>, cover: &Image, parent: &ApplicationWindow) {
+    if edit.is_empty() {
+        return;
+    }
+    let edit = Rc::new(edit);
+    let restore = |undo: bool| {
+        let edit = edit.clone();
+        let tabs = Rc::downgrade(tabs);
+        let cover = cover.clone();
+        let parent = parent.clone();
+        move || {
+            let (restored, errors) = if undo { edit.undo() } else { edit.redo() };
+            if let Some(tabs) = tabs.upgrade() {
+                update_files(&tabs, &cover, &restored);
+            }
+            if !errors.is_empty() {
+                show_error_dialog(&parent, &format!("Could not restore the tags of:\n{}", errors.join("\n")));
+            }
+        }
+    };
+    playlist.record_file_edit(restore(true), restore(false));
+}
+
+fn update_plays(tabs: &PlaylistTabs, path: &str) {
+    for (_, playlist) in tabs.playlists() {
+        playlist.update_plays(path);
     }
 }
 
@@ -264,6 +1328,22 @@ fn millis_to_minutes(millis: u64) -> String {
     format!("{}:{:02}", minutes, seconds)
 }
 
+/// Formats seconds since the epoch as a local date and time.
+fn format_timestamp(seconds: i64) -> String {
+    glib::DateTime::new_from_unix_local(seconds).format("%Y-%m-%d %H:%M").unwrap_or_default()
+}
+
+/// Formats a total running time, with hours once it reaches an hour.
+fn millis_to_hours(millis: u64) -> String {
+    let hours = millis / 3_600_000;
+    if hours == 0 {
+        return millis_to_minutes(millis);
+    }
+    let minutes = millis / 60_000 % 60;
+    let seconds = millis / 1_000 % 60;
+    format!("{}:{:02}:{:02}", hours, minutes, seconds)
+}
+
 fn main() {
     let application = Application::new("com.zero.rusic", ApplicationFlags::empty())
         .expect("Application initialization failed");
@@ -0,0 +1,98 @@
+use gtk::{
+    Menu,
+    MenuItem,
+    MenuShellExt,
+    SeparatorMenuItem,
+    WidgetExt,
+};
+
+pub struct PlaylistMenu {
+    pub add_to_queue_item: MenuItem,
+    pub cleanup_item: MenuItem,
+    pub copy_item: MenuItem,
+    pub cut_item: MenuItem,
+    pub delete_item: MenuItem,
+    pub edit_tags_item: MenuItem,
+    pub menu: Menu,
+    pub move_bottom_item: MenuItem,
+    pub move_top_item: MenuItem,
+    pub paste_item: MenuItem,
+    pub play_next_item: MenuItem,
+    pub redo_item: MenuItem,
+    pub set_cover_item: MenuItem,
+    pub undo_item: MenuItem,
+}
+
+impl PlaylistMenu {
+    pub fn new() -> Self {
+        let menu = Menu::new();
+
+        let play_next_item = MenuItem::new_with_mnemonic("Play _next");
+        menu.append(&play_next_item);
+
+        let add_to_queue_item = MenuItem::new_with_mnemonic("Add to _queue");
+        menu.append(&add_to_queue_item);
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let undo_item = MenuItem::new_with_mnemonic("_Undo");
+        menu.append(&undo_item);
+
+        let redo_item = MenuItem::new_with_mnemonic("_Redo");
+        menu.append(&redo_item);
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let cut_item = MenuItem::new_with_mnemonic("Cu_t");
+        menu.append(&cut_item);
+
+        let copy_item = MenuItem::new_with_mnemonic("_Copy");
+        menu.append(&copy_item);
+
+        let paste_item = MenuItem::new_with_mnemonic("_Paste");
+        menu.append(&paste_item);
+
+        let delete_item = MenuItem::new_with_mnemonic("_Delete");
+        menu.append(&delete_item);
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let move_top_item = MenuItem::new_with_mnemonic("Move to _top");
+        menu.append(&move_top_item);
+
+        let move_bottom_item = MenuItem::new_with_mnemonic("Move to _bottom");
+        menu.append(&move_bottom_item);
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let edit_tags_item = MenuItem::new_with_mnemonic("_Edit tags…");
+        menu.append(&edit_tags_item);
+
+        let set_cover_item = MenuItem::new_with_mnemonic("Set _cover from file…");
+        menu.append(&set_cover_item);
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let cleanup_item = MenuItem::new_with_mnemonic("Find duplicates and _missing files…");
+        menu.append(&cleanup_item);
+
+        menu.show_all();
+
+        PlaylistMenu {
+            add_to_queue_item,
+            cleanup_item,
+            copy_item,
+            cut_item,
+            delete_item,
+            edit_tags_item,
+            menu,
+            move_bottom_item,
+            move_top_item,
+            paste_item,
+            play_next_item,
+            redo_item,
+            set_cover_item,
+            undo_item,
+        }
+    }
+}
@@ -0,0 +1,883 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+
+use id3::Tag;
+
+use crate::cover::embedded_cover;
+use crate::rating::read_rating;
+
+const ID3V1_SIZE: u64 = 128;
+const ID3V2_HEADER_SIZE: usize = 10;
+const ID3V2_UNSYNCHRONISATION: u8 = 0x80;
+const ID3V2_EXTENDED_HEADER: u8 = 0x40;
+const ID3V2_FOOTER: u8 = 0x10;
+/// The ID3v2 text frames read, by their ID3v2.2 ID and the later one.
+const ID3V2_TEXT_FRAMES: &[(&str, &str)] = &[("TT2", "TIT2"), ("TP1", "TPE1"), ("TAL", "TALB"), ("TCO", "TCON")];
+const APE_FOOTER_SIZE: u64 = 32;
+const APE_BINARY_FLAG: u32 = 1 << 1;
+const APE_HAS_HEADER: u32 = 1 << 31;
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_PICTURE: u8 = 6;
+const FLAC_FRONT_COVER: u32 = 3;
+/// How far into an Ogg stream the comment header is looked for.
+const OGG_MAX_PAGES: usize = 16;
+/// Bounds the size of a tag read into memory, against corrupt length fields.
+const MAX_TAG_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The ID3v1 genres, Winamp extensions included, by index.
+const GENRES: &[&str] = &[
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz",
+    "Metal", "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno",
+    "Industrial", "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno",
+    "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance", "Classical", "Instrumental",
+    "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise", "Alternative Rock", "Bass", "Soul",
+    "Punk", "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic",
+    "Darkwave", "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream",
+    "Southern Rock", "Comedy", "Cult", "Gangsta", "Top 40", "Christian Rap", "Pop/Funk", "Jungle",
+    "Native American", "Cabaret", "New Wave", "Psychedelic", "Rave", "Showtunes", "Trailer",
+    "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll",
+    "Hard Rock", "Folk", "Folk-Rock", "National Folk", "Swing", "Fast Fusion", "Bebop", "Latin",
+    "Revival", "Celtic", "Bluegrass", "Avantgarde", "Gothic Rock", "Progressive Rock",
+    "Psychedelic Rock", "Symphonic Rock", "Slow Rock", "Big Band", "Chorus", "Easy Listening",
+    "Acoustic", "Humour", "Speech", "Chanson", "Opera", "Chamber Music", "Sonata", "Symphony",
+    "Booty Bass", "Primus", "Porn Groove", "Satire", "Slow Jam", "Club", "Tango", "Samba",
+    "Folklore", "Ballad", "Power Ballad", "Rhythmic Soul", "Freestyle", "Duet", "Punk Rock",
+    "Drum Solo", "A Cappella", "Euro-House", "Dance Hall",
+];
+
+static LEGACY_ENCODING: OnceLock<Option<&'static Encoding>> = OnceLock::new();
+
+/// Sets the encoding of tags written in a legacy code page, by its WHATWG label, e.g.
+/// `windows-1251` or `shift_jis`. Without one, the encoding of each value is guessed.
+pub fn set_legacy_encoding(label: Option<&str>) {
+    let encoding = label.and_then(|label| Encoding::for_label(label.trim().as_bytes()));
+    let _ = LEGACY_ENCODING.set(encoding);
+}
+
+/// Decodes text stored in an unknown 8-bit encoding: UTF-8 if valid, else the configured
+/// legacy encoding or the guessed one.
+fn decode_legacy(bytes: &[u8]) -> String {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    let encoding = LEGACY_ENCODING.get().copied().flatten().unwrap_or_else(|| {
+        let mut detector = EncodingDetector::new();
+        detector.feed(bytes, true);
+        detector.guess(None, false)
+    });
+    encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+
+/// Tag fields merged from every tag format found in a file.
+#[derive(Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub picture: Option<Vec<u8>>,
+    /// Stars from 1 to 5, see `read_rating`.
+    pub rating: Option<u8>,
+}
+
+impl Metadata {
+    /// Fills the fields still missing, or blank, with those of `other`.
+    fn merge(&mut self, other: Metadata) {
+        let present = |text: Option<String>| text.filter(|text| !text.trim().is_empty());
+        self.title = present(self.title.take()).or(present(other.title));
+        self.artist = present(self.artist.take()).or(present(other.artist));
+        self.album = present(self.album.take()).or(present(other.album));
+        self.genre = present(self.genre.take()).or(present(other.genre));
+        self.year = self.year.or(other.year);
+        self.track = self.track.or(other.track);
+        self.total_tracks = self.total_tracks.or(other.total_tracks);
+        self.picture = self.picture.take().or(other.picture);
+        self.rating = self.rating.or(other.rating);
+    }
+
+    fn set_number_pair(&mut self, value: &str) {
+        let (track, total_tracks) = parse_number_pair(value);
+        self.track = self.track.or(track);
+        self.total_tracks = self.total_tracks.or(total_tracks);
+    }
+}
+
+/// Reads the tags of `path`. Each field comes from the first of these that has it: Vorbis
+/// comments, native to FLAC and Ogg, then ID3v2, APEv2 and finally ID3v1.
+pub fn read_metadata(path: &Path) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return metadata,
+    };
+
+    let mut magic = [0; 4];
+    if file.read_exact(&mut magic).is_ok() {
+        let vorbis = match &magic {
+            b"fLaC" => read_flac(&mut file),
+            b"OggS" => read_ogg(&mut file),
+            _ => None,
+        };
+        if let Some(vorbis) = vorbis {
+            metadata.merge(vorbis);
+        }
+    }
+    if let Ok(tag) = Tag::read_from_path(path) {
+        let latin1 = read_id3v2_data(&mut file).map(|data| id3v2_latin1_frames(&data)).unwrap_or_default();
+        metadata.merge(from_id3v2(&tag, &latin1));
+    }
+    let id3v1 = read_id3v1(&mut file);
+    if let Some(ape) = read_ape(&mut file, id3v1.is_some()) {
+        metadata.merge(ape);
+    }
+    if let Some(id3v1) = id3v1 {
+        metadata.merge(id3v1);
+    }
+    metadata
+}
+
+/// Parses `3` or `3/12`.
+fn parse_number_pair(value: &str) -> (Option<u32>, Option<u32>) {
+    let mut parts = value.splitn(2, '/');
+    let number = parts.next().and_then(|number| number.trim().parse().ok());
+    let total = parts.next().and_then(|total| total.trim().parse().ok());
+    (number, total)
+}
+
+/// The year at the start of a date such as `1959`, `1959-08-17` or `1959-08-17T10:00`.
+/// The year of an ID3v2 tag: ID3v2.4 dates the recording with TDRC instead of TYER.
+pub fn id3v2_year(tag: &Tag) -> Option<i32> {
+    tag.year().or_else(|| tag.get("TDRC").and_then(|frame| frame.content().text()).and_then(parse_year))
+}
+
+fn parse_year(value: &str) -> Option<i32> {
+    let digits: String = value.trim().chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+/// Resolves the ID3v1 genre references of ID3v2, `(17)` or `17`, to names.
+fn genre_name(genre: &str) -> String {
+    let reference = genre.trim().strip_prefix('(')
+        .and_then(|rest| rest.split_once(')'))
+        .map(|(number, refinement)| (number, refinement.trim()));
+    match reference {
+        Some((number, refinement)) => match number.parse::<usize>().ok().and_then(|index| GENRES.get(index)) {
+            Some(name) if refinement.is_empty() => name.to_string(),
+            Some(_) => refinement.to_string(),
+            None => genre.to_string(),
+        },
+        None => genre.trim().parse::<usize>().ok()
+            .and_then(|index| GENRES.get(index))
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| genre.to_string()),
+    }
+}
+
+/// The fields of `tag`, with the text of the frames in `latin1` taken from there.
+fn from_id3v2(tag: &Tag, latin1: &HashMap<String, String>) -> Metadata {
+    let text = |id: &str, value: Option<&str>| latin1.get(id).cloned().or_else(|| value.map(str::to_string));
+    Metadata {
+        title: text("TIT2", tag.title()),
+        artist: text("TPE1", tag.artist()),
+        album: text("TALB", tag.album()),
+        genre: text("TCON", tag.genre()).map(|genre| genre_name(&genre)),
+        year: id3v2_year(tag),
+        track: tag.track(),
+        total_tracks: tag.total_tracks(),
+        picture: embedded_cover(tag).map(|picture| picture.data.clone()),
+        rating: read_rating(tag),
+    }
+}
+
+/// Reads the ID3v2 tag heading `file`, header included.
+fn read_id3v2_data(file: &mut File) -> Option<Vec<u8>> {
+    let mut data = vec![0; ID3V2_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_exact(&mut data).ok()?;
+    if &data[..3] != b"ID3" {
+        return None;
+    }
+    let size = u64::from(synchsafe(&data[6..10]));
+    if size > MAX_TAG_SIZE {
+        return None;
+    }
+    data.resize(ID3V2_HEADER_SIZE + size as usize, 0);
+    file.read_exact(&mut data[ID3V2_HEADER_SIZE..]).ok()?;
+    Some(data)
+}
+
+/// The size of `data` without its ID3v2, APEv2 and ID3v1 tags, which rewriting the tags
+/// leaves unchanged.
+pub fn audio_size<R: Read + Seek>(data: &mut R) -> Option<u64> {
+    let mut end = data.seek(SeekFrom::End(0)).ok()?;
+    let mut header = [0; ID3V2_HEADER_SIZE];
+    data.seek(SeekFrom::Start(0)).ok()?;
+    let start = match data.read_exact(&mut header) {
+        Ok(()) if &header[..3] == b"ID3" => {
+            let footer = if header[5] & ID3V2_FOOTER != 0 { ID3V2_HEADER_SIZE as u64 } else { 0 };
+            ID3V2_HEADER_SIZE as u64 + u64::from(synchsafe(&header[6..10])) + footer
+        },
+        _ => 0,
+    };
+    if end >= start + ID3V1_SIZE {
+        let mut tag = [0; 3];
+        data.seek(SeekFrom::Start(end - ID3V1_SIZE)).ok()?;
+        data.read_exact(&mut tag).ok()?;
+        if &tag == b"TAG" {
+            end -= ID3V1_SIZE;
+        }
+    }
+    if end >= start + APE_FOOTER_SIZE {
+        let mut footer = [0; APE_FOOTER_SIZE as usize];
+        data.seek(SeekFrom::Start(end - APE_FOOTER_SIZE)).ok()?;
+        data.read_exact(&mut footer).ok()?;
+        if &footer[..8] == b"APETAGEX" {
+            let size = u64::from(u32::from_le_bytes(footer[12..16].try_into().unwrap()));
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header = if flags & APE_HAS_HEADER != 0 { APE_FOOTER_SIZE } else { 0 };
+            end = end.saturating_sub(size + header);
+        }
+    }
+    Some(end.saturating_sub(start))
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, &byte| value << 7 | u32::from(byte & 0x7f))
+}
+
+/// Drops the zero byte the unsynchronisation scheme puts after each 0xFF byte.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &byte in data {
+        if previous != 0xff || byte != 0 {
+            result.push(byte);
+        }
+        previous = byte;
+    }
+    result
+}
+
+/// The text frames read of the ID3v2 tag `data` whose encoding byte declares ISO-8859-1,
+/// by ID3v2.3 ID. Such text is often really in a legacy code page, which id3 cannot tell,
+/// so it is decoded again from the bytes of the frame.
+fn id3v2_latin1_frames(data: &[u8]) -> HashMap<String, String> {
+    let mut frames = HashMap::new();
+    let header = match data.get(..ID3V2_HEADER_SIZE) {
+        Some(header) if &header[..3] == b"ID3" => header,
+        _ => return frames,
+    };
+    let (version, flags) = (header[3], header[5]);
+    let end = (ID3V2_HEADER_SIZE + synchsafe(&header[6..10]) as usize).min(data.len());
+    let mut body = data[ID3V2_HEADER_SIZE..end].to_vec();
+    // ID3v2.4 unsynchronises frame by frame instead.
+    if version < 4 && flags & ID3V2_UNSYNCHRONISATION != 0 {
+        body = resynchronise(&body);
+    }
+    let mut rest = &body[..];
+    if version >= 3 && flags & ID3V2_EXTENDED_HEADER != 0 {
+        // The size of the extended header only counts itself from ID3v2.4 on.
+        let size = match rest.get(..4) {
+            Some(size) if version == 3 => u32::from_be_bytes(size.try_into().unwrap()) as usize + 4,
+            Some(size) => synchsafe(size) as usize,
+            None => return frames,
+        };
+        rest = rest.get(size..).unwrap_or_default();
+    }
+
+    let (id_size, header_size) = if version == 2 { (3, 6) } else { (4, 10) };
+    while let Some(frame_header) = rest.get(..header_size) {
+        // Padding.
+        if frame_header[0] == 0 {
+            break;
+        }
+        let size = match version {
+            2 => u32::from_be_bytes([0, frame_header[3], frame_header[4], frame_header[5]]),
+            3 => u32::from_be_bytes(frame_header[4..8].try_into().unwrap()),
+            _ => synchsafe(&frame_header[4..8]),
+        } as usize;
+        let content = match rest.get(header_size..header_size + size) {
+            Some(content) => content,
+            None => break,
+        };
+        rest = &rest[header_size + size..];
+
+        let id = String::from_utf8_lossy(&frame_header[..id_size]);
+        let id = match ID3V2_TEXT_FRAMES.iter().find(|(v22_id, v23_id)| id == *v22_id || id == *v23_id) {
+            Some((_, id)) => id,
+            None => continue,
+        };
+        let format = if version == 2 { 0 } else { frame_header[9] };
+        if let Some(content) = frame_content(content, version, format) {
+            if let Some((0, text)) = content.split_first() {
+                frames.insert(id.to_string(), split_id3_string(text, 0).0);
+            }
+        }
+    }
+    frames
+}
+
+/// The content of a frame without the fields its format `flags` add before it, None if it
+/// is compressed or encrypted.
+fn frame_content(content: &[u8], version: u8, flags: u8) -> Option<Vec<u8>> {
+    match version {
+        3 => {
+            if flags & 0xc0 != 0 {
+                return None;
+            }
+            // A grouping identity byte.
+            let start = if flags & 0x20 != 0 { 1 } else { 0 };
+            content.get(start..).map(<[u8]>::to_vec)
+        },
+        4 => {
+            if flags & 0x0c != 0 {
+                return None;
+            }
+            // A grouping identity byte, then a data length indicator.
+            let start = if flags & 0x40 != 0 { 1 } else { 0 } + if flags & 0x01 != 0 { 4 } else { 0 };
+            let content = content.get(start..)?;
+            Some(if flags & 0x02 != 0 { resynchronise(content) } else { content.to_vec() })
+        },
+        _ => Some(content.to_vec()),
+    }
+}
+
+fn read_id3v1(file: &mut File) -> Option<Metadata> {
+    let mut data = [0; ID3V1_SIZE as usize];
+    file.seek(SeekFrom::End(-(ID3V1_SIZE as i64))).ok()?;
+    file.read_exact(&mut data).ok()?;
+    if &data[..3] != b"TAG" {
+        return None;
+    }
+
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+        let text = decode_legacy(&bytes[..end]);
+        let text = text.trim();
+        if text.is_empty() { None } else { Some(text.to_string()) }
+    };
+    let comment = &data[97..127];
+    // ID3v1.1 keeps the track number in the last byte of the comment.
+    let track = if comment[28] == 0 && comment[29] != 0 { Some(u32::from(comment[29])) } else { None };
+    Some(Metadata {
+        title: field(&data[3..33]),
+        artist: field(&data[33..63]),
+        album: field(&data[63..93]),
+        year: field(&data[93..97]).and_then(|year| parse_year(&year)),
+        genre: GENRES.get(data[127] as usize).map(|genre| genre.to_string()),
+        track,
+        ..Metadata::default()
+    })
+}
+
+/// Reads an APEv2 tag at the end of the file, or before its ID3v1 tag.
+fn read_ape(file: &mut File, has_id3v1: bool) -> Option<Metadata> {
+    let end = file.seek(SeekFrom::End(0)).ok()? - if has_id3v1 { ID3V1_SIZE } else { 0 };
+    let mut footer = [0; APE_FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(end.checked_sub(APE_FOOTER_SIZE)?)).ok()?;
+    file.read_exact(&mut footer).ok()?;
+    if &footer[..8] != b"APETAGEX" {
+        return None;
+    }
+    let size = u64::from(u32::from_le_bytes(footer[12..16].try_into().unwrap()));
+    let count = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+    if !(APE_FOOTER_SIZE..=MAX_TAG_SIZE).contains(&size) {
+        return None;
+    }
+    // The size counts the items and the footer, not the optional header.
+    let mut items = vec![0; (size - APE_FOOTER_SIZE) as usize];
+    file.seek(SeekFrom::Start(end.checked_sub(size)?)).ok()?;
+    file.read_exact(&mut items).ok()?;
+
+    let mut metadata = Metadata::default();
+    let mut rest = &items[..];
+    for _ in 0..count {
+        if rest.len() < 8 {
+            break;
+        }
+        let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let flags = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let key_end = 8 + rest[8..].iter().position(|&byte| byte == 0)?;
+        let key = String::from_utf8_lossy(&rest[8..key_end]).to_lowercase();
+        let value = rest.get(key_end + 1..key_end + 1 + length)?;
+        rest = &rest[key_end + 1 + length..];
+
+        if flags & APE_BINARY_FLAG != 0 {
+            // Cover art items hold a file name, then the image.
+            if key == "cover art (front)" || (key.starts_with("cover art") && metadata.picture.is_none()) {
+                let start = value.iter().position(|&byte| byte == 0).map(|end| end + 1).unwrap_or(0);
+                metadata.picture = Some(value[start..].to_vec());
+            }
+            continue;
+        }
+        // Items are UTF-8, but some taggers wrote the local code page.
+        let value = decode_legacy(value);
+        // Lists are separated by NUL, only the first value is kept.
+        let value = value.split('\0').next().unwrap_or_default().trim().to_string();
+        match key.as_str() {
+            "title" => metadata.title = Some(value),
+            "artist" => metadata.artist = Some(value),
+            "album" => metadata.album = Some(value),
+            "genre" => metadata.genre = Some(genre_name(&value)),
+            "year" => metadata.year = parse_year(&value),
+            "track" => metadata.set_number_pair(&value),
+            _ => (),
+        }
+    }
+    Some(metadata)
+}
+
+/// Reads the Vorbis comments and front cover among the metadata blocks following `fLaC`.
+fn read_flac(file: &mut File) -> Option<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut picture_type = None;
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header).ok()?;
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        if block_type == FLAC_VORBIS_COMMENT || block_type == FLAC_PICTURE {
+            let mut block = vec![0; length.min(MAX_TAG_SIZE) as usize];
+            file.read_exact(&mut block).ok()?;
+            if block_type == FLAC_VORBIS_COMMENT {
+                let comments = parse_vorbis_comments(&block)?;
+                picture_type = picture_type.or(comments.1);
+                metadata.merge(comments.0);
+            } else if let Some((kind, data)) = parse_flac_picture(&block) {
+                if picture_type.map(|current| current != FLAC_FRONT_COVER && kind == FLAC_FRONT_COVER).unwrap_or(true) {
+                    picture_type = Some(kind);
+                    metadata.picture = Some(data);
+                }
+            }
+        } else {
+            file.seek(SeekFrom::Current(length as i64)).ok()?;
+        }
+        if last {
+            return Some(metadata);
+        }
+    }
+}
+
+/// Reads the comment header, the second packet of the first logical stream of an Ogg
+/// Vorbis or Opus file.
+fn read_ogg(file: &mut File) -> Option<Metadata> {
+    file.seek(SeekFrom::Start(0)).ok()?;
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut serial = None;
+    for _ in 0..OGG_MAX_PAGES {
+        let mut header = [0; 27];
+        file.read_exact(&mut header).ok()?;
+        if &header[..4] != b"OggS" {
+            return None;
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let mut segments = vec![0; header[26] as usize];
+        file.read_exact(&mut segments).ok()?;
+        let mut body = vec![0; segments.iter().map(|&length| length as usize).sum()];
+        file.read_exact(&mut body).ok()?;
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &length in &segments {
+            packets.last_mut()?.extend_from_slice(&body[offset..offset + length as usize]);
+            offset += length as usize;
+            // A segment shorter than 255 bytes ends its packet.
+            if length < 255 {
+                if packets.len() == 2 {
+                    let packet = &packets[1];
+                    let comments = packet.strip_prefix(b"\x03vorbis")
+                        .or_else(|| packet.strip_prefix(b"OpusTags"))?;
+                    return parse_vorbis_comments(comments).map(|(metadata, _)| metadata);
+                }
+                packets.push(Vec::new());
+            }
+        }
+    }
+    None
+}
+
+/// Parses a Vorbis comment block, returning the fields and the type of a picture embedded
+/// as `METADATA_BLOCK_PICTURE`.
+fn parse_vorbis_comments(data: &[u8]) -> Option<(Metadata, Option<u32>)> {
+    let read_length = |data: &[u8]| -> Option<usize> {
+        Some(u32::from_le_bytes(data.get(..4)?.try_into().unwrap()) as usize)
+    };
+    let vendor_length = read_length(data)?;
+    let mut rest = data.get(4 + vendor_length..)?;
+    let count = read_length(rest)?;
+    rest = &rest[4..];
+
+    let mut metadata = Metadata::default();
+    let mut picture_type = None;
+    let mut total_tracks = None;
+    for _ in 0..count {
+        let length = read_length(rest)?;
+        let comment = String::from_utf8_lossy(rest.get(4..4 + length)?).into_owned();
+        rest = &rest[4 + length..];
+        let (key, value) = match comment.split_once('=') {
+            Some((key, value)) => (key.to_uppercase(), value.trim().to_string()),
+            None => continue,
+        };
+        match key.as_str() {
+            "TITLE" => metadata.title = metadata.title.take().or(Some(value)),
+            "ARTIST" => metadata.artist = metadata.artist.take().or(Some(value)),
+            "ALBUM" => metadata.album = metadata.album.take().or(Some(value)),
+            "GENRE" => metadata.genre = metadata.genre.take().or(Some(value)),
+            "DATE" | "YEAR" => metadata.year = metadata.year.or_else(|| parse_year(&value)),
+            "TRACKNUMBER" => metadata.set_number_pair(&value),
+            "TRACKTOTAL" | "TOTALTRACKS" => total_tracks = value.parse().ok(),
+            "METADATA_BLOCK_PICTURE" => {
+                if let Some((kind, data)) = decode_base64(&value).as_deref().and_then(parse_flac_picture) {
+                    if picture_type != Some(FLAC_FRONT_COVER) {
+                        picture_type = Some(kind);
+                        metadata.picture = Some(data);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+    metadata.total_tracks = metadata.total_tracks.or(total_tracks);
+    Some((metadata, picture_type))
+}
+
+/// Parses a FLAC picture block into its picture type and image data.
+fn parse_flac_picture(block: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(block.get(offset..offset + 4)?.try_into().unwrap()))
+    };
+    let kind = read_u32(0)?;
+    let mime_length = read_u32(4)? as usize;
+    let description_offset = 8 + mime_length;
+    let description_length = read_u32(description_offset)? as usize;
+    // Width, height, color depth and number of colors precede the data length.
+    let data_offset = description_offset + 4 + description_length + 16;
+    let data_length = read_u32(data_offset)? as usize;
+    let data = block.get(data_offset + 4..data_offset + 4 + data_length)?;
+    Some((kind, data.to_vec()))
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in text.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' | b'\r' | b'\n' | b' ' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+/// Splits a string terminated as the ID3 `encoding` requires from the data following it.
+pub fn split_id3_string(data: &[u8], encoding: u8) -> (String, &[u8]) {
+    let wide = encoding == 1 || encoding == 2;
+    let (end, terminator) = if wide {
+        let end = (0..data.len() / 2).map(|index| index * 2)
+            .find(|&index| data[index] == 0 && data[index + 1] == 0);
+        (end, 2)
+    } else {
+        (data.iter().position(|&byte| byte == 0), 1)
+    };
+    let (bytes, rest) = match end {
+        Some(end) => (&data[..end], &data[end + terminator..]),
+        None => (data, &data[data.len()..]),
+    };
+    let text = match encoding {
+        0 => decode_legacy(bytes),
+        1 | 2 => decode_utf16(bytes, encoding == 2),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    };
+    (text, rest)
+}
+
+fn decode_utf16(mut bytes: &[u8], mut big_endian: bool) -> String {
+    if bytes.starts_with(&[0xff, 0xfe]) {
+        big_endian = false;
+        bytes = &bytes[2..];
+    } else if bytes.starts_with(&[0xfe, 0xff]) {
+        big_endian = true;
+        bytes = &bytes[2..];
+    }
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|pair| if big_endian { u16::from_be_bytes([pair[0], pair[1]]) } else { u16::from_le_bytes([pair[0], pair[1]]) })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rusic-metadata-{}-{}", process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(b"vendor");
+        data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    fn flac_picture(kind: u32, image: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&kind.to_be_bytes());
+        data.extend_from_slice(&10u32.to_be_bytes());
+        data.extend_from_slice(b"image/jpeg");
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&(image.len() as u32).to_be_bytes());
+        data.extend_from_slice(image);
+        data
+    }
+
+    fn flac_block(block_type: u8, last: bool, block: &[u8]) -> Vec<u8> {
+        let length = (block.len() as u32).to_be_bytes();
+        let mut data = vec![block_type | if last { 0x80 } else { 0 }, length[1], length[2], length[3]];
+        data.extend_from_slice(block);
+        data
+    }
+
+    fn ogg_page(serial: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut segments = Vec::new();
+        let mut body = Vec::new();
+        for packet in packets {
+            let mut rest = *packet;
+            while rest.len() >= 255 {
+                segments.push(255);
+                rest = &rest[255..];
+            }
+            segments.push(rest.len() as u8);
+            body.extend_from_slice(packet);
+        }
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&[0; 8]);
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(&body);
+        page
+    }
+
+    fn ape_item(key: &str, flags: u32, value: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(key.as_bytes());
+        data.push(0);
+        data.extend_from_slice(value);
+        data
+    }
+
+    fn id3v2_frame(id: &str, content: &[u8]) -> Vec<u8> {
+        let mut data = id.as_bytes().to_vec();
+        data.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(content);
+        data
+    }
+
+    #[test]
+    fn resolves_genre_references() {
+        assert_eq!(genre_name("(17)"), "Rock");
+        assert_eq!(genre_name("17"), "Rock");
+        assert_eq!(genre_name("(17)Indie Rock"), "Indie Rock");
+        assert_eq!(genre_name("Jazz"), "Jazz");
+        assert_eq!(genre_name("(999)"), "(999)");
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8h").unwrap(), b"hello!");
+        assert!(decode_base64("aGVs*G8=").is_none());
+    }
+
+    #[test]
+    fn parses_numbers_and_years() {
+        assert_eq!(parse_number_pair("3/12"), (Some(3), Some(12)));
+        assert_eq!(parse_number_pair(" 7 "), (Some(7), None));
+        assert_eq!(parse_year("1959-08-17T10:00"), Some(1959));
+        assert_eq!(parse_year("unknown"), None);
+    }
+
+    #[test]
+    fn parses_vorbis_comments() {
+        let picture = flac_picture(FLAC_FRONT_COVER, b"cover");
+        let encoded = {
+            const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+            picture.chunks(3).map(|chunk| {
+                let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+                let value = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+                (0..4).map(|index| {
+                    if index > chunk.len() { '=' } else { ALPHABET[(value >> (18 - 6 * index) & 0x3f) as usize] as char }
+                }).collect::<String>()
+            }).collect::<String>()
+        };
+        let block = vorbis_comments(&[
+            "title=Song", "ARTIST=Artist", "Album=Album", "GENRE=Jazz", "DATE=2001-02-03",
+            "TRACKNUMBER=4", "TRACKTOTAL=9", "no separator", &format!("METADATA_BLOCK_PICTURE={}", encoded),
+        ]);
+        let (metadata, picture_type) = parse_vorbis_comments(&block).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.genre.as_deref(), Some("Jazz"));
+        assert_eq!(metadata.year, Some(2001));
+        assert_eq!((metadata.track, metadata.total_tracks), (Some(4), Some(9)));
+        assert_eq!(metadata.picture.as_deref(), Some(&b"cover"[..]));
+        assert_eq!(picture_type, Some(FLAC_FRONT_COVER));
+
+        assert!(parse_vorbis_comments(&block[..block.len() - 3]).is_none());
+    }
+
+    #[test]
+    fn reads_flac() {
+        let mut data = b"fLaC".to_vec();
+        data.extend(flac_block(0, false, &[0; 34]));
+        data.extend(flac_block(FLAC_PICTURE, false, &flac_picture(0, b"other")));
+        data.extend(flac_block(FLAC_VORBIS_COMMENT, false, &vorbis_comments(&["TITLE=Flac song"])));
+        data.extend(flac_block(FLAC_PICTURE, true, &flac_picture(FLAC_FRONT_COVER, b"front")));
+        let path = temp_file("song.flac", &data);
+        let metadata = read_metadata(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Flac song"));
+        assert_eq!(metadata.picture.as_deref(), Some(&b"front"[..]));
+    }
+
+    #[test]
+    fn reads_ogg() {
+        let mut comments = b"\x03vorbis".to_vec();
+        comments.extend(vorbis_comments(&["TITLE=Ogg song", &format!("COMMENT={}", "x".repeat(300))]));
+        let mut data = ogg_page(1, &[b"\x01vorbis identification"]);
+        // Pages of another logical stream are skipped.
+        data.extend(ogg_page(2, &[b"\x01other", b"\x03vorbis other"]));
+        data.extend(ogg_page(1, &[&comments]));
+        let path = temp_file("song.ogg", &data);
+        let metadata = read_metadata(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Ogg song"));
+    }
+
+    #[test]
+    fn reads_ape_before_id3v1() {
+        let mut items = ape_item("Title", 0, "Ape song".as_bytes());
+        items.extend(ape_item("Artist", 0, b"First\0Second"));
+        items.extend(ape_item("Track", 0, b"2/10"));
+        items.extend(ape_item("Cover Art (Front)", APE_BINARY_FLAG, b"cover.jpg\0image"));
+        let mut data = vec![0xff, 0xfb, 0x90, 0x00];
+        data.extend_from_slice(&items);
+        data.extend_from_slice(b"APETAGEX");
+        data.extend_from_slice(&2000u32.to_le_bytes());
+        data.extend_from_slice(&(items.len() as u32 + APE_FOOTER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes());
+        data.extend_from_slice(&[0; 12]);
+        let mut id3v1 = vec![0; ID3V1_SIZE as usize];
+        id3v1[..3].copy_from_slice(b"TAG");
+        id3v1[3..13].copy_from_slice(b"V1 title  ");
+        id3v1[33..42].copy_from_slice(b"V1 artist");
+        id3v1[127] = 8;
+        data.extend(id3v1);
+
+        let path = temp_file("ape.mp3", &data);
+        let metadata = read_metadata(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Ape song"));
+        assert_eq!(metadata.artist.as_deref(), Some("First"));
+        assert_eq!((metadata.track, metadata.total_tracks), (Some(2), Some(10)));
+        assert_eq!(metadata.picture.as_deref(), Some(&b"image"[..]));
+        // Fields missing from the APE tag come from the ID3v1 one.
+        assert_eq!(metadata.genre.as_deref(), Some("Jazz"));
+    }
+
+    #[test]
+    fn redecodes_only_latin1_frames() {
+        set_legacy_encoding(Some("windows-1251"));
+        let mut frames = id3v2_frame("TIT2", b"\x00\xcf\xf0\xe8\xe2\xe5\xf2");
+        frames.extend(id3v2_frame("TPE1", "\u{3}Björk".as_bytes()));
+        frames.extend(id3v2_frame("TALB", b"\x01\xff\xfeM\x00\xf6\x00"));
+        frames.extend(id3v2_frame("COMM", b"\x00eng\x00\xcf"));
+        frames.extend_from_slice(&[0; 16]);
+        let mut data = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len() as u32;
+        data.extend_from_slice(&[(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+        data.extend_from_slice(&frames);
+
+        let latin1 = id3v2_latin1_frames(&data);
+        assert_eq!(latin1.get("TIT2").map(String::as_str), Some("Привет"));
+        assert!(!latin1.contains_key("TPE1"));
+        assert!(!latin1.contains_key("TALB"));
+        assert_eq!(latin1.len(), 1);
+
+        let mut unsynchronised = data.clone();
+        unsynchronised[5] = ID3V2_UNSYNCHRONISATION;
+        assert_eq!(id3v2_latin1_frames(&unsynchronised), latin1);
+        assert_eq!(resynchronise(&[0xff, 0x00, 0x00, 0xff, 0xe0]), [0xff, 0x00, 0xff, 0xe0]);
+    }
+
+    #[test]
+    fn measures_audio_without_tags() {
+        let audio = [0xff, 0xfb, 0x90, 0x00, 0x12, 0x34];
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x0a".to_vec();
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(&audio);
+        assert_eq!(audio_size(&mut Cursor::new(&data)), Some(audio.len() as u64));
+
+        let items = ape_item("Title", 0, b"Ape song");
+        let mut ape = b"APETAGEX".to_vec();
+        ape.extend_from_slice(&2000u32.to_le_bytes());
+        ape.extend_from_slice(&(items.len() as u32 + APE_FOOTER_SIZE as u32).to_le_bytes());
+        ape.extend_from_slice(&1u32.to_le_bytes());
+        ape.extend_from_slice(&APE_HAS_HEADER.to_le_bytes());
+        ape.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&ape);
+        data.extend_from_slice(&items);
+        data.extend_from_slice(&ape);
+        let mut id3v1 = vec![0; ID3V1_SIZE as usize];
+        id3v1[..3].copy_from_slice(b"TAG");
+        data.extend(id3v1);
+        assert_eq!(audio_size(&mut Cursor::new(&data)), Some(audio.len() as u64));
+
+        assert_eq!(audio_size(&mut Cursor::new(&audio)), Some(audio.len() as u64));
+    }
+}
@@ -1,9 +1,44 @@
 use std::io::{Read, Seek, SeekFrom};
 use std::time::Duration;
 
-use simplemad;
+use simplemad_sys::{MadLayer, MadMode};
+
+use crate::metadata::audio_size;
 use crate::to_millis;
 
+/// Stream properties measured by a pass over every frame header.
+pub struct AudioProperties {
+    pub duration: Duration,
+    /// The average bit rate, in bits per second.
+    pub bit_rate: u32,
+    pub sample_rate: u32,
+    pub mode: MadMode,
+    pub layer: MadLayer,
+    /// The file size, in bytes.
+    pub size: u64,
+    /// The size without the tags, which rewriting them leaves unchanged.
+    pub audio_size: u64,
+}
+
+impl AudioProperties {
+    pub fn channels(&self) -> &'static str {
+        match self.mode {
+            MadMode::SingleChannel => "Mono",
+            MadMode::DualChannel => "Dual channel",
+            MadMode::JointStereo => "Joint stereo",
+            MadMode::Stereo => "Stereo",
+        }
+    }
+
+    pub fn codec(&self) -> &'static str {
+        match self.layer {
+            MadLayer::LayerI => "MPEG Layer I",
+            MadLayer::LayerII => "MPEG Layer II",
+            MadLayer::LayerIII => "MPEG Layer III",
+        }
+    }
+}
+
 pub struct Mp3Decoder<R> where R: Read {
     reader: simplemad::Decoder<R>,
     current_frame: simplemad::Frame,
@@ -13,7 +48,7 @@ pub struct Mp3Decoder<R> where R: Read {
 }
 
 fn is_mp3<R>(mut data: R) -> bool where R: Read + Seek {
-    let stream_pos = data.seek(SeekFrom::Current(0)).unwrap();
+    let stream_pos = data.stream_position().unwrap();
     let is_mp3 = simplemad::Decoder::decode(data.by_ref()).is_ok();
     data.seek(SeekFrom::Start(stream_pos)).unwrap();
     is_mp3
@@ -35,7 +70,7 @@ fn next_frame<R: Read>(decoder: &mut simplemad::Decoder<R>) -> simplemad::Frame
 }
 
 fn next_sample<R: Read>(decoder: &mut Mp3Decoder<R>) -> Option<i16> {
-    if decoder.current_frame.samples[0].len() == 0 {
+    if decoder.current_frame.samples[0].is_empty() {
         return None
     }
 
@@ -65,19 +100,28 @@ fn next_sample<R: Read>(decoder: &mut Mp3Decoder<R>) -> Option<i16> {
     decoder.current_frame_sample_pos = 0;
     decoder.current_time += to_millis(decoder.current_frame.duration);
 
-    return Some(sample);
+    Some(sample)
 }
 
 impl<R> Mp3Decoder<R> where R: Read + Seek {
-    pub fn new(mut data: R) -> Result<Mp3Decoder<R>, R> {
+    pub fn new(data: R) -> Result<Mp3Decoder<R>, R> {
+        Self::new_at(data, Duration::from_secs(0))
+    }
+
+    /// Decodes from `start`, skipping the frames before it by their headers only.
+    pub fn new_at(mut data: R, start: Duration) -> Result<Mp3Decoder<R>, R> {
         if !is_mp3(data.by_ref()) {
             return Err(data);
         }
 
-        let mut reader = simplemad::Decoder::decode(data).unwrap();
+        let mut reader = if start == Duration::from_secs(0) {
+            simplemad::Decoder::decode(data).unwrap()
+        } else {
+            simplemad::Decoder::decode_interval(data, start, Duration::MAX).unwrap()
+        };
 
         let current_frame = next_frame(&mut reader);
-        let current_time = to_millis(current_frame.duration);
+        let current_time = to_millis(current_frame.position + current_frame.duration);
 
         Ok(Mp3Decoder {
             reader,
@@ -96,18 +140,40 @@ impl<R> Mp3Decoder<R> where R: Read + Seek {
         self.current_frame.sample_rate
     }
 
-    pub fn compute_duration(mut data: R) -> Option<Duration> {
+    pub fn compute_properties(mut data: R) -> Option<AudioProperties> {
         if !is_mp3(data.by_ref()) {
             return None
         }
 
+        let position = data.stream_position().ok()?;
+        let size = data.seek(SeekFrom::End(0)).ok()?;
+        let audio_size = audio_size(&mut data).unwrap_or(size);
+        data.seek(SeekFrom::Start(position)).ok()?;
+
         let decoder = simplemad::Decoder::decode_headers(data).unwrap();
-        Some(decoder.filter_map(|frame| {
-            match frame {
-                Ok(frame) => Some(frame.duration),
-                Err(_) => None
+        let mut properties = AudioProperties {
+            duration: Duration::from_secs(0),
+            bit_rate: 0,
+            sample_rate: 0,
+            mode: MadMode::Stereo,
+            layer: MadLayer::LayerIII,
+            size,
+            audio_size,
+        };
+        // Weighted by frame duration, so that VBR files get their average bit rate.
+        let mut bits = 0;
+        for frame in decoder.filter_map(|frame| frame.ok()) {
+            if properties.duration == Duration::from_secs(0) {
+                properties.sample_rate = frame.sample_rate;
+                properties.mode = frame.mode;
+                properties.layer = frame.layer;
             }
-        }).sum())
+            bits += frame.bit_rate as u64 * to_millis(frame.duration);
+            properties.duration += frame.duration;
+        }
+
+        properties.bit_rate = bits.checked_div(to_millis(properties.duration)).unwrap_or(0) as u32;
+        Some(properties)
     }
 }
 
@@ -5,21 +5,27 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::cell::Cell;
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crossbeam::sync::SegQueue;
 use pulse_simple::Playback;
 
-use crate::mp3::Mp3Decoder;
+use crate::library::PlayStats;
+use crate::mp3::{AudioProperties, Mp3Decoder};
+use crate::stretch::TimeStretch;
 use self::Action::*;
 
-use std::time::Duration;
+use crate::to_millis;
 
 const BUFFER_SIZE: usize = 1000;
 const DEFAULT_RATE: u32 = 44100;
 
 enum Action {
     Load(PathBuf),
+    Seek(u64),
+    Speed(f64),
     Stop,
+    Volume(f64),
 }
 
 #[derive(Clone)]
@@ -41,10 +47,19 @@ impl EventLoop {
 
 pub(crate) struct State {
     pub current_time: u64,
-    pub durations: HashMap<String, u64>,
+    pub ended: bool,
+    pub plays: HashMap<String, PlayStats>,
+    pub properties: HashMap<String, AudioProperties>,
     pub stopped: bool,
 }
 
+impl State {
+    /// The duration in milliseconds of the file at `path`, once measured.
+    pub fn duration(&self, path: &str) -> Option<u64> {
+        self.properties.get(path).map(|properties| to_millis(properties.duration))
+    }
+}
+
 pub struct Player {
     app_state: Arc<Mutex<super::State>>,
     event_loop: EventLoop,
@@ -74,34 +89,77 @@ impl Player {
                 let mut buffer = [[0; 2]; BUFFER_SIZE];
                 let mut playback = Playback::new("MP3", "MP3 Playback", None, DEFAULT_RATE);
                 let mut source = None;
+                let mut current_path = None;
+                let mut stretch = TimeStretch::new(DEFAULT_RATE, 1.0);
+                let mut volume = 1.0;
 
                 loop {
                     if let Some(action) = event_loop.queue.try_pop() {
                         match action {
                             Load(path) => {
                                 println!("Load {:?}", path);
-                                let file = File::open(path).unwrap();
-                                source = Some(Mp3Decoder::new(BufReader::new(file)).unwrap());
+                                let decoder = File::open(&path).map_err(|error| error.to_string())
+                                    .and_then(|file| Mp3Decoder::new(BufReader::new(file)).map_err(|_| "not an MP3 file".to_string()));
+                                source = match decoder {
+                                    Ok(decoder) => Some(decoder),
+                                    Err(error) => {
+                                        eprintln!("Cannot play {}: {}", path.display(), error);
+                                        source = None;
+                                        current_path = None;
+                                        app_state.lock().unwrap().stopped = true;
+                                        *event_loop.playing.lock().unwrap() = false;
+                                        continue;
+                                    },
+                                };
                                 let rate = source.as_ref().map(|source| source.sample_rate()).unwrap_or(DEFAULT_RATE);
                                 playback = Playback::new("MP3", "MP3 Playback", None, rate);
+                                stretch = TimeStretch::new(rate, stretch.speed());
+                                current_path = Some(path);
                                 app_state.lock().unwrap().stopped = false;
                                 *event_loop.playing.lock().unwrap() = true;
                             },
+                            Seek(millis) => {
+                                // The stream is decoded again from the frame holding `millis`.
+                                let decoder = current_path.as_ref()
+                                    .and_then(|path| File::open(path).ok())
+                                    .and_then(|file| Mp3Decoder::new_at(BufReader::new(file), Duration::from_millis(millis)).ok());
+                                if let Some(decoder) = decoder {
+                                    app_state.lock().unwrap().current_time = decoder.current_time();
+                                    source = Some(decoder);
+                                    stretch.reset();
+                                }
+                            },
+                            Speed(speed) => stretch.set_speed(speed),
                             Stop => {},
+                            Volume(level) => volume = level,
                         }
                     } else if *event_loop.playing.lock().unwrap() {
                         let mut written = false;
                         if let Some(ref mut source) = source {
-                            let size = iter_to_buffer(source, &mut buffer);
+                            let size = if stretch.is_bypassed() {
+                                iter_to_buffer(source, &mut buffer)
+                            } else {
+                                stretch.process(source, &mut buffer)
+                            };
                             if size > 0 {
+                                if volume < 1.0 {
+                                    for frame in buffer[..size].iter_mut() {
+                                        for sample in frame.iter_mut() {
+                                            *sample = (f64::from(*sample) * volume) as i16;
+                                        }
+                                    }
+                                }
                                 playback.write(&buffer[..size]);
                                 written = true;
                             }
-                            app_state.lock().unwrap().current_time = source.current_time();
+                            // Media time: what was decoded but is still in the stretcher is yet to be heard.
+                            app_state.lock().unwrap().current_time = source.current_time().saturating_sub(stretch.latency());
                         }
 
                         if !written {
-                            app_state.lock().unwrap().stopped = true;
+                            let mut app_state = app_state.lock().unwrap();
+                            app_state.stopped = true;
+                            app_state.ended = true;
                             *event_loop.playing.lock().unwrap() = false;
                             source = None;
                         }
@@ -125,6 +183,22 @@ impl Player {
         self.event_loop.queue.push(Load(file));
     }
 
+    /// Moves playback of the loaded file to `millis`. A paused player seeks once resumed.
+    pub fn seek(&self, millis: u64) {
+        self.app_state.lock().unwrap().current_time = millis;
+        self.emit(Seek(millis));
+    }
+
+    /// Plays at `speed` times the normal speed, keeping the pitch.
+    pub fn set_speed(&self, speed: f64) {
+        self.emit(Speed(speed));
+    }
+
+    /// Scales the samples by `volume`, from 0 for silence to 1 for the volume of the file.
+    pub fn set_volume(&self, volume: f64) {
+        self.emit(Volume(volume.clamp(0.0, 1.0)));
+    }
+
     pub fn is_paused(&self) -> bool {
         self.paused.get()
     }
@@ -177,7 +251,7 @@ fn iter_to_buffer<I: Iterator<Item=i16>> (iter: &mut I, buffer: &mut [[i16; 2];
     index
 }
 
-pub fn compute_duration<P: AsRef<Path>>(path: P) -> Option<Duration> {
-    let file = File::open(path).unwrap();
-    Mp3Decoder::compute_duration(BufReader::new(file))
+pub fn compute_properties<P: AsRef<Path>>(path: P) -> Option<AudioProperties> {
+    let file = File::open(path).ok()?;
+    Mp3Decoder::compute_properties(BufReader::new(file))
 }
\ No newline at end of file
@@ -1,40 +1,74 @@
-use std::path::Path;
-
-use m3u;
+use std::path::{Path, PathBuf};
 
 use std::fs::File;
 
-use gdk_pixbuf::{InterpType, Pixbuf, PixbufLoader};
+use gdk_pixbuf::Pixbuf;
+
+use gdk::{EventButton, EventType};
 
 use gtk::{
+    Cast,
     CellLayoutExt,
+    CellRendererExt,
+    CheckMenuItem,
+    CheckMenuItemExt,
     CellRendererPixbuf,
     CellRendererText,
     ListStore,
     ListStoreExt,
     ListStoreExtManual,
+    Inhibit,
+    Menu,
+    MenuExtManual,
+    MenuItem,
+    MenuItemExt,
+    MenuShellExt,
+    SeparatorMenuItem,
+    SortColumn,
+    SortType,
+    TreeSortableExtManual,
+    SelectionMode,
     StaticType,
     ToValue,
     TreeIter,
     TreeModelExt,
+    TreeModelFilter,
+    TreeModelFilterExt,
+    TreeModelFilterExtManual,
+    TreeRowReference,
     TreeSelectionExt,
     TreeView,
     TreeViewColumn,
     TreeViewColumnExt,
+    TreeViewDropPosition,
     TreeViewExt,
     Type,
+    Value,
     WidgetExt,
 };
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::max;
-use std::thread;
-use crate::to_millis;
+use std::collections::{HashMap, HashSet};
+use std::io::{Seek, SeekFrom};
+use std::rc::Rc;
 
-use id3::Tag;
+use rand::seq::SliceRandom;
 
+use crate::config::ColumnLayout;
+use crate::cover::CoverCache;
+use crate::library::PlayStats;
+use crate::metadata::audio_size;
+use crate::{format_timestamp, millis_to_hours, millis_to_minutes, to_millis};
+use crate::mp3::AudioProperties;
 use crate::player::Player;
 use crate::player::State;
+use crate::queue::Queue;
+use crate::rating::{stars_text, MAX_STARS};
+use crate::search::{Field, Query};
+use crate::watcher::moved_path;
+use crate::scanner::{measure_properties, TrackInfo};
+use crate::scrobble::ListenedTrack;
 use self::Visibility::*;
 
 use std::sync::{Arc, Mutex};
@@ -54,21 +88,155 @@ const YEAR_COLUMN: u32 = 5;
 const TRACK_COLUMN: u32 = 6;
 const PATH_COLUMN: u32 = 7;
 const PIXBUF_COLUMN: u32 = 8;
-const IMAGE_SIZE: i32 = 256;
-const THUMBNAIL_SIZE: i32 = 64;
-const INTERP_HYPER: InterpType = 3;
+const TRACK_NUMBER_COLUMN: u32 = 9;
+const DURATION_COLUMN: u32 = 10;
+const DURATION_MILLIS_COLUMN: u32 = 11;
+const BITRATE_COLUMN: u32 = 12;
+const BITRATE_KBPS_COLUMN: u32 = 13;
+const SAMPLE_RATE_COLUMN: u32 = 14;
+const SAMPLE_RATE_HZ_COLUMN: u32 = 15;
+const CHANNELS_COLUMN: u32 = 16;
+const CODEC_COLUMN: u32 = 17;
+const SIZE_COLUMN: u32 = 18;
+const SIZE_BYTES_COLUMN: u32 = 19;
+const MISSING_COLUMN: u32 = 20;
+const PLAYS_COLUMN: u32 = 21;
+const PLAY_COUNT_COLUMN: u32 = 22;
+const LAST_PLAYED_COLUMN: u32 = 23;
+const LAST_PLAYED_TIME_COLUMN: u32 = 24;
+const RATING_COLUMN: u32 = 25;
+const RATING_STARS_COLUMN: u32 = 26;
+/// The position of each row before a reordering, to tell how the rows moved.
+const ORDER_COLUMN: u32 = 27;
+
+const NO_ARTIST: &str = "(no artist)";
+const NO_ALBUM: &str = "(no album)";
+const HIDDEN_COLUMNS: &[&str] = &["sample_rate", "channels", "codec", "size", "path"];
+
+fn field_column(field: Field) -> u32 {
+    match field {
+        Field::Title => TITLE_COLUMN,
+        Field::Artist => ARTIST_COLUMN,
+        Field::Album => ALBUM_COLUMN,
+        Field::Genre => GENRE_COLUMN,
+        Field::Path => PATH_COLUMN,
+        Field::Year => YEAR_COLUMN,
+        Field::Track => TRACK_COLUMN,
+    }
+}
+
+/// Gives where a loaded file starts playing.
+type ResumePosition = Box<dyn Fn(&str) -> Option<u64>>;
+
+type FileAction = Box<dyn Fn()>;
+
+/// How many edits can be undone.
+const MAX_EDITS: usize = 100;
+
+/// A playlist edit, as stored on the undo and redo stacks: what takes it back.
+enum Edit {
+    /// Rows to put back at their positions, in ascending order, after they were removed.
+    Insert(Vec<(usize, Vec<Value>)>),
+    /// The positions of rows to take out, in ascending order, after they were inserted.
+    Remove(Vec<usize>),
+    /// Values to set back on the rows at their positions, after they changed.
+    Replace(Vec<(usize, Vec<Value>)>),
+    /// The order to put the rows back in, as `ListStore::reorder` takes it, after a sort or a move.
+    Reorder(Vec<u32>),
+    /// Edits made as one, in the order they were made.
+    Group(Vec<Edit>),
+    /// A change to files, e.g. to their tags, that `revert` takes back and `apply` makes again.
+    Files { revert: FileAction, apply: FileAction },
+}
 
+#[derive(Default)]
+struct Edits {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl Edits {
+    /// Records an edit about to be made, which can no longer be redone over.
+    fn record(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        if self.undo.len() > MAX_EDITS {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    /// Records `edit` as part of the last one.
+    fn join(&mut self, edit: Edit) {
+        let joined = match self.undo.pop() {
+            Some(Edit::Group(mut edits)) => {
+                edits.push(edit);
+                Edit::Group(edits)
+            },
+            Some(last) => Edit::Group(vec![last, edit]),
+            None => edit,
+        };
+        self.record(joined);
+    }
+}
+
+/// Numbers the rows in their order, for `restoring_order` to tell how they were moved.
+fn number_rows(model: &ListStore) {
+    if let Some(iter) = model.get_iter_first() {
+        let mut position = 0_u32;
+        loop {
+            model.set_value(&iter, ORDER_COLUMN, &position.to_value());
+            position += 1;
+            if !model.iter_next(&iter) {
+                break;
+            }
+        }
+    }
+}
+
+/// The order putting the rows numbered by `number_rows` back where they were.
+fn restoring_order(model: &ListStore) -> Vec<u32> {
+    let mut order = vec![0; model.iter_n_children(None) as usize];
+    if let Some(iter) = model.get_iter_first() {
+        let mut position = 0_u32;
+        loop {
+            let number = model.get_value(&iter, ORDER_COLUMN as i32).get::<u32>().unwrap_or_default();
+            if let Some(slot) = order.get_mut(number as usize) {
+                *slot = position;
+            }
+            position += 1;
+            if !model.iter_next(&iter) {
+                break;
+            }
+        }
+    }
+    order
+}
 
 pub struct Playlist {
+    clipboard: RefCell<Vec<Vec<Value>>>,
+    columns: Rc<Vec<(&'static str, TreeViewColumn)>>,
+    covers: CoverCache,
+    current_row: RefCell<Option<TreeRowReference>>,
     current_song: RefCell<Option<String>>,
+    edits: Rc<RefCell<Edits>>,
+    filter: TreeModelFilter,
+    history: RefCell<Vec<TreeRowReference>>,
+    known_properties: Cell<usize>,
     model: ListStore,
-    player: Player,
+    player: Rc<Player>,
+    query: Rc<RefCell<Query>>,
+    queue: Rc<Queue>,
+    repeat: Cell<bool>,
+    resume_position: RefCell<Option<ResumePosition>>,
+    shuffle: Cell<bool>,
+    shuffle_played: RefCell<HashSet<String>>,
     state: Arc<Mutex<State>>,
+    summary_changed: Rc<Cell<bool>>,
     treeview: TreeView,
 }
 
 impl Playlist {
-    pub(crate) fn new(state: Arc<Mutex<State>>) -> Self {
+    pub(crate) fn new(state: Arc<Mutex<State>>, queue: Rc<Queue>, player: Rc<Player>, layout: &[ColumnLayout]) -> Self {
         let model = ListStore::new(&[
             Pixbuf::static_type(),
             Type::String,
@@ -79,19 +247,77 @@ impl Playlist {
             Type::String,
             Type::String,
             Pixbuf::static_type(),
+            Type::U32,
+            Type::String,
+            Type::U64,
+            Type::String,
+            Type::U32,
+            Type::String,
+            Type::U32,
+            Type::String,
+            Type::String,
+            Type::String,
+            Type::U64,
+            Type::Bool,
+            Type::String,
+            Type::U32,
+            Type::String,
+            Type::I64,
+            Type::String,
+            Type::U32,
+            Type::U32,
         ]);
 
-        let treeview = TreeView::new_with_model(&model);
+        let query = Rc::new(RefCell::new(Query::parse("")));
+        let filter = TreeModelFilter::new(&model, None);
+        {
+            let query = query.clone();
+            filter.set_visible_func(move |model, iter| {
+                let query = query.borrow();
+                query.is_empty() || query.matches(|field| {
+                    model.get_value(iter, field_column(field) as i32).get::<String>()
+                })
+            });
+        }
+
+        let treeview = TreeView::new_with_model(&filter);
         treeview.set_hexpand(true);
         treeview.set_vexpand(true);
+        treeview.get_selection().set_mode(SelectionMode::Multiple);
 
-        Self::create_columns(&treeview);
+        let edits = Rc::new(RefCell::new(Edits::default()));
+        let columns = Self::create_columns(&treeview, &model, &edits, layout);
+
+        let summary_changed = Rc::new(Cell::new(true));
+        {
+            let summary_changed = summary_changed.clone();
+            model.connect_row_deleted(move |_, _| summary_changed.set(true));
+        }
+        {
+            let summary_changed = summary_changed.clone();
+            model.connect_row_changed(move |_, _, _| summary_changed.set(true));
+        }
 
         Playlist{
+            clipboard: RefCell::new(Vec::new()),
+            columns,
+            covers: CoverCache::new(),
+            current_row: RefCell::new(None),
             current_song: RefCell::new(None),
+            edits,
+            filter,
+            history: RefCell::new(Vec::new()),
+            known_properties: Cell::new(0),
             model,
-            player: Player::new(state.clone()),
+            player,
+            query,
+            queue,
+            repeat: Cell::new(false),
+            resume_position: RefCell::new(None),
+            shuffle: Cell::new(false),
+            shuffle_played: RefCell::new(HashSet::new()),
             state,
+            summary_changed,
             treeview,
         }
     }
@@ -100,124 +326,955 @@ impl Playlist {
         &self.treeview
     }
 
-    fn create_columns(treeview: &TreeView) {
-        Self::add_pixbuf_column(treeview, THUMBNAIL_COLUMN as i32, Visible);
-        Self::add_text_column(treeview, "Title", TITLE_COLUMN as i32);
-        Self::add_text_column(treeview, "Artist", ARTIST_COLUMN as i32);
-        Self::add_text_column(treeview, "Album", ALBUM_COLUMN as i32);
-        Self::add_text_column(treeview, "Genre", GENRE_COLUMN as i32);
-        Self::add_text_column(treeview, "Year", YEAR_COLUMN as i32);
-        Self::add_text_column(treeview, "Track", TRACK_COLUMN as i32);
+    /// Builds the view columns in their default order, then applies the saved `layout`.
+    fn create_columns(treeview: &TreeView, model: &ListStore, edits: &Rc<RefCell<Edits>>, layout: &[ColumnLayout]) -> Rc<Vec<(&'static str, TreeViewColumn)>> {
+        let columns = Rc::new(vec![
+            ("cover", Self::add_pixbuf_column(treeview, THUMBNAIL_COLUMN as i32, Visible)),
+            ("title", Self::add_text_column(treeview, model, edits, "Title", TITLE_COLUMN, TITLE_COLUMN)),
+            ("artist", Self::add_text_column(treeview, model, edits, "Artist", ARTIST_COLUMN, ARTIST_COLUMN)),
+            ("album", Self::add_text_column(treeview, model, edits, "Album", ALBUM_COLUMN, ALBUM_COLUMN)),
+            ("genre", Self::add_text_column(treeview, model, edits, "Genre", GENRE_COLUMN, GENRE_COLUMN)),
+            ("year", Self::add_text_column(treeview, model, edits, "Year", YEAR_COLUMN, YEAR_COLUMN)),
+            ("track", Self::add_text_column(treeview, model, edits, "Track", TRACK_COLUMN, TRACK_NUMBER_COLUMN)),
+            ("duration", Self::add_text_column(treeview, model, edits, "Duration", DURATION_COLUMN, DURATION_MILLIS_COLUMN)),
+            ("bitrate", Self::add_text_column(treeview, model, edits, "Bitrate", BITRATE_COLUMN, BITRATE_KBPS_COLUMN)),
+            ("sample_rate", Self::add_text_column(treeview, model, edits, "Sample rate", SAMPLE_RATE_COLUMN, SAMPLE_RATE_HZ_COLUMN)),
+            ("channels", Self::add_text_column(treeview, model, edits, "Channels", CHANNELS_COLUMN, CHANNELS_COLUMN)),
+            ("codec", Self::add_text_column(treeview, model, edits, "Codec", CODEC_COLUMN, CODEC_COLUMN)),
+            ("size", Self::add_text_column(treeview, model, edits, "Size", SIZE_COLUMN, SIZE_BYTES_COLUMN)),
+            ("plays", Self::add_text_column(treeview, model, edits, "Plays", PLAYS_COLUMN, PLAY_COUNT_COLUMN)),
+            ("last_played", Self::add_text_column(treeview, model, edits, "Last played", LAST_PLAYED_COLUMN, LAST_PLAYED_TIME_COLUMN)),
+            ("rating", Self::add_text_column(treeview, model, edits, "Rating", RATING_COLUMN, RATING_STARS_COLUMN)),
+            ("path", Self::add_text_column(treeview, model, edits, "Path", PATH_COLUMN, PATH_COLUMN)),
+        ]);
         Self::add_pixbuf_column(treeview, PIXBUF_COLUMN as i32, Invisible);
+        for id in HIDDEN_COLUMNS {
+            if let Some(column) = Self::column_by_id(&columns, id) {
+                column.set_visible(false);
+            }
+        }
+
+        let mut previous = None;
+        for column_layout in layout {
+            if let Some(column) = Self::column_by_id(&columns, &column_layout.id) {
+                treeview.move_column_after(column, previous.as_ref());
+                column.set_visible(column_layout.visible);
+                previous = Some(column.clone());
+            }
+        }
+
+        for (_, column) in columns.iter() {
+            let header_columns = columns.clone();
+            let treeview = treeview.clone();
+            let column = column.clone();
+            if let Some(button) = column.get_button() {
+                button.connect_button_press_event(move |_, event| {
+                    if event.get_button() == 3 {
+                        Self::show_header_menu(&treeview, &header_columns, &column, event);
+                        return Inhibit(true);
+                    }
+                    Inhibit(false)
+                });
+            }
+        }
+
+        columns
+    }
 
+    fn column_by_id<'a>(columns: &'a [(&'static str, TreeViewColumn)], id: &str) -> Option<&'a TreeViewColumn> {
+        columns.iter()
+            .find(|&&(column_id, _)| column_id == id)
+            .map(|(_, column)| column)
     }
 
-    fn add_text_column(treeview: &TreeView, title: &str, column: i32) {
+    /// The header context menu: a check item per column to show or hide it,
+    /// and items to move the clicked column.
+    fn show_header_menu(treeview: &TreeView, columns: &[(&'static str, TreeViewColumn)],
+                        clicked: &TreeViewColumn, event: &EventButton) {
+        let menu = Menu::new();
+        for column in treeview.get_columns() {
+            if !columns.iter().any(|(_, known)| *known == column) {
+                continue;
+            }
+            let title = column.get_title().filter(|title| !title.is_empty()).unwrap_or("Cover".to_string());
+            let item = CheckMenuItem::new_with_label(&title);
+            item.set_active(column.get_visible());
+            item.connect_toggled(move |item| {
+                column.set_visible(item.get_active());
+            });
+            menu.append(&item);
+        }
+
+        menu.append(&SeparatorMenuItem::new());
+
+        let visible_columns: Vec<_> = treeview.get_columns().into_iter()
+            .filter(|column| columns.iter().any(|(_, known)| known == column))
+            .collect();
+        let index = visible_columns.iter().position(|column| column == clicked).unwrap_or(0);
+
+        let move_left_item = MenuItem::new_with_mnemonic("Move _left");
+        move_left_item.set_sensitive(index > 0);
+        {
+            let treeview = treeview.clone();
+            let clicked = clicked.clone();
+            let base = if index >= 2 { visible_columns.get(index - 2).cloned() } else { None };
+            move_left_item.connect_activate(move |_| {
+                treeview.move_column_after(&clicked, base.as_ref());
+            });
+        }
+        menu.append(&move_left_item);
+
+        let move_right_item = MenuItem::new_with_mnemonic("Move _right");
+        let base = visible_columns.get(index + 1).cloned();
+        move_right_item.set_sensitive(base.is_some());
+        {
+            let treeview = treeview.clone();
+            let clicked = clicked.clone();
+            move_right_item.connect_activate(move |_| {
+                treeview.move_column_after(&clicked, base.as_ref());
+            });
+        }
+        menu.append(&move_right_item);
+
+        menu.show_all();
+        menu.popup_easy(event.get_button(), event.get_time());
+    }
+
+    /// The current column order and visibility, to be saved in the config.
+    pub fn column_layout(&self) -> Vec<ColumnLayout> {
+        self.treeview.get_columns().iter()
+            .filter_map(|column| {
+                self.columns.iter()
+                    .find(|(_, known)| known == column)
+                    .map(|&(id, _)| ColumnLayout {
+                        id: id.to_string(),
+                        visible: column.get_visible(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Calls `callback` whenever columns are moved, shown or hidden.
+    pub fn connect_layout_changed<F: Fn() + 'static>(&self, callback: F) {
+        let callback = Rc::new(callback);
+        {
+            let callback = callback.clone();
+            self.treeview.connect_columns_changed(move |_| callback());
+        }
+        for (_, column) in self.columns.iter() {
+            let callback = callback.clone();
+            column.connect_property_visible_notify(move |_| callback());
+        }
+    }
+
+    /// Calls `callback` with the file of the row and the stars clicked in its rating, 0 when
+    /// clicking the rating it already has, to clear it.
+    pub fn connect_rating_changed<F: Fn(PathBuf, u8) + 'static>(&self, callback: F) {
+        let rating_column = match Self::column_by_id(&self.columns, "rating") {
+            Some(column) => column.clone(),
+            None => return,
+        };
+        let filter = self.filter.clone();
+        self.treeview.connect_button_press_event(move |treeview, event| {
+            if event.get_button() != 1 || event.get_event_type() != EventType::ButtonPress {
+                return Inhibit(false);
+            }
+            let (x, y) = event.get_position();
+            let (path, cell_x) = match treeview.get_path_at_pos(x as i32, y as i32) {
+                Some((Some(path), Some(column), cell_x, _)) if column == rating_column => (path, cell_x),
+                _ => return Inhibit(false),
+            };
+            // Every row draws five stars, so one of them is a fifth of the text of the cell.
+            let cell = match rating_column.get_cells().into_iter().next() {
+                Some(cell) => cell,
+                None => return Inhibit(false),
+            };
+            let (padding, _) = cell.get_padding();
+            let (_, width) = cell.get_preferred_width(treeview);
+            let star_width = max(1, (width - 2 * padding) / MAX_STARS as i32);
+            let offset = cell_x - padding;
+            if offset < 0 || offset >= star_width * MAX_STARS as i32 {
+                return Inhibit(false);
+            }
+            if let Some(iter) = filter.get_iter(&path) {
+                let file = filter.get_value(&iter, PATH_COLUMN as i32).get::<String>().unwrap_or_default();
+                let current = filter.get_value(&iter, RATING_STARS_COLUMN as i32).get::<u32>().unwrap_or(0);
+                let stars = (offset / star_width + 1) as u8;
+                callback(PathBuf::from(file), if u32::from(stars) == current { 0 } else { stars });
+            }
+            Inhibit(false)
+        });
+    }
+
+    /// Sets where loaded files start playing, from the start when `resume_position` gives `None`.
+    pub fn connect_resume_position<F: Fn(&str) -> Option<u64> + 'static>(&self, resume_position: F) {
+        *self.resume_position.borrow_mut() = Some(Box::new(resume_position));
+    }
+
+    fn add_text_column(treeview: &TreeView, model: &ListStore, edits: &Rc<RefCell<Edits>>, title: &str, column: u32,
+                       sort_column: u32) -> TreeViewColumn {
         let view_column = TreeViewColumn::new();
         view_column.set_title(title);
         let cell = CellRendererText::new();
         view_column.set_expand(true);
+        view_column.set_reorderable(true);
+        view_column.set_clickable(true);
         view_column.pack_start(&cell, true);
-        view_column.add_attribute(&cell, "text", column);
+        view_column.add_attribute(&cell, "text", column as i32);
+        view_column.add_attribute(&cell, "strikethrough", MISSING_COLUMN as i32);
         treeview.append_column(&view_column);
+
+        // The view shows a filter, which cannot sort, so the header sorts the store itself.
+        let edits = edits.clone();
+        let model = model.clone();
+        view_column.connect_clicked(move |view_column| {
+            number_rows(&model);
+            let order = match model.get_sort_column_id() {
+                Some((SortColumn::Index(current), SortType::Ascending)) if current == sort_column => SortType::Descending,
+                _ => SortType::Ascending,
+            };
+            model.set_sort_column_id(SortColumn::Index(sort_column), order);
+            edits.borrow_mut().record(Edit::Reorder(restoring_order(&model)));
+            if let Some(treeview) = view_column.get_tree_view().and_then(|widget| widget.downcast::<TreeView>().ok()) {
+                for column in treeview.get_columns() {
+                    column.set_sort_indicator(false);
+                }
+            }
+            view_column.set_sort_indicator(true);
+            view_column.set_sort_order(order);
+        });
+        view_column
     }
 
-    fn add_pixbuf_column(treeview: &TreeView, column: i32, visibility: Visibility) {
+    fn add_pixbuf_column(treeview: &TreeView, column: i32, visibility: Visibility) -> TreeViewColumn {
         let view_column = TreeViewColumn::new();
         if visibility == Visible {
             let cell = CellRendererPixbuf::new();
             view_column.pack_start(&cell, true);
             view_column.add_attribute(&cell, "pixbuf", column);
+            view_column.set_reorderable(true);
         }
         treeview.append_column(&view_column);
+        view_column
     }
 
-    fn set_pixbuf(&self, row: &TreeIter, tag: &Tag) {
-        if let Some(picture) = tag.pictures().next() {
-            let pixbuf_loader = PixbufLoader::new();
-            pixbuf_loader.set_size(IMAGE_SIZE, IMAGE_SIZE);
-            pixbuf_loader.loader_write(&picture.data).unwrap();
-            if let Some(pixbuf) = pixbuf_loader.get_pixbuf() {
-                let thumbnail = pixbuf.scale_simple(THUMBNAIL_SIZE,
-                    THUMBNAIL_SIZE, INTERP_HYPER
-                ).unwrap();
-                self.model.set_value(row, THUMBNAIL_COLUMN, &thumbnail.to_value());
-                self.model.set_value(row, PIXBUF_COLUMN, &pixbuf.to_value());
-            }
-            pixbuf_loader.close().unwrap();
+    fn set_pixbuf(&self, row: &TreeIter, picture: Option<&[u8]>) {
+        let (pixbuf, thumbnail) = match picture.and_then(|picture| self.covers.get(picture)) {
+            Some((pixbuf, thumbnail)) => (Some(pixbuf), Some(thumbnail)),
+            None => (None, None),
+        };
+        self.model.set_value(row, THUMBNAIL_COLUMN, &thumbnail.to_value());
+        self.model.set_value(row, PIXBUF_COLUMN, &pixbuf.to_value());
+    }
+
+    /// Adds a row for a track whose tags were already read, e.g. by a background `Scan`,
+    /// at `position` or at the end of the playlist.
+    pub fn insert(&self, info: &TrackInfo, position: Option<i32>) {
+        let row = self.model.insert(position.unwrap_or(-1));
+        self.set_info(&row, info);
+        self.model.set_value(&row, PATH_COLUMN, &info.path.to_value());
+
+        let state = self.state.lock().unwrap();
+        if let Some(properties) = state.properties.get(&info.path) {
+            self.set_properties(&row, properties);
         }
+        self.set_plays(&row, state.plays.get(&info.path));
     }
 
-    pub fn add(&self, path: &Path) {
-        self.compute_duration(path);
-        let filename = path.file_stem().unwrap_or_default().to_str().unwrap_or_default();
-        let row = self.model.append();
+    fn set_plays(&self, row: &TreeIter, stats: Option<&PlayStats>) {
+        let (count, last_played) = stats.map(|stats| (stats.count, stats.last_played)).unwrap_or((0, 0));
+        let last_played_text = if count > 0 { format_timestamp(last_played) } else { String::new() };
+        self.model.set(row, &[PLAYS_COLUMN, PLAY_COUNT_COLUMN, LAST_PLAYED_COLUMN, LAST_PLAYED_TIME_COLUMN],
+                       &[&count.to_string(), &count, &last_played_text, &last_played]);
+    }
+
+    /// Shows the play statistics of `path` again, once it was played.
+    pub fn update_plays(&self, path: &str) {
+        let stats = self.state.lock().unwrap().plays.get(path).cloned();
+        self.for_rows_under(Path::new(path), |iter, row_path| {
+            if row_path == Path::new(path) {
+                self.set_plays(iter, stats.as_ref());
+            }
+        });
+    }
+
+    fn set_info(&self, row: &TreeIter, info: &TrackInfo) {
+        let filename = info.filename();
+        let title = info.title.clone().unwrap_or(filename);
+        let artist = info.artist.as_deref().unwrap_or(NO_ARTIST);
+        let album = info.album.as_deref().unwrap_or(NO_ALBUM);
+        let genre = info.genre.as_deref().unwrap_or("(no genre)");
+        let year = info.year.map(|year| year.to_string()).unwrap_or("(no year)".to_string());
+
+        let track = info.track.map(|track| track.to_string()).unwrap_or("??".to_string());
+        let total_tracks = info.total_tracks.map(|total_tracks| total_tracks.to_string()).unwrap_or("??".to_string());
+        let track_value = format!("{} / {}", track, total_tracks);
+
+        self.set_pixbuf(row, info.picture.as_deref());
 
-        if let Ok(tag) = Tag::read_from_path(path) {
-            let title = tag.title().unwrap_or(filename);
-            let artist = tag.artist().unwrap_or("(no artist)");
-            let album = tag.album().unwrap_or("(no album)");
-            let genre = tag.genre().unwrap_or("(no genre)");
-            let year = tag.year().map(|year| year.to_string()).unwrap_or("(no year)".to_string());
+        self.model.set_value(row, TITLE_COLUMN, &title.to_value());
+        self.model.set_value(row, ARTIST_COLUMN, &artist.to_value());
+        self.model.set_value(row, ALBUM_COLUMN, &album.to_value());
+        self.model.set_value(row, GENRE_COLUMN, &genre.to_value());
+        self.model.set_value(row, YEAR_COLUMN, &year.to_value());
+        self.model.set_value(row, TRACK_COLUMN, &track_value.to_value());
+        self.model.set_value(row, TRACK_NUMBER_COLUMN, &info.track.unwrap_or(0).to_value());
+        let stars = info.rating.unwrap_or(0);
+        self.model.set(row, &[RATING_COLUMN, RATING_STARS_COLUMN], &[&stars_text(stars), &(stars as u32)]);
+    }
 
-            let track = tag.track().map(|track| track.to_string()).unwrap_or("??".to_string());
-            let total_tracks = tag.total_tracks().map(|total_tracks| total_tracks.to_string()).unwrap_or("??".to_string());
-            let track_value = format!("{} / {}", track, total_tracks);
+    /// Calls `update` with every row whose file is `path` or, for a folder, under it.
+    fn for_rows_under<F: FnMut(&TreeIter, &Path)>(&self, path: &Path, mut update: F) {
+        if let Some(iter) = self.model.get_iter_first() {
+            loop {
+                if let Some(row_path) = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>() {
+                    if Path::new(&row_path).starts_with(path) {
+                        update(&iter, Path::new(&row_path));
+                    }
+                }
+                if !self.model.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+    }
 
-            self.set_pixbuf(&row, &tag);
+    /// Removes every row, as an edit.
+    pub fn clear(&self) {
+        let edit = self.take_rows((0..self.len()).collect());
+        self.edits.borrow_mut().record(edit);
+    }
 
-            self.model.set_value(&row, TITLE_COLUMN, &title.to_value());
-            self.model.set_value(&row, ARTIST_COLUMN, &artist.to_value());
-            self.model.set_value(&row, ALBUM_COLUMN, &album.to_value());
-            self.model.set_value(&row, GENRE_COLUMN, &genre.to_value());
-            self.model.set_value(&row, YEAR_COLUMN, &year.to_value());
-            self.model.set_value(&row, TRACK_COLUMN, &track_value.to_value());
+    pub fn len(&self) -> usize {
+        self.model.iter_n_children(None) as usize
+    }
+
+    /// Records the `count` rows inserted from `start`, e.g. by an import, as an edit, or as
+    /// part of the last one if `join` is set.
+    pub fn record_insertion(&self, start: usize, count: usize, join: bool) {
+        let edit = Edit::Remove((start..start + count).collect());
+        if join {
+            self.edits.borrow_mut().join(edit);
         } else {
-            self.model.set_value(&row, TITLE_COLUMN, &filename.to_value());
+            self.edits.borrow_mut().record(edit);
+        }
+    }
+
+    /// Makes the rows those of `paths`, in this order, e.g. a re-evaluated smart playlist, and
+    /// returns the paths that have no row yet, to be imported before syncing again. The rows
+    /// there are kept, and as they follow the paths, the edits before can no longer be undone.
+    pub fn sync_paths(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let wanted: HashMap<String, usize> = paths.iter().enumerate()
+            .map(|(index, path)| (path.to_string_lossy().to_string(), index))
+            .collect();
+        let mut present = HashSet::new();
+        // The position of each row kept among `paths`.
+        let mut positions = Vec::new();
+        if let Some(iter) = self.model.get_iter_first() {
+            loop {
+                let path = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>().unwrap_or_default();
+                let valid = match wanted.get(&path) {
+                    Some(&position) if present.insert(path) => {
+                        positions.push(position);
+                        self.model.iter_next(&iter)
+                    },
+                    _ => self.model.remove(&iter),
+                };
+                if !valid {
+                    break;
+                }
+            }
+        }
+        if positions.windows(2).any(|pair| pair[0] > pair[1]) {
+            let mut new_order: Vec<u32> = (0..positions.len() as u32).collect();
+            new_order.sort_by_key(|&row| positions[row as usize]);
+            self.unsort();
+            self.model.reorder(&new_order);
+        }
+        *self.edits.borrow_mut() = Edits::default();
+
+        paths.iter()
+            .filter(|path| !present.contains(path.to_string_lossy().as_ref()))
+            .cloned()
+            .collect()
+    }
+
+    /// Points the rows at the positions of `relocated` to their new file and removes the
+    /// rows at `removed`, as one edit.
+    pub fn fix_rows(&self, removed: &[usize], relocated: &[(usize, PathBuf)]) {
+        let mut replaced = Vec::new();
+        for (row, path) in relocated {
+            if let Some(iter) = self.model.iter_nth_child(None, *row as i32) {
+                replaced.push((*row, self.row_values(&iter)));
+                self.set_info(&iter, &TrackInfo::read(path));
+                self.model.set_value(&iter, MISSING_COLUMN, &false.to_value());
+            }
+        }
+        let removed = self.take_rows(removed.to_vec());
+        self.edits.borrow_mut().record(Edit::Group(vec![Edit::Replace(replaced), removed]));
+    }
+
+    /// Reads the tags of `path` again for its rows, e.g. after it was retagged.
+    /// The file is only read if it has rows, most changes of the music folders have none.
+    /// Its audio is only measured again if its size without the tags changed, unless it was
+    /// `retagged` by the application, whose edits leave the audio alone.
+    pub fn update_file(&self, path: &Path, retagged: bool) {
+        let mut info = None;
+        self.for_rows_under(path, |iter, _| {
+            let info = info.get_or_insert_with(|| TrackInfo::read(path));
+            self.set_info(iter, info);
+            self.model.set_value(iter, MISSING_COLUMN, &false.to_value());
+            // Cleared so that `refresh_properties` fills the row once measured again.
+            self.model.set_value(iter, SIZE_BYTES_COLUMN, &0_u64.to_value());
+        });
+        if info.is_none() {
+            return;
+        }
+        let remeasure = {
+            let mut state = self.state.lock().unwrap();
+            let key = path.to_string_lossy();
+            match state.properties.get_mut(&*key) {
+                Some(properties) => {
+                    let mut file = File::open(path).ok();
+                    let size = file.as_mut().and_then(|file| file.seek(SeekFrom::End(0)).ok());
+                    let audio_size = file.as_mut().and_then(audio_size);
+                    match size {
+                        Some(size) if retagged || audio_size == Some(properties.audio_size) => {
+                            properties.size = size;
+                            false
+                        },
+                        // The first playlist with rows of the file has it measured again.
+                        _ => state.properties.remove(&*key).is_some(),
+                    }
+                },
+                None => false,
+            }
+        };
+        if remeasure {
+            measure_properties(path.to_path_buf(), self.state.clone());
+        }
+        // Has the next `refresh_properties` go over the rows whatever the count.
+        self.known_properties.set(usize::MAX);
+    }
+
+    /// Flags the rows whose file is gone, `path` being a file or a folder.
+    pub fn mark_missing(&self, path: &Path) {
+        self.for_rows_under(path, |iter, _| {
+            self.model.set_value(iter, MISSING_COLUMN, &true.to_value());
+        });
+    }
+
+    /// Points the rows of a moved file, or of the files of a moved folder, to their new path.
+    pub fn rename_file(&self, from: &Path, to: &Path) {
+        let mut current_song = self.current_song.borrow_mut();
+        let mut state = self.state.lock().unwrap();
+        self.for_rows_under(from, |iter, row_path| {
+            let new_path = match moved_path(row_path, from, to) {
+                Some(new_path) => new_path,
+                None => return,
+            };
+            let (row_path, new_path) = (row_path.to_string_lossy().to_string(), new_path.to_string_lossy().to_string());
+            if let Some(properties) = state.properties.remove(&row_path) {
+                state.properties.insert(new_path.clone(), properties);
+            }
+            if current_song.as_ref() == Some(&row_path) {
+                *current_song = Some(new_path.clone());
+            }
+            self.model.set_value(iter, PATH_COLUMN, &new_path.to_value());
+            self.model.set_value(iter, MISSING_COLUMN, &false.to_value());
+        });
+    }
+
+    fn set_properties(&self, row: &TreeIter, properties: &AudioProperties) {
+        let duration = to_millis(properties.duration);
+        let kbps = properties.bit_rate / 1000;
+        let sample_rate = format!("{:.1} kHz", properties.sample_rate as f64 / 1000.0);
+        let size = format!("{:.1} MB", properties.size as f64 / (1024.0 * 1024.0));
+        self.model.set_value(row, DURATION_COLUMN, &millis_to_minutes(duration).to_value());
+        self.model.set_value(row, DURATION_MILLIS_COLUMN, &duration.to_value());
+        self.model.set_value(row, BITRATE_COLUMN, &format!("{} kbps", kbps).to_value());
+        self.model.set_value(row, BITRATE_KBPS_COLUMN, &kbps.to_value());
+        self.model.set_value(row, SAMPLE_RATE_COLUMN, &sample_rate.to_value());
+        self.model.set_value(row, SAMPLE_RATE_HZ_COLUMN, &properties.sample_rate.to_value());
+        self.model.set_value(row, CHANNELS_COLUMN, &properties.channels().to_value());
+        self.model.set_value(row, CODEC_COLUMN, &properties.codec().to_value());
+        self.model.set_value(row, SIZE_COLUMN, &size.to_value());
+        self.model.set_value(row, SIZE_BYTES_COLUMN, &properties.size.to_value());
+    }
+
+    /// Returns the model position matching a drop at `(x, y)` in the view, `None` meaning the end.
+    pub fn drop_position(&self, x: i32, y: i32) -> Option<i32> {
+        let (path, position) = match self.treeview.get_dest_row_at_pos(x, y) {
+            Some((Some(path), position)) => (path, position),
+            _ => return None,
+        };
+        let path = self.filter.convert_path_to_child_path(&path)?;
+        let index = *path.get_indices().first()?;
+        match position {
+            TreeViewDropPosition::Before | TreeViewDropPosition::IntoOrBefore => Some(index),
+            _ => Some(index + 1),
+        }
+    }
+
+    /// Shows only the rows matching `text`, see `Query` for the syntax.
+    pub fn set_filter(&self, text: &str) {
+        *self.query.borrow_mut() = Query::parse(text);
+        self.filter.refilter();
+    }
+
+    fn is_visible(&self, iter: &TreeIter) -> bool {
+        self.filter.convert_child_iter_to_iter(iter).is_some()
+    }
+
+    fn is_selected(&self, iter: &TreeIter) -> bool {
+        match self.filter.convert_child_iter_to_iter(iter) {
+            Some(filter_iter) => self.treeview.get_selection().iter_is_selected(&filter_iter),
+            None => false,
+        }
+    }
+
+    fn select(&self, iter: &TreeIter) {
+        if let Some(filter_iter) = self.filter.convert_child_iter_to_iter(iter) {
+            self.treeview.get_selection().select_iter(&filter_iter);
+        }
+    }
+
+    /// Selects the rows of `paths` only.
+    pub fn select_paths(&self, paths: &[String]) {
+        self.treeview.get_selection().unselect_all();
+        for path in paths {
+            if let Some(iter) = self.find_row(path) {
+                self.select(&iter);
+            }
+        }
+    }
+
+    /// The files of all the rows, in order.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        if let Some(iter) = self.model.get_iter_first() {
+            loop {
+                paths.extend(self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>());
+                if !self.model.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+        paths
+    }
+
+    /// Records a change to files of the playlist, reverted by `revert` and made again by `apply`.
+    pub fn record_file_edit<R: Fn() + 'static, A: Fn() + 'static>(&self, revert: R, apply: A) {
+        self.edits.borrow_mut().record(Edit::Files { revert: Box::new(revert), apply: Box::new(apply) });
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.edits.borrow().undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.edits.borrow().redo.is_empty()
+    }
+
+    pub fn undo(&self) {
+        let edit = self.edits.borrow_mut().undo.pop();
+        if let Some(edit) = edit {
+            let edit = self.revert(edit);
+            self.edits.borrow_mut().redo.push(edit);
+        }
+    }
+
+    pub fn redo(&self) {
+        let edit = self.edits.borrow_mut().redo.pop();
+        if let Some(edit) = edit {
+            let edit = self.revert(edit);
+            self.edits.borrow_mut().undo.push(edit);
+        }
+    }
+
+    /// Takes `edit` back and returns the edit that makes it again.
+    fn revert(&self, edit: Edit) -> Edit {
+        if !matches!(edit, Edit::Files { .. }) {
+            self.unsort();
         }
+        match edit {
+            Edit::Insert(rows) => self.put_rows(rows),
+            Edit::Remove(positions) => self.take_rows(positions),
+            Edit::Replace(rows) => {
+                let mut replaced = Vec::new();
+                for (position, values) in rows {
+                    if let Some(iter) = self.model.iter_nth_child(None, position as i32) {
+                        replaced.push((position, self.row_values(&iter)));
+                        self.set_row_values(&iter, &values);
+                    }
+                }
+                Edit::Replace(replaced)
+            },
+            Edit::Reorder(order) => {
+                number_rows(&self.model);
+                if order.len() == self.len() {
+                    self.model.reorder(&order);
+                }
+                Edit::Reorder(restoring_order(&self.model))
+            },
+            Edit::Group(edits) => Edit::Group(edits.into_iter().rev().map(|edit| self.revert(edit)).collect()),
+            Edit::Files { revert, apply } => {
+                revert();
+                Edit::Files { revert: apply, apply: revert }
+            },
+        }
+    }
+
+    /// Inserts `rows` at their positions, in ascending order, and returns the edit taking
+    /// them out again. The play statistics and audio properties are those known now.
+    fn put_rows(&self, rows: Vec<(usize, Vec<Value>)>) -> Edit {
+        let mut positions = Vec::new();
+        let state = self.state.lock().unwrap();
+        for (position, values) in rows {
+            let iter = self.model.insert(position as i32);
+            self.set_row_values(&iter, &values);
+            if let Some(path) = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>() {
+                if let Some(properties) = state.properties.get(&path) {
+                    self.set_properties(&iter, properties);
+                }
+                self.set_plays(&iter, state.plays.get(&path));
+            }
+            positions.push(position);
+        }
+        drop(state);
+        // The reference to the current row died with it.
+        if self.current_row().is_none() {
+            let current = self.current_song.borrow().clone();
+            *self.current_row.borrow_mut() = current
+                .and_then(|path| self.find_row(&path))
+                .and_then(|iter| self.model.get_path(&iter))
+                .and_then(|path| TreeRowReference::new(&self.model, &path));
+        }
+        Edit::Remove(positions)
+    }
+
+    /// Removes the rows at `positions` and returns the edit putting them back.
+    fn take_rows(&self, mut positions: Vec<usize>) -> Edit {
+        positions.sort_unstable();
+        positions.dedup();
+        let mut rows = Vec::new();
+        for position in positions.into_iter().rev() {
+            if let Some(iter) = self.model.iter_nth_child(None, position as i32) {
+                rows.push((position, self.row_values(&iter)));
+                self.model.remove(&iter);
+            }
+        }
+        rows.reverse();
+        Edit::Insert(rows)
+    }
 
-        let path = path.to_str().unwrap_or_default();
-        self.model.set_value(&row, PATH_COLUMN, &path.to_value());
+    /// The position in the playlist of `iter`.
+    fn position(&self, iter: &TreeIter) -> Option<usize> {
+        self.model.get_path(iter)?.get_indices().first().map(|&index| index as usize)
+    }
+
+    /// Records the moves made by `reorder`, which the rows are numbered for.
+    fn record_reorder<F: FnOnce()>(&self, reorder: F) {
+        number_rows(&self.model);
+        reorder();
+        self.edits.borrow_mut().record(Edit::Reorder(restoring_order(&self.model)));
+    }
+
+    fn selected_rows(&self) -> Vec<TreeIter> {
+        let (paths, _) = self.treeview.get_selection().get_selected_rows();
+        paths.iter()
+            .filter_map(|path| self.filter.convert_path_to_child_path(path))
+            .filter_map(|path| self.model.get_iter(&path))
+            .collect()
+    }
+
+    /// The files of the selected rows.
+    pub fn selected_paths(&self) -> Vec<PathBuf> {
+        self.selected_rows().iter()
+            .filter_map(|iter| self.model.get_value(iter, PATH_COLUMN as i32).get::<String>())
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    fn row_values(&self, iter: &TreeIter) -> Vec<Value> {
+        (0..self.model.get_n_columns())
+            .map(|column| self.model.get_value(iter, column))
+            .collect()
+    }
+
+    fn set_row_values(&self, iter: &TreeIter, values: &[Value]) {
+        for (column, value) in values.iter().enumerate() {
+            self.model.set_value(iter, column as u32, value);
+        }
     }
-    
+
+    fn current_row(&self) -> Option<TreeIter> {
+        let current_row = self.current_row.borrow();
+        let path = current_row.as_ref()?.get_path()?;
+        self.model.get_iter(&path)
+    }
+
+    fn is_current_row(&self, iter: &TreeIter) -> bool {
+        match (self.current_row(), self.model.get_path(iter)) {
+            (Some(current), Some(path)) => self.model.get_path(&current) == Some(path),
+            _ => false,
+        }
+    }
+
+    fn select_only(&self, iter: &TreeIter) {
+        self.treeview.get_selection().unselect_all();
+        self.select(iter);
+    }
+
+    /// Makes sure the row under `(x, y)` is selected, keeping the selection if it already is.
+    pub fn select_at(&self, x: i32, y: i32) {
+        if let Some((Some(path), _, _, _)) = self.treeview.get_path_at_pos(x, y) {
+            let selection = self.treeview.get_selection();
+            if !selection.path_is_selected(&path) {
+                selection.unselect_all();
+                selection.select_path(&path);
+            }
+        }
+    }
+
+    /// Rows keep their sorted order, they can only be moved by hand once unsorted.
+    fn unsort(&self) {
+        self.model.set_unsorted();
+        for (_, column) in self.columns.iter() {
+            column.set_sort_indicator(false);
+        }
+    }
+
     pub fn remove_selection(&self) {
-        let selection = self.treeview.get_selection();
-        if let Some((_, iter)) = selection.get_selected() {
-            self.model.remove(&iter);
+        let positions = self.selected_rows().iter().filter_map(|iter| self.position(iter)).collect();
+        let edit = self.take_rows(positions);
+        self.edits.borrow_mut().record(edit);
+    }
+
+    pub fn copy_selection(&self) {
+        let rows = self.selected_rows().iter().map(|iter| self.row_values(iter)).collect();
+        *self.clipboard.borrow_mut() = rows;
+    }
+
+    pub fn cut_selection(&self) {
+        self.copy_selection();
+        self.remove_selection();
+    }
+
+    /// Appends the rows of `other`, e.g. to duplicate it.
+    pub fn copy_rows_from(&self, other: &Playlist) {
+        if let Some(iter) = other.model.get_iter_first() {
+            loop {
+                let row = self.model.append();
+                self.set_row_values(&row, &other.row_values(&iter));
+                if !other.model.iter_next(&iter) {
+                    break;
+                }
+            }
         }
     }
 
-    pub fn pixbuf(&self) -> Option<Pixbuf> {
-        let selection = self.treeview.get_selection();
-        if let Some((_, iter)) = selection.get_selected() {
-            let value = self.model.get_value(&iter, PIXBUF_COLUMN as i32);
-            return value.get::<Pixbuf>();
+    /// Inserts the copied rows after the last selected row, or at the end of the playlist.
+    pub fn paste(&self) {
+        self.unsort();
+        let mut sibling = self.selected_rows().pop();
+        self.treeview.get_selection().unselect_all();
+        let mut pasted = Vec::new();
+        for values in self.clipboard.borrow().iter() {
+            let iter = self.model.insert_after(sibling.as_ref());
+            if sibling.is_none() {
+                // `insert_after` with no sibling prepends, so the first row goes to the end instead.
+                self.model.move_before(&iter, None);
+            }
+            self.set_row_values(&iter, values);
+            self.select(&iter);
+            pasted.push(iter.clone());
+            sibling = Some(iter);
         }
-        None
+        let positions = pasted.iter().filter_map(|iter| self.position(iter)).collect();
+        self.edits.borrow_mut().record(Edit::Remove(positions));
+    }
+
+    pub fn move_selection_to_top(&self) {
+        self.unsort();
+        self.record_reorder(|| {
+            for iter in self.selected_rows().iter().rev() {
+                self.model.move_after(iter, None);
+            }
+        });
+    }
+
+    pub fn move_selection_to_bottom(&self) {
+        self.unsort();
+        self.record_reorder(|| {
+            for iter in self.selected_rows() {
+                self.model.move_before(&iter, None);
+            }
+        });
     }
 
-    fn selected_path(&self) -> Option<String> {
-        let selection = self.treeview.get_selection();
-        if let Some((_, iter)) = selection.get_selected() {
-            let value = self.model.get_value(&iter, PATH_COLUMN as i32);
-            return value.get::<String>();
+    /// Moves the selected rows, keeping their relative order, so that they start at `position`.
+    pub fn move_selection_to(&self, position: Option<i32>) {
+        self.unsort();
+        let mut anchor = position.and_then(|position| self.model.iter_nth_child(None, position));
+        while let Some(iter) = anchor.take() {
+            if !self.is_selected(&iter) {
+                anchor = Some(iter);
+                break;
+            }
+            if self.model.iter_next(&iter) {
+                anchor = Some(iter);
+            }
         }
-        None
+
+        self.record_reorder(|| {
+            for iter in self.selected_rows() {
+                self.model.move_before(&iter, anchor.as_ref());
+            }
+        });
+    }
+
+    /// Fills the audio properties of the rows whose file was measured since the last call.
+    pub fn refresh_properties(&self) {
+        let state = self.state.lock().unwrap();
+        if state.properties.len() == self.known_properties.get() {
+            return;
+        }
+        self.known_properties.set(state.properties.len());
+
+        if let Some(iter) = self.model.get_iter_first() {
+            loop {
+                let size = self.model.get_value(&iter, SIZE_BYTES_COLUMN as i32).get::<u64>().unwrap_or(0);
+                let path = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>();
+                if let (0, Some(path)) = (size, path) {
+                    if let Some(properties) = state.properties.get(&path) {
+                        self.set_properties(&iter, properties);
+                    }
+                }
+                if !self.model.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Makes `changed_summary` give the summary again, e.g. once the playlist is shown.
+    pub fn invalidate_summary(&self) {
+        self.summary_changed.set(true);
+    }
+
+    /// The footer text, "N tracks, total time", when rows changed since the last call.
+    pub fn changed_summary(&self) -> Option<String> {
+        if !self.summary_changed.replace(false) {
+            return None;
+        }
+
+        let mut count = 0;
+        let mut total = 0;
+        let mut unknown = false;
+        if let Some(iter) = self.model.get_iter_first() {
+            loop {
+                count += 1;
+                match self.model.get_value(&iter, DURATION_MILLIS_COLUMN as i32).get::<u64>() {
+                    Some(duration) if duration > 0 => total += duration,
+                    _ => unknown = true,
+                }
+                if !self.model.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        let tracks = if count == 1 { "track" } else { "tracks" };
+        let approximation = if unknown { "more than " } else { "" };
+        Some(format!("{} {}, {}{}", count, tracks, approximation, millis_to_hours(total)))
+    }
+
+    pub fn pixbuf(&self) -> Option<Pixbuf> {
+        let iter = self.current_row()?;
+        let value = self.model.get_value(&iter, PIXBUF_COLUMN as i32);
+        value.get::<Pixbuf>()
     }
 
     pub fn play(&self) -> bool {
-        if let Some(path) = self.selected_path() {
-            if self.player.is_paused() && Some(&path) == self.path().as_ref() {
-                self.player.resume();
-            } else {
-                self.player.load(&path);
-                *self.current_song.borrow_mut() = Some(path);
-                self.player.resume();
+        match self.selected_rows().into_iter().next() {
+            Some(iter) => self.play_row(&iter, true),
+            None => false,
+        }
+    }
+
+    /// Plays `iter`, or resumes it if it is the paused current row. Returns false, leaving
+    /// the current track as is, when the file of the row is missing.
+    /// When `remember` is set, the row being left is pushed on the history used by `previous`.
+    fn play_row(&self, iter: &TreeIter, remember: bool) -> bool {
+        if !self.is_playable(iter) {
+            return false;
+        }
+        if self.player.is_paused() && self.is_current_row(iter) {
+            self.player.resume();
+            return true;
+        }
+
+        if let Some(path) = self.model.get_value(iter, PATH_COLUMN as i32).get::<String>() {
+            let row = self.model.get_path(iter)
+                .and_then(|path| TreeRowReference::new(&self.model, &path));
+            let previous_row = self.current_row.replace(row);
+            if remember {
+                if let Some(previous_row) = previous_row {
+                    self.history.borrow_mut().push(previous_row);
+                }
             }
+            self.shuffle_played.borrow_mut().insert(path.clone());
+            self.load(path);
+        }
+        true
+    }
 
-            true
-        } else {
-            false
+    /// Whether the file of `iter` is there to be played, striking the row through if not.
+    fn is_playable(&self, iter: &TreeIter) -> bool {
+        let exists = self.model.get_value(iter, PATH_COLUMN as i32).get::<String>()
+            .map(|path| Path::new(&path).is_file())
+            .unwrap_or(false);
+        if self.model.get_value(iter, MISSING_COLUMN as i32).get::<bool>() != Some(!exists) {
+            self.model.set_value(iter, MISSING_COLUMN, &(!exists).to_value());
+        }
+        exists
+    }
+
+    fn load(&self, path: String) {
+        self.player.load(&path);
+        if let Some(position) = self.resume_position.borrow().as_ref().and_then(|resume| resume(&path)) {
+            self.player.seek(position);
+        }
+        *self.current_song.borrow_mut() = Some(path);
+        self.player.resume();
+    }
+
+    /// Plays a queued track: its playlist row if it has one, the file alone otherwise.
+    fn play_path(&self, path: String) {
+        match self.find_row(&path) {
+            Some(iter) => {
+                self.select_only(&iter);
+                self.play_row(&iter, true);
+            },
+            None => {
+                if let Some(previous_row) = self.current_row.replace(None) {
+                    self.history.borrow_mut().push(previous_row);
+                }
+                self.load(path);
+            },
+        }
+    }
+
+    fn find_row(&self, path: &str) -> Option<TreeIter> {
+        let iter = self.model.get_iter_first()?;
+        loop {
+            if self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>().as_deref() == Some(path) {
+                return Some(iter);
+            }
+            if !self.model.iter_next(&iter) {
+                return None;
+            }
         }
     }
 
@@ -225,69 +1282,260 @@ impl Playlist {
         self.player.pause();
     }
 
+    /// Makes the row of `path` the current one, as after a restart. With a `position`, the
+    /// track is also loaded paused there, so that playing resumes it. A file gone since is
+    /// not restored.
+    pub fn restore_current(&self, path: &str, position: Option<u64>) {
+        if !Path::new(path).is_file() {
+            return;
+        }
+        if let Some(iter) = self.find_row(path) {
+            let row = self.model.get_path(&iter)
+                .and_then(|path| TreeRowReference::new(&self.model, &path));
+            *self.current_row.borrow_mut() = row;
+            // Playing resumes the paused track only from its selected row.
+            let selected = self.selected_rows().iter()
+                .any(|selected| self.model.get_path(selected) == self.model.get_path(&iter));
+            if position.is_some() && !selected {
+                self.select_only(&iter);
+            }
+        }
+        if let Some(position) = position {
+            self.player.load(&path.to_string());
+            self.player.seek(position);
+            self.player.pause();
+            *self.current_song.borrow_mut() = Some(path.to_string());
+        }
+    }
+
+    /// Moves the current track to `millis`.
+    pub fn seek(&self, millis: u64) {
+        if self.current_song.borrow().is_some() {
+            self.player.seek(millis);
+        }
+    }
+
     pub fn path(&self) -> Option<String> {
         self.current_song.borrow().clone()
     }
 
+    /// The tags of the playing row, as shown, so that they need not be read again.
+    pub fn current_track(&self) -> Option<ListenedTrack> {
+        let iter = self.current_row()?;
+        let text = |column: u32| self.model.get_value(&iter, column as i32).get::<String>();
+        let artist = text(ARTIST_COLUMN).filter(|artist| artist != NO_ARTIST);
+        let album = text(ALBUM_COLUMN).filter(|album| album != NO_ALBUM);
+        let track = self.model.get_value(&iter, TRACK_NUMBER_COLUMN as i32).get::<u32>().filter(|&track| track != 0);
+        Some(ListenedTrack {
+            artist: artist.unwrap_or_default(),
+            title: text(TITLE_COLUMN)?,
+            album,
+            track,
+        })
+    }
+
     pub fn stop(&self) {
-        *self.current_song.borrow_mut() = None;
+        self.release();
         self.player.stop();
     }
 
-    pub fn next(&self) -> bool {
-        let selection = self.treeview.get_selection();
-        let next_iter = if let Some((_, iter)) = selection.get_selected() {
-            if ! self.model.iter_next(&iter) {
+    /// Forgets the current track without stopping it, once another playlist plays.
+    pub fn release(&self) {
+        *self.current_song.borrow_mut() = None;
+        *self.current_row.borrow_mut() = None;
+        self.history.borrow_mut().clear();
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) {
+        self.shuffle.set(shuffle);
+        self.shuffle_played.borrow_mut().clear();
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle.get()
+    }
+
+    pub fn repeat(&self) -> bool {
+        self.repeat.get()
+    }
+
+    pub fn set_repeat(&self, repeat: bool) {
+        self.repeat.set(repeat);
+    }
+
+    pub fn set_speed(&self, speed: f64) {
+        self.player.set_speed(speed);
+    }
+
+    pub fn set_volume(&self, volume: f64) {
+        self.player.set_volume(volume);
+    }
+
+    /// Adds the selected rows to the queue, at its start when `next` is set.
+    pub fn queue_selection(&self, next: bool) {
+        let tracks: Vec<_> = self.selected_rows().iter()
+            .filter_map(|iter| {
+                let title = self.model.get_value(iter, TITLE_COLUMN as i32).get::<String>()?;
+                let path = self.model.get_value(iter, PATH_COLUMN as i32).get::<String>()?;
+                Some((title, path))
+            })
+            .collect();
+        if next {
+            self.queue.push_front(&tracks);
+        } else {
+            self.queue.push_back(&tracks);
+        }
+    }
+
+    /// The row next/previous navigate from: the playing row, even if it was moved,
+    /// or else the first selected row.
+    fn navigation_row(&self) -> Option<TreeIter> {
+        self.current_row().or_else(|| self.selected_rows().into_iter().next())
+    }
+
+    /// Moves `iter` to the next, or previous, row shown by the search filter.
+    fn step_visible(&self, iter: &TreeIter, forward: bool) -> bool {
+        loop {
+            let moved = if forward { self.model.iter_next(iter) } else { self.model.iter_previous(iter) };
+            if !moved {
                 return false;
             }
-            Some(iter)
+            if self.is_visible(iter) {
+                return true;
+            }
+        }
+    }
+
+    fn first_visible(&self) -> Option<TreeIter> {
+        let iter = self.filter.get_iter_first()?;
+        Some(self.filter.convert_iter_to_child_iter(&iter))
+    }
+
+    fn last_visible(&self) -> Option<TreeIter> {
+        let count = self.filter.iter_n_children(None);
+        let iter = self.filter.iter_nth_child(None, max(0, count - 1))?;
+        Some(self.filter.convert_iter_to_child_iter(&iter))
+    }
+
+    /// The first shown row with a playable file after `iter`, or before it going backward,
+    /// `iter` itself included if `inclusive`. With repeat on, the search wraps around once.
+    fn playable_row(&self, iter: TreeIter, forward: bool, inclusive: bool) -> Option<TreeIter> {
+        if inclusive && self.is_playable(&iter) {
+            return Some(iter);
+        }
+        let start = self.model.get_path(&iter);
+        let mut iter = iter;
+        let mut wrapped = false;
+        loop {
+            if !self.step_visible(&iter, forward) {
+                if !self.repeat.get() || wrapped {
+                    return None;
+                }
+                wrapped = true;
+                iter = if forward { self.first_visible() } else { self.last_visible() }?;
+            }
+            if self.is_playable(&iter) {
+                return Some(iter);
+            }
+            if self.model.get_path(&iter) == start {
+                return None;
+            }
+        }
+    }
+
+    /// Picks a shown row that was not played since shuffle was turned on, starting a new round
+    /// only when repeat is on. Rows known to be missing are left out.
+    fn shuffle_row(&self) -> Option<TreeIter> {
+        let mut candidates = Vec::new();
+        if let Some(iter) = self.first_visible() {
+            let played = self.shuffle_played.borrow();
+            loop {
+                let path = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>();
+                let missing = self.model.get_value(&iter, MISSING_COLUMN as i32).get::<bool>().unwrap_or(false);
+                if !missing && path.map(|path| !played.contains(&path)).unwrap_or(false) {
+                    candidates.push(iter.clone());
+                }
+                if !self.step_visible(&iter, true) {
+                    break;
+                }
+            }
+        }
+
+        if candidates.is_empty() {
+            // With nothing played, every shown row is missing, or there is none.
+            if !self.repeat.get() || self.shuffle_played.borrow().is_empty() {
+                return None;
+            }
+            self.shuffle_played.borrow_mut().clear();
+            return self.shuffle_row();
+        }
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.into_iter().find(|iter| self.is_playable(iter))
+    }
+
+    /// Plays the next track: from the queue first, then from the rows shown in the playlist.
+    /// Files gone missing are skipped.
+    pub fn next(&self) -> bool {
+        while let Some(path) = self.queue.pop() {
+            if Path::new(&path).is_file() {
+                self.play_path(path);
+                return true;
+            }
+        }
+
+        let next_iter = if self.shuffle.get() {
+            self.shuffle_row()
         } else {
-            self.model.get_iter_first()
+            match self.navigation_row() {
+                Some(iter) => self.playable_row(iter, true, false),
+                None => self.first_visible().and_then(|iter| self.playable_row(iter, true, true)),
+            }
         };
 
         if let Some(ref iter) = next_iter {
-            selection.select_iter(iter);
-            self.play();
+            self.select_only(iter);
+            self.play_row(iter, true);
         }
         next_iter.is_some()
     }
 
     pub fn previous(&self) -> bool {
-        let selection = self.treeview.get_selection();
-        let previous_iter = if let Some((_, iter)) = selection.get_selected() {
-            if ! self.model.iter_previous(&iter) {
-                return false;
+        if self.shuffle.get() {
+            loop {
+                let row = self.history.borrow_mut().pop();
+                let row = match row {
+                    Some(row) => row,
+                    None => break,
+                };
+                if let Some(iter) = row.get_path().and_then(|path| self.model.get_iter(&path)) {
+                    if self.is_playable(&iter) {
+                        self.select_only(&iter);
+                        self.play_row(&iter, false);
+                        return true;
+                    }
+                }
             }
-            Some(iter)
-        } else {
-            self.model.iter_nth_child(None, max(0, self.model.iter_n_children(None) - 1))
+        }
+
+        let previous_iter = match self.navigation_row() {
+            Some(iter) => self.playable_row(iter, false, false),
+            None => self.last_visible().and_then(|iter| self.playable_row(iter, false, true)),
         };
 
         if let Some(ref iter) = previous_iter {
-            selection.select_iter(iter);
-            self.play();
+            self.select_only(iter);
+            self.play_row(iter, false);
         }
 
         previous_iter.is_some()
     }
 
-    fn compute_duration(&self, path: &Path) {
-        let state = self.state.clone();
-        let path = path.to_string_lossy().to_string();
-        thread::spawn(move || {
-            if let Some(duration) = crate::player::compute_duration(&path) {
-                let mut state = state.lock().unwrap();
-                state.durations.insert(path, to_millis(duration));
-            }
-        });
-    }
-
     pub fn save(&self, path: &Path) {
         let mut file = File::create(path).unwrap();
         let mut writer = m3u::Writer::new(&mut file);
 
         let mut write_iter = |iter: &TreeIter| {
-            let value = self.model.get_value(&iter, PATH_COLUMN as i32);
+            let value = self.model.get_value(iter, PATH_COLUMN as i32);
             let path = value.get::<String>().unwrap();
             writer.write_entry(&m3u::path_entry(path)).unwrap();
         };
@@ -300,13 +1548,5 @@ impl Playlist {
         }
     }
 
-    pub fn load(&self, path: &Path) {
-        let mut reader = m3u::Reader::open(path).unwrap();
-        for entry in reader.entries() {
-            if let Ok(m3u::Entry::Path(path)) = entry {
-                self.add(&path);
-            }
-        }
-    }
 }
 
@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::path::Path;
+
+use gtk::{
+    Button,
+    ButtonExt,
+    CellLayoutExt,
+    CellRendererText,
+    ContainerExt,
+    Label,
+    ListStore,
+    ListStoreExt,
+    ListStoreExtManual,
+    ScrolledWindow,
+    SelectionMode,
+    TreeIter,
+    TreeModelExt,
+    TreeSelectionExt,
+    TreeView,
+    TreeViewColumn,
+    TreeViewColumnExt,
+    TreeViewExt,
+    Type,
+    WidgetExt,
+};
+
+use gtk::Orientation::{Horizontal, Vertical};
+
+use crate::xdg;
+
+const TITLE_COLUMN: u32 = 0;
+const PATH_COLUMN: u32 = 1;
+const QUEUE_FILE: &str = "queue.m3u";
+const PANEL_WIDTH: i32 = 220;
+
+/// The tracks to play before `Playlist::next` picks from the playlist again.
+///
+/// The queue is kept in `$XDG_DATA_HOME/rusic/queue.m3u` and saved on every change.
+pub struct Queue {
+    model: ListStore,
+    panel: gtk::Box,
+    treeview: TreeView,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        let model = ListStore::new(&[Type::String, Type::String]);
+
+        let treeview = TreeView::new_with_model(&model);
+        treeview.set_reorderable(true);
+        treeview.get_selection().set_mode(SelectionMode::Multiple);
+        let view_column = TreeViewColumn::new();
+        view_column.set_title("Up next");
+        let cell = CellRendererText::new();
+        view_column.pack_start(&cell, true);
+        view_column.add_attribute(&cell, "text", TITLE_COLUMN as i32);
+        treeview.append_column(&view_column);
+
+        let scrolled_window = ScrolledWindow::new(None, None);
+        scrolled_window.set_vexpand(true);
+        scrolled_window.add(&treeview);
+
+        let remove_button = Button::new_with_label("Remove");
+        let clear_button = Button::new_with_label("Clear");
+        let buttons = gtk::Box::new(Horizontal, 5);
+        buttons.add(&remove_button);
+        buttons.add(&clear_button);
+
+        let panel = gtk::Box::new(Vertical, 5);
+        panel.set_size_request(PANEL_WIDTH, -1);
+        panel.add(&Label::new("Queue"));
+        panel.add(&scrolled_window);
+        panel.add(&buttons);
+
+        let queue = Queue {
+            model,
+            panel,
+            treeview,
+        };
+        queue.load(&xdg::data_file(QUEUE_FILE));
+        queue.connect_events(&remove_button, &clear_button);
+        queue
+    }
+
+    fn connect_events(&self, remove_button: &Button, clear_button: &Button) {
+        // Reordering by drag-and-drop inserts, fills then deletes a row, all done once it ends.
+        let model = self.model.clone();
+        self.treeview.connect_drag_end(move |_, _| {
+            save(&model);
+        });
+
+        let model = self.model.clone();
+        let treeview = self.treeview.clone();
+        remove_button.connect_clicked(move |_| {
+            let (paths, _) = treeview.get_selection().get_selected_rows();
+            let rows: Vec<_> = paths.iter().filter_map(|path| model.get_iter(path)).collect();
+            for iter in rows {
+                model.remove(&iter);
+            }
+            save(&model);
+        });
+
+        let model = self.model.clone();
+        clear_button.connect_clicked(move |_| {
+            model.clear();
+            save(&model);
+        });
+    }
+
+    pub fn view(&self) -> &gtk::Box {
+        &self.panel
+    }
+
+    fn set_row(&self, iter: &TreeIter, title: &str, path: &str) {
+        self.model.set(iter, &[TITLE_COLUMN, PATH_COLUMN], &[&title, &path]);
+    }
+
+    /// Adds `(title, path)` tracks at the end of the queue.
+    pub fn push_back(&self, tracks: &[(String, String)]) {
+        for (title, path) in tracks {
+            let iter = self.model.append();
+            self.set_row(&iter, title, path);
+        }
+        save(&self.model);
+    }
+
+    /// Adds `(title, path)` tracks at the start of the queue, keeping their order.
+    pub fn push_front(&self, tracks: &[(String, String)]) {
+        for (position, (title, path)) in tracks.iter().enumerate() {
+            let iter = self.model.insert(position as i32);
+            self.set_row(&iter, title, path);
+        }
+        save(&self.model);
+    }
+
+    /// Removes the first queued track and returns its path.
+    pub fn pop(&self) -> Option<String> {
+        let iter = self.model.get_iter_first()?;
+        let path = self.model.get_value(&iter, PATH_COLUMN as i32).get::<String>();
+        self.model.remove(&iter);
+        save(&self.model);
+        path
+    }
+
+    fn load(&self, path: &Path) {
+        let mut reader = match m3u::Reader::open_ext(path) {
+            Ok(reader) => reader,
+            Err(_) => return,
+        };
+        for entry in reader.entry_exts() {
+            if let Ok(m3u::EntryExt { entry: m3u::Entry::Path(path), extinf }) = entry {
+                let iter = self.model.append();
+                self.set_row(&iter, &extinf.name, &path.to_string_lossy());
+            }
+        }
+    }
+}
+
+fn save(model: &ListStore) {
+    let mut file = match File::create(xdg::data_file(QUEUE_FILE)) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let mut writer = match m3u::Writer::new_ext(&mut file) {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    let mut write_iter = |iter: &TreeIter| {
+        let title = model.get_value(iter, TITLE_COLUMN as i32).get::<String>().unwrap_or_default();
+        // Rows being dragged are briefly empty, they are saved once filled.
+        if let Some(path) = model.get_value(iter, PATH_COLUMN as i32).get::<String>() {
+            let _ = writer.write_entry(&m3u::path_entry(path).extend(-1.0, title));
+        }
+    };
+
+    if let Some(iter) = model.get_iter_first() {
+        write_iter(&iter);
+        while model.iter_next(&iter) {
+            write_iter(&iter);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::OnceLock;
+
+use id3::{Content, Frame, Tag};
+
+pub const MAX_STARS: u8 = 5;
+/// The email identifying the POPM frame written, when none is configured.
+const DEFAULT_EMAIL: &str = "rusic";
+const POPULARIMETER_ID: &str = "POPM";
+const FULL_STAR: char = '★';
+const EMPTY_STAR: char = '☆';
+
+static RATING_EMAIL: OnceLock<String> = OnceLock::new();
+
+/// Sets the email of the POPM frame holding the ratings, e.g. `Windows Media Player 9 Series`
+/// to share them with players that use it.
+pub fn set_rating_email(email: Option<&str>) {
+    let _ = RATING_EMAIL.set(email.unwrap_or(DEFAULT_EMAIL).to_string());
+}
+
+fn rating_email() -> &'static str {
+    RATING_EMAIL.get().map(String::as_str).unwrap_or(DEFAULT_EMAIL)
+}
+
+/// A POPM frame: a Latin-1 email, a rating from 1 to 255, 0 being unknown, and an optional
+/// play counter.
+struct Popularimeter {
+    email: String,
+    rating: u8,
+    counter: Vec<u8>,
+}
+
+impl Popularimeter {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let end = data.iter().position(|&byte| byte == 0)?;
+        let rating = *data.get(end + 1)?;
+        Some(Popularimeter {
+            email: data[..end].iter().map(|&byte| byte as char).collect(),
+            rating,
+            counter: data[end + 2..].to_vec(),
+        })
+    }
+
+    fn to_frame(&self) -> Frame {
+        let mut data: Vec<u8> = self.email.chars()
+            .map(|character| if (character as u32) <= 0xff { character as u8 } else { b'?' })
+            .collect();
+        data.push(0);
+        data.push(self.rating);
+        data.extend_from_slice(&self.counter);
+        Frame::with_content(POPULARIMETER_ID, Content::Unknown(data))
+    }
+}
+
+fn popularimeters(tag: &Tag) -> Vec<Popularimeter> {
+    tag.frames()
+        .filter(|frame| frame.id() == POPULARIMETER_ID)
+        .filter_map(|frame| frame.content().unknown())
+        .filter_map(Popularimeter::parse)
+        .collect()
+}
+
+/// The stars, from 1 to 5, of the POPM frame with the configured email, else of the first
+/// one. The byte ranges are the usual ones, so that ratings of other players read the same.
+pub fn read_rating(tag: &Tag) -> Option<u8> {
+    let frames = popularimeters(tag);
+    let frame = frames.iter().find(|frame| frame.email == rating_email()).or_else(|| frames.first())?;
+    match frame.rating {
+        0 => None,
+        1..=31 => Some(1),
+        32..=95 => Some(2),
+        96..=159 => Some(3),
+        160..=223 => Some(4),
+        _ => Some(5),
+    }
+}
+
+/// Rates the tag with `stars`, 0 removing the rating. The frames of other emails are kept,
+/// as is the play counter.
+pub fn set_rating(tag: &mut Tag, stars: u8) {
+    let mut frames = popularimeters(tag);
+    tag.remove(POPULARIMETER_ID);
+    let rating = match stars.min(MAX_STARS) {
+        0 => 0,
+        1 => 1,
+        2 => 64,
+        3 => 128,
+        4 => 196,
+        _ => 255,
+    };
+    match frames.iter().position(|frame| frame.email == rating_email()) {
+        Some(index) => frames[index].rating = rating,
+        None => frames.push(Popularimeter {
+            email: rating_email().to_string(),
+            rating,
+            counter: Vec::new(),
+        }),
+    }
+    for frame in frames {
+        // A frame left without a rating or a counter says nothing.
+        if frame.rating != 0 || !frame.counter.is_empty() {
+            tag.add_frame(frame.to_frame());
+        }
+    }
+}
+
+/// Draws `stars` out of five, e.g. `★★★☆☆`.
+pub fn stars_text(stars: u8) -> String {
+    (0..MAX_STARS).map(|star| if star < stars { FULL_STAR } else { EMPTY_STAR }).collect()
+}
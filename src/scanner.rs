@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam::sync::SegQueue;
+
+use crate::cover::read_cover;
+use crate::metadata::read_metadata;
+use crate::player::State;
+
+const SUPPORTED_EXTENSIONS: &[&str] = &["mp3"];
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u"];
+const DEFAULT_WORKERS: usize = 4;
+
+pub struct TrackInfo {
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub picture: Option<Vec<u8>>,
+    pub rating: Option<u8>,
+}
+
+impl TrackInfo {
+    pub fn read(path: &Path) -> Self {
+        let metadata = read_metadata(path);
+        TrackInfo {
+            path: path.to_string_lossy().to_string(),
+            title: metadata.title,
+            artist: metadata.artist,
+            album: metadata.album,
+            genre: metadata.genre,
+            year: metadata.year,
+            track: metadata.track,
+            total_tracks: metadata.total_tracks,
+            picture: read_cover(path, metadata.picture),
+            rating: metadata.rating,
+        }
+    }
+
+    pub fn filename(&self) -> String {
+        Path::new(&self.path).file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    has_extension(path, SUPPORTED_EXTENSIONS)
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    has_extension(path, PLAYLIST_EXTENSIONS)
+}
+
+/// Reads the file entries of an M3U playlist, skipping URLs and unreadable lines.
+/// Relative entries are resolved against the playlist's directory.
+pub fn playlist_entries(path: &Path) -> Vec<PathBuf> {
+    let mut reader = match m3u::Reader::open(path) {
+        Ok(reader) => reader,
+        Err(_) => return Vec::new(),
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    reader.entries()
+        .filter_map(|entry| match entry {
+            Ok(m3u::Entry::Path(entry)) => Some(dir.join(entry)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Expands the paths given by the user (files, folders or playlists) into the audio files to add.
+pub fn expand_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(&path, &mut files);
+        } else if is_playlist(&path) {
+            files.extend(playlist_entries(&path));
+        } else if is_supported(&path) {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// Walks `dir` recursively and collects every supported audio file, sorted by path
+/// within each directory so that albums keep their on-disk order.
+pub fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else if is_supported(&path) {
+            files.push(path);
+        }
+    }
+}
+
+struct Shared {
+    files: Mutex<Vec<PathBuf>>,
+    results: SegQueue<(usize, TrackInfo)>,
+    total: AtomicUsize,
+    next_tag: AtomicUsize,
+    next_duration: AtomicUsize,
+    walked: AtomicBool,
+}
+
+/// A background import of files and folders.
+///
+/// The directory walk, tag reading and duration computation all happen on worker threads;
+/// the GTK thread only pulls finished tracks with `next_batch`, in their original order.
+pub struct Scan {
+    shared: Arc<Shared>,
+    pending: BTreeMap<usize, TrackInfo>,
+    next: usize,
+}
+
+impl Scan {
+    pub(crate) fn start(paths: Vec<PathBuf>, state: Arc<Mutex<State>>) -> Self {
+        let shared = Arc::new(Shared {
+            files: Mutex::new(Vec::new()),
+            results: SegQueue::new(),
+            total: AtomicUsize::new(0),
+            next_tag: AtomicUsize::new(0),
+            next_duration: AtomicUsize::new(0),
+            walked: AtomicBool::new(false),
+        });
+
+        {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let files = expand_paths(paths);
+                shared.total.store(files.len(), Ordering::SeqCst);
+                *shared.files.lock().unwrap() = files;
+                shared.walked.store(true, Ordering::SeqCst);
+
+                let workers = thread::available_parallelism()
+                    .map(|count| count.get())
+                    .unwrap_or(DEFAULT_WORKERS);
+                for _ in 0..workers {
+                    let shared = shared.clone();
+                    let state = state.clone();
+                    thread::spawn(move || scan_worker(&shared, &state));
+                }
+            });
+        }
+
+        Scan {
+            shared,
+            pending: BTreeMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns up to `max` tracks whose tags have been read, keeping the walk order.
+    pub fn next_batch(&mut self, max: usize) -> Vec<TrackInfo> {
+        while let Some((index, info)) = self.shared.results.try_pop() {
+            self.pending.insert(index, info);
+        }
+
+        let mut batch = Vec::new();
+        while batch.len() < max {
+            match self.pending.remove(&self.next) {
+                Some(info) => {
+                    batch.push(info);
+                    self.next += 1;
+                },
+                None => break,
+            }
+        }
+        batch
+    }
+
+    pub fn added(&self) -> usize {
+        self.next
+    }
+
+    pub fn total(&self) -> usize {
+        self.shared.total.load(Ordering::SeqCst)
+    }
+
+    pub fn is_walking(&self) -> bool {
+        !self.shared.walked.load(Ordering::SeqCst)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.is_walking() && self.next >= self.total()
+    }
+}
+
+/// Measures the audio properties of `path` again in the background, e.g. once it was
+/// rewritten, for `Playlist::refresh_properties` to pick up.
+pub(crate) fn measure_properties(path: PathBuf, state: Arc<Mutex<State>>) {
+    thread::spawn(move || {
+        if let Some(properties) = crate::player::compute_properties(&path) {
+            let path = path.to_string_lossy().to_string();
+            state.lock().unwrap().properties.insert(path, properties);
+        }
+    });
+}
+
+fn file_at(shared: &Shared, index: usize) -> Option<PathBuf> {
+    shared.files.lock().unwrap().get(index).cloned()
+}
+
+fn scan_worker(shared: &Shared, state: &Mutex<State>) {
+    // Tags first so that rows show up quickly, audio properties need a full pass over the file.
+    loop {
+        let index = shared.next_tag.fetch_add(1, Ordering::SeqCst);
+        match file_at(shared, index) {
+            Some(path) => shared.results.push((index, TrackInfo::read(&path))),
+            None => break,
+        }
+    }
+
+    loop {
+        let index = shared.next_duration.fetch_add(1, Ordering::SeqCst);
+        match file_at(shared, index) {
+            Some(path) => {
+                if let Some(properties) = crate::player::compute_properties(&path) {
+                    let path = path.to_string_lossy().to_string();
+                    state.lock().unwrap().properties.insert(path, properties);
+                }
+            },
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::library::Play;
+use crate::xdg;
+
+/// The listens waiting to be submitted, one ListenBrainz listen in JSON per line.
+const QUEUE_FILE: &str = "listens.jsonl";
+/// The listens in the Audioscrobbler portable player format, for Last.fm uploaders.
+const SCROBBLER_LOG_FILE: &str = ".scrobbler.log";
+const SCROBBLER_LOG_HEADER: &str = concat!("#AUDIOSCROBBLER/1.1\n#TZ/UTC\n#CLIENT/Rusic ", env!("CARGO_PKG_VERSION"), "\n");
+/// Tracks shorter than this are never scrobbled.
+const MIN_DURATION_MILLIS: u64 = 30_000;
+/// A listen this long counts whatever the duration of the track.
+const MIN_LISTENED_MILLIS: u64 = 4 * 60_000;
+/// How many listens go in a submission.
+const BATCH_SIZE: usize = 100;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Guards the queue file between the GTK thread appending to it and a submission.
+static QUEUE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Deserialize, Serialize)]
+struct Listen {
+    listened_at: i64,
+    track_metadata: TrackMetadata,
+}
+
+#[derive(Deserialize, Serialize)]
+struct TrackMetadata {
+    artist_name: String,
+    track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<String>,
+    additional_info: AdditionalInfo,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AdditionalInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracknumber: Option<u32>,
+    media_player: String,
+    submission_client: String,
+    submission_client_version: String,
+}
+
+#[derive(Serialize)]
+struct Submission<'a> {
+    listen_type: &'static str,
+    payload: &'a [Listen],
+}
+
+/// Whether a play counts as a listen by the scrobbling rules: half of the track, or four
+/// minutes, heard of a track of at least thirty seconds.
+pub fn qualifies(listened: u64, duration: Option<u64>) -> bool {
+    match duration {
+        Some(duration) => duration >= MIN_DURATION_MILLIS
+            && (listened * 2 >= duration || listened >= MIN_LISTENED_MILLIS),
+        None => listened >= MIN_LISTENED_MILLIS,
+    }
+}
+
+/// The tags of a played track, as the playlist already has them.
+pub struct ListenedTrack {
+    pub artist: String,
+    /// The title, or the file name without one.
+    pub title: String,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+}
+
+/// Logs the listens to local files, which stay usable offline, and submits the queued ones
+/// to a ListenBrainz compatible endpoint when one is set.
+pub struct Scrobbler {
+    endpoint: Option<String>,
+    log_path: PathBuf,
+    queue_path: PathBuf,
+    submitting: Arc<AtomicBool>,
+    token: Option<String>,
+}
+
+impl Scrobbler {
+    /// A scrobbler keeping its queue and log in the data directory.
+    pub fn new(endpoint: Option<String>, token: Option<String>) -> Self {
+        Self::with_files(endpoint, token, xdg::data_file(QUEUE_FILE), xdg::data_file(SCROBBLER_LOG_FILE))
+    }
+
+    pub fn with_files(endpoint: Option<String>, token: Option<String>, queue_path: PathBuf, log_path: PathBuf) -> Self {
+        Scrobbler {
+            endpoint,
+            log_path,
+            queue_path,
+            submitting: Arc::new(AtomicBool::new(false)),
+            token,
+        }
+    }
+
+    /// Logs `play` of `track` if it qualifies as a listen, then submits the queue.
+    pub fn log(&self, play: &Play, duration: Option<u64>, track: &ListenedTrack) {
+        if !qualifies(play.listened, duration) {
+            return;
+        }
+        let listen = Listen {
+            listened_at: play.played_at,
+            track_metadata: TrackMetadata {
+                artist_name: track.artist.clone(),
+                track_name: track.title.clone(),
+                release_name: track.album.clone(),
+                additional_info: AdditionalInfo {
+                    duration_ms: duration,
+                    tracknumber: track.track,
+                    media_player: "Rusic".to_string(),
+                    submission_client: "rusic".to_string(),
+                    submission_client_version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+            },
+        };
+        if let Err(error) = append_listen(&self.queue_path, &self.log_path, &listen) {
+            eprintln!("Cannot log the listen of {}: {}", play.path, error);
+        }
+        self.submit();
+    }
+
+    /// Sends the queued listens from a background thread, keeping those that fail to go
+    /// through for the next time.
+    pub fn submit(&self) {
+        let endpoint = match self.endpoint {
+            Some(ref endpoint) => endpoint.clone(),
+            None => return,
+        };
+        if self.submitting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let token = self.token.clone();
+        let queue_path = self.queue_path.clone();
+        let submitting = self.submitting.clone();
+        thread::spawn(move || {
+            if let Err(error) = submit_queue(&endpoint, token.as_deref(), &queue_path) {
+                eprintln!("Cannot submit the listens to {}: {}", endpoint, error);
+            }
+            submitting.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn append_listen(queue_path: &Path, log_path: &Path, listen: &Listen) -> Result<(), String> {
+    let _lock = QUEUE_LOCK.lock().unwrap();
+    let line = serde_json::to_string(listen).map_err(|error| error.to_string())?;
+    let mut queue = OpenOptions::new().create(true).append(true).open(queue_path)
+        .map_err(|error| error.to_string())?;
+    writeln!(queue, "{}", line).map_err(|error| error.to_string())?;
+
+    let is_new = !log_path.exists();
+    let mut log = OpenOptions::new().create(true).append(true).open(log_path)
+        .map_err(|error| error.to_string())?;
+    if is_new {
+        log.write_all(SCROBBLER_LOG_HEADER.as_bytes()).map_err(|error| error.to_string())?;
+    }
+    writeln!(log, "{}", scrobbler_log_line(listen)).map_err(|error| error.to_string())
+}
+
+/// The tab-separated fields of the portable player format: artist, album, title, track
+/// number, duration in seconds, rating (L for listened), timestamp and MusicBrainz id.
+fn scrobbler_log_line(listen: &Listen) -> String {
+    let metadata = &listen.track_metadata;
+    let field = |value: &str| value.replace(['\t', '\n'], " ");
+    format!("{}\t{}\t{}\t{}\t{}\tL\t{}\t",
+            field(&metadata.artist_name),
+            field(metadata.release_name.as_deref().unwrap_or_default()),
+            field(&metadata.track_name),
+            metadata.additional_info.tracknumber.map(|track| track.to_string()).unwrap_or_default(),
+            metadata.additional_info.duration_ms.map(|duration| (duration / 1000).to_string()).unwrap_or_default(),
+            listen.listened_at)
+}
+
+fn read_queue(queue_path: &Path) -> Vec<String> {
+    File::open(queue_path)
+        .map(|file| BufReader::new(file).lines().map_while(Result::ok).filter(|line| !line.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Submits the queue in batches, dropping each batch from it once accepted. Unreadable
+/// lines are dropped rather than submitted, a batch of only those without a submission.
+fn submit_queue(endpoint: &str, token: Option<&str>, queue_path: &Path) -> Result<(), String> {
+    loop {
+        let lines = {
+            let _lock = QUEUE_LOCK.lock().unwrap();
+            read_queue(queue_path)
+        };
+        let batch: Vec<_> = lines.iter().take(BATCH_SIZE).collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let listens: Vec<Listen> = batch.iter()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(listen) => Some(listen),
+                Err(error) => {
+                    eprintln!("Dropping the unreadable listen {:?}: {}", line, error);
+                    None
+                },
+            })
+            .collect();
+        if !listens.is_empty() {
+            let body = serde_json::to_string(&Submission {
+                listen_type: "import",
+                payload: &listens,
+            }).map_err(|error| error.to_string())?;
+            post_json(endpoint, token, &body)?;
+        }
+
+        // Listens are only ever appended meanwhile, so the batch is still at the start.
+        let _lock = QUEUE_LOCK.lock().unwrap();
+        let remaining: Vec<_> = read_queue(queue_path).into_iter().skip(batch.len()).collect();
+        let mut contents = remaining.join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        fs::write(queue_path, contents).map_err(|error| error.to_string())?;
+    }
+}
+
+/// Posts `body` to an `http://` URL, succeeding on a 2xx status. TLS is not supported, an
+/// HTTPS service is reached through a local proxy.
+fn post_json(url: &str, token: Option<&str>, body: &str) -> Result<(), String> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| format!("unsupported URL {}, only http:// is", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let address = address.to_socket_addrs().map_err(|error| error.to_string())?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", authority))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|error| error.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                               Content-Length: {}\r\nConnection: close\r\n",
+                              path, authority, body.len());
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Token {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).map_err(|error| error.to_string())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|error| error.to_string())?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("the server answered {:?}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusic-scrobble-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Answers one request with `status`, returning the request received.
+    fn mock_server(status: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/1/submit-listens", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            request
+        });
+        (endpoint, server)
+    }
+
+    fn log_listen(dir: &Path) -> PathBuf {
+        let queue_path = dir.join(QUEUE_FILE);
+        let scrobbler = Scrobbler::with_files(None, None, queue_path.clone(), dir.join(SCROBBLER_LOG_FILE));
+        let play = Play {
+            path: "/music/song.mp3".to_string(),
+            played_at: 1_700_000_000,
+            listened: 200_000,
+            completed: true,
+            skipped: false,
+        };
+        let track = ListenedTrack {
+            artist: "Artist".to_string(),
+            title: "Title".to_string(),
+            album: Some("Album".to_string()),
+            track: Some(3),
+        };
+        scrobbler.log(&play, Some(240_000), &track);
+        queue_path
+    }
+
+    #[test]
+    fn submits_and_drains_the_queue() {
+        let dir = temp_dir("submit");
+        let queue_path = log_listen(&dir);
+        let (endpoint, server) = mock_server("200 OK");
+
+        submit_queue(&endpoint, Some("secret"), &queue_path).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /1/submit-listens HTTP/1.1\r\n"));
+        assert!(request.contains("Authorization: Token secret\r\n"));
+        assert!(request.contains("\"listen_type\":\"import\""));
+        assert!(request.contains("\"track_name\":\"Title\""));
+        assert!(request.contains("\"listened_at\":1700000000"));
+        assert!(read_queue(&queue_path).is_empty());
+
+        let log = fs::read_to_string(dir.join(SCROBBLER_LOG_FILE)).unwrap();
+        assert!(log.starts_with(SCROBBLER_LOG_HEADER));
+        assert!(log.ends_with("Artist\tAlbum\tTitle\t3\t240\tL\t1700000000\t\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_the_queue_when_refused() {
+        let dir = temp_dir("refused");
+        let queue_path = log_listen(&dir);
+        let (endpoint, server) = mock_server("401 Unauthorized");
+
+        assert!(submit_queue(&endpoint, None, &queue_path).is_err());
+        server.join().unwrap();
+        assert_eq!(read_queue(&queue_path).len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn drops_unreadable_lines() {
+        let dir = temp_dir("unreadable");
+        let queue_path = log_listen(&dir);
+        let mut queue = OpenOptions::new().append(true).open(&queue_path).unwrap();
+        writeln!(queue, "{{\"listened_at\":").unwrap();
+        let (endpoint, server) = mock_server("200 OK");
+
+        submit_queue(&endpoint, None, &queue_path).unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request.matches("\"track_name\"").count(), 1);
+        assert!(read_queue(&queue_path).is_empty());
+
+        // A batch of only unreadable lines is dropped without anything to submit.
+        fs::write(&queue_path, "not a listen\n").unwrap();
+        submit_queue("http://127.0.0.1:1/", None, &queue_path).unwrap();
+        assert!(read_queue(&queue_path).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Path,
+    Year,
+    Track,
+}
+
+/// The fields matched by a term without a prefix.
+const TEXT_FIELDS: &[Field] = &[Field::Title, Field::Artist, Field::Album, Field::Genre, Field::Path];
+
+enum Condition {
+    Contains(String),
+    Compare(Ordering, bool, i64),
+}
+
+struct Term {
+    fields: Vec<Field>,
+    condition: Condition,
+}
+
+/// A playlist search: whitespace separated terms that must all match.
+///
+/// A term is either free text, matched against title, artist, album, genre and path,
+/// or `field:value`, e.g. `artist:davis`, `"album:kind of blue"` or `year:>2000`.
+/// Numeric fields (`year`, `track`) accept `<`, `<=`, `>`, `>=` and `=`.
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Self {
+        let terms = split_terms(text).iter()
+            .filter_map(|term| parse_term(term))
+            .collect();
+        Query {
+            terms,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Checks a track, whose fields are read through `value`, against every term.
+    pub fn matches<F: Fn(Field) -> Option<String>>(&self, value: F) -> bool {
+        self.terms.iter().all(|term| {
+            term.fields.iter().any(|&field| {
+                let value = match value(field) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                match term.condition {
+                    Condition::Contains(ref text) => value.to_lowercase().contains(text),
+                    Condition::Compare(ordering, or_equal, number) => {
+                        match leading_number(&value) {
+                            Some(value) => {
+                                let result = value.cmp(&number);
+                                result == ordering || (or_equal && result == Ordering::Equal)
+                            },
+                            None => false,
+                        }
+                    },
+                }
+            })
+        })
+    }
+}
+
+/// Splits on whitespace, keeping double-quoted parts together.
+fn split_terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for character in text.chars() {
+        match character {
+            '"' => quoted = !quoted,
+            character if character.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    terms.push(current.clone());
+                    current.clear();
+                }
+            },
+            character => current.push(character),
+        }
+    }
+    if !current.is_empty() {
+        terms.push(current);
+    }
+    terms
+}
+
+fn parse_field(name: &str) -> Option<Field> {
+    let field = match name.to_lowercase().as_str() {
+        "title" => Field::Title,
+        "artist" => Field::Artist,
+        "album" => Field::Album,
+        "genre" => Field::Genre,
+        "path" => Field::Path,
+        "year" => Field::Year,
+        "track" => Field::Track,
+        _ => return None,
+    };
+    Some(field)
+}
+
+fn parse_term(term: &str) -> Option<Term> {
+    let (fields, value) = match term.find(':') {
+        Some(index) => match parse_field(&term[..index]) {
+            Some(field) => (vec![field], &term[index + 1..]),
+            // Not a known field, e.g. a time like 12:30, so match the whole term as text.
+            None => (TEXT_FIELDS.to_vec(), term),
+        },
+        None => (TEXT_FIELDS.to_vec(), term),
+    };
+    if value.is_empty() {
+        return None;
+    }
+
+    let numeric = fields.iter().all(|&field| field == Field::Year || field == Field::Track);
+    let condition = if numeric {
+        parse_comparison(value)?
+    } else {
+        Condition::Contains(value.to_lowercase())
+    };
+    Some(Term {
+        fields,
+        condition,
+    })
+}
+
+fn parse_comparison(value: &str) -> Option<Condition> {
+    let (ordering, or_equal, number) = if let Some(number) = value.strip_prefix(">=") {
+        (Ordering::Greater, true, number)
+    } else if let Some(number) = value.strip_prefix("<=") {
+        (Ordering::Less, true, number)
+    } else if let Some(number) = value.strip_prefix('>') {
+        (Ordering::Greater, false, number)
+    } else if let Some(number) = value.strip_prefix('<') {
+        (Ordering::Less, false, number)
+    } else {
+        (Ordering::Equal, true, value.strip_prefix('=').unwrap_or(value))
+    };
+    Some(Condition::Compare(ordering, or_equal, number.trim().parse().ok()?))
+}
+
+/// Reads the number at the start of `value`, so that "3 / 12" gives 3.
+fn leading_number(value: &str) -> Option<i64> {
+    let digits: String = value.trim().chars().take_while(|character| character.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
@@ -0,0 +1,68 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::xdg;
+
+const SESSION_FILE: &str = "session.json";
+
+/// A playlist tab.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SavedPlaylist {
+    pub name: String,
+    /// The files of the playlist, in order.
+    pub tracks: Vec<String>,
+    pub selected: Vec<String>,
+}
+
+/// What the next launch restores, kept as JSON in `$XDG_STATE_HOME/rusic/session.json`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Session {
+    pub playlists: Vec<SavedPlaylist>,
+    /// The index of the shown playlist.
+    pub current_tab: usize,
+    /// The index of the playlist `current` belongs to.
+    pub playing_tab: Option<usize>,
+    /// The track playing or paused, if any.
+    pub current: Option<String>,
+    /// The position in the current track, in milliseconds.
+    pub position: u64,
+    pub repeat: bool,
+    pub shuffle: bool,
+    /// The playback speed, normal when unset.
+    pub speed: Option<f64>,
+    /// The playback volume, from 0 to 1, full when unset.
+    pub volume: Option<f64>,
+}
+
+impl Session {
+    pub fn load() -> Self {
+        File::open(xdg::state_file(SESSION_FILE)).ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the session to a temporary file first, renamed over the previous session once
+    /// complete, so that a crash or a full disk midway leaves the previous one intact.
+    pub fn save(&self) {
+        let path = xdg::state_file(SESSION_FILE);
+        let temporary_path = path.with_extension("json.tmp");
+        let written = File::create(&temporary_path).ok()
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer(&mut writer, self).ok()?;
+                writer.into_inner().ok()?.sync_all().ok()
+            });
+        match written {
+            Some(()) => {
+                let _ = fs::rename(&temporary_path, &path);
+            },
+            None => {
+                let _ = fs::remove_file(&temporary_path);
+            },
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use gio::{ActionMapExt, SimpleAction, SimpleActionExt};
+
+use gtk::{
+    Application,
+    ApplicationWindow,
+    Builder,
+    GtkApplicationExt,
+    GtkWindowExt,
+    WidgetExt,
+    Window,
+};
+
+/// An application action and the keys triggering it.
+pub struct Shortcut {
+    /// The name of the action, also the key of its accelerators in the config.
+    pub action: &'static str,
+    pub title: &'static str,
+    /// The accelerators used unless the config remaps the action, in the syntax of
+    /// `gtk_accelerator_parse`, e.g. `<Primary>o`.
+    pub accels: &'static [&'static str],
+}
+
+const fn shortcut(action: &'static str, title: &'static str, accels: &'static [&'static str]) -> Shortcut {
+    Shortcut { action, title, accels }
+}
+
+/// The shortcuts by group, in the order of the shortcuts window.
+pub const SHORTCUT_GROUPS: &[(&str, &[Shortcut])] = &[
+    ("Playback", &[
+        shortcut("play-pause", "Play or pause", &["space"]),
+        shortcut("seek-backward", "Seek backward", &["Left"]),
+        shortcut("seek-forward", "Seek forward", &["Right"]),
+        shortcut("volume-up", "Volume up", &["plus", "KP_Add"]),
+        shortcut("volume-down", "Volume down", &["minus", "KP_Subtract"]),
+    ]),
+    ("Playlist", &[
+        shortcut("open", "Open files", &["<Primary>o"]),
+        shortcut("save", "Save the playlist", &["<Primary>s"]),
+        shortcut("remove", "Remove the selection", &["Delete"]),
+        shortcut("undo", "Undo", &["<Primary>z"]),
+        shortcut("redo", "Redo", &["<Primary><Shift>z"]),
+        shortcut("search", "Search", &["<Primary>f"]),
+    ]),
+    ("General", &[
+        shortcut("shortcuts", "Keyboard shortcuts", &["<Primary>question", "F1"]),
+        shortcut("quit", "Quit", &["<Primary>q"]),
+    ]),
+];
+
+/// Adds the action `name` to the application, calling `activate` when triggered.
+pub fn add_action<F: Fn() + 'static>(application: &Application, name: &str, activate: F) {
+    let action = SimpleAction::new(name, None);
+    action.connect_activate(move |_, _| activate());
+    application.add_action(&action);
+}
+
+/// Binds the accelerators of every shortcut: those `remapped` in the config, an empty list
+/// leaving the action without any, else the defaults. Unparsable accelerators are skipped.
+pub fn set_accels(application: &Application, remapped: &BTreeMap<String, Vec<String>>) {
+    for (_, shortcuts) in SHORTCUT_GROUPS {
+        for shortcut in shortcuts.iter() {
+            let accels: Vec<&str> = match remapped.get(shortcut.action) {
+                Some(accels) => accels.iter().map(String::as_str).collect(),
+                None => shortcut.accels.to_vec(),
+            };
+            let accels: Vec<&str> = accels.into_iter()
+                .filter(|accel| {
+                    let valid = gtk::accelerator_parse(accel).0 != 0;
+                    if !valid {
+                        eprintln!("Invalid accelerator {:?} for {}", accel, shortcut.action);
+                    }
+                    valid
+                })
+                .collect();
+            application.set_accels_for_action(&format!("app.{}", shortcut.action), &accels);
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Lists the shortcuts with their current accelerators in a shortcuts window.
+pub fn show_shortcuts_window(parent: &ApplicationWindow) {
+    let application = match parent.get_application() {
+        Some(application) => application,
+        None => return,
+    };
+    // The shortcut widgets have no bindings, the window is built from its UI definition.
+    let mut groups = String::new();
+    for (title, shortcuts) in SHORTCUT_GROUPS {
+        let mut items = String::new();
+        for shortcut in shortcuts.iter() {
+            let accels = application.get_accels_for_action(&format!("app.{}", shortcut.action));
+            if accels.is_empty() {
+                continue;
+            }
+            items.push_str(&format!(
+                "<child><object class=\"GtkShortcutsShortcut\"><property name=\"visible\">1</property>\
+                 <property name=\"accelerator\">{}</property><property name=\"title\">{}</property>\
+                 </object></child>",
+                escape(&accels.join(" ")), escape(shortcut.title)));
+        }
+        groups.push_str(&format!(
+            "<child><object class=\"GtkShortcutsGroup\"><property name=\"visible\">1</property>\
+             <property name=\"title\">{}</property>{}</object></child>",
+            escape(title), items));
+    }
+    let definition = format!(
+        "<interface><object class=\"GtkShortcutsWindow\" id=\"shortcuts\"><property name=\"modal\">1</property>\
+         <child><object class=\"GtkShortcutsSection\"><property name=\"visible\">1</property>\
+         <property name=\"section-name\">shortcuts</property>{}</object></child></object></interface>",
+        groups);
+
+    if let Some(window) = Builder::new_from_string(&definition).get_object::<Window>("shortcuts") {
+        window.set_transient_for(Some(parent));
+        window.show_all();
+    }
+}
@@ -0,0 +1,362 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use gtk::{
+    ApplicationWindow,
+    Button,
+    ButtonExt,
+    ComboBoxExt,
+    ComboBoxText,
+    ComboBoxTextExt,
+    ContainerExt,
+    Dialog,
+    DialogExt,
+    DialogFlags,
+    Entry,
+    EntryExt,
+    Label,
+    SpinButton,
+    SpinButtonExt,
+    WidgetExt,
+};
+
+use gtk::Orientation::{Horizontal, Vertical};
+
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use rusqlite::types::Value;
+
+use serde::{Deserialize, Serialize};
+
+use crate::library::Library;
+use crate::toolbar::show_error_dialog;
+
+const MAX_LIMIT: f64 = 100_000.0;
+const ICON_SIZE_BUTTON: i32 = 4;
+const MATCH_CHOICES: &[(bool, &str)] = &[(true, "all"), (false, "any")];
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    Track,
+    Rating,
+}
+
+const RULE_FIELDS: &[(RuleField, &str)] = &[
+    (RuleField::Title, "Title"),
+    (RuleField::Artist, "Artist"),
+    (RuleField::Album, "Album"),
+    (RuleField::Genre, "Genre"),
+    (RuleField::Year, "Year"),
+    (RuleField::Track, "Track number"),
+    (RuleField::Rating, "Rating"),
+];
+
+impl RuleField {
+    fn column(self) -> &'static str {
+        match self {
+            RuleField::Title => "title",
+            RuleField::Artist => "artist",
+            RuleField::Album => "album",
+            RuleField::Genre => "genre",
+            RuleField::Year => "year",
+            RuleField::Track => "track",
+            // Unrated tracks compare as rated 0.
+            RuleField::Rating => "IFNULL(rating, 0)",
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        self == RuleField::Year || self == RuleField::Track || self == RuleField::Rating
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    Is,
+    IsNot,
+    Contains,
+    NotContains,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+const OPERATORS: &[(Operator, &str)] = &[
+    (Operator::Is, "is"),
+    (Operator::IsNot, "is not"),
+    (Operator::Contains, "contains"),
+    (Operator::NotContains, "does not contain"),
+    (Operator::Less, "<"),
+    (Operator::LessOrEqual, "≤"),
+    (Operator::Greater, ">"),
+    (Operator::GreaterOrEqual, "≥"),
+];
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Rule {
+    pub field: RuleField,
+    pub operator: Operator,
+    pub value: String,
+}
+
+impl Rule {
+    /// The SQL condition on the library's tracks and its parameter,
+    /// `None` when a numeric field is not given a number.
+    fn condition(&self) -> Option<(String, Value)> {
+        let column = self.field.column();
+        let value = if self.field.is_numeric() {
+            Value::Integer(self.value.trim().parse().ok()?)
+        } else {
+            Value::Text(self.value.clone())
+        };
+        let condition = match self.operator {
+            Operator::Is => format!("{} = ? COLLATE NOCASE", column),
+            Operator::IsNot => format!("{} <> ? COLLATE NOCASE", column),
+            Operator::Contains => format!("instr(lower({}), lower(?)) > 0", column),
+            Operator::NotContains => format!("instr(lower({}), lower(?)) = 0", column),
+            Operator::Less => format!("{} < ?", column),
+            Operator::LessOrEqual => format!("{} <= ?", column),
+            Operator::Greater => format!("{} > ?", column),
+            Operator::GreaterOrEqual => format!("{} >= ?", column),
+        };
+        Some((condition, value))
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Random,
+    Title,
+    Artist,
+    Album,
+    Year,
+    Rating,
+}
+
+const ORDERS: &[(Order, &str)] = &[
+    (Order::Random, "Random"),
+    (Order::Title, "Title"),
+    (Order::Artist, "Artist"),
+    (Order::Album, "Album"),
+    (Order::Year, "Year"),
+    (Order::Rating, "Rating"),
+];
+
+impl Order {
+    fn order_by(self) -> &'static str {
+        match self {
+            Order::Random | Order::Title => "title COLLATE NOCASE, path",
+            Order::Artist => "artist COLLATE NOCASE, album COLLATE NOCASE, track, path",
+            Order::Album => "album COLLATE NOCASE, track, path",
+            Order::Year => "year, artist COLLATE NOCASE, album COLLATE NOCASE, track, path",
+            Order::Rating => "IFNULL(rating, 0) DESC, artist COLLATE NOCASE, album COLLATE NOCASE, track, path",
+        }
+    }
+}
+
+/// A saved playlist defined by rules on the library, e.g. genre is Jazz and year < 1970.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SmartPlaylist {
+    pub name: String,
+    /// Whether a track must match every rule, or any of them.
+    pub match_all: bool,
+    pub rules: Vec<Rule>,
+    /// The maximum number of tracks, 0 for no limit.
+    pub limit: u32,
+    pub order: Order,
+}
+
+impl SmartPlaylist {
+    /// Lists the matching tracks. `seed` drives the random order, so that evaluating again
+    /// with the same seed keeps the tracks already picked. A rule that is not valid, rather
+    /// than being ignored, matches nothing.
+    pub fn evaluate(&self, library: &Library, seed: u64) -> Vec<PathBuf> {
+        let conditions: Option<Vec<_>> = self.rules.iter().map(Rule::condition).collect();
+        let (conditions, params): (Vec<_>, Vec<_>) = match conditions {
+            Some(conditions) => conditions.into_iter().unzip(),
+            None => return Vec::new(),
+        };
+        let clause = if conditions.is_empty() {
+            "1".to_string()
+        } else {
+            conditions.join(if self.match_all { " AND " } else { " OR " })
+        };
+
+        let random = self.order == Order::Random;
+        let limit = if random || self.limit == 0 { None } else { Some(self.limit) };
+        let mut paths = library.select_paths(&clause, params, self.order.order_by(), limit);
+        if random {
+            paths.sort_by_key(|path| {
+                let mut hasher = DefaultHasher::new();
+                seed.hash(&mut hasher);
+                path.hash(&mut hasher);
+                hasher.finish()
+            });
+            if self.limit > 0 {
+                paths.truncate(self.limit as usize);
+            }
+        }
+        paths.into_iter().map(PathBuf::from).collect()
+    }
+}
+
+struct RuleRow {
+    container: gtk::Box,
+    field: ComboBoxText,
+    operator: ComboBoxText,
+    value: Entry,
+}
+
+fn combo<T: PartialEq>(choices: &[(T, &str)], active: &T) -> ComboBoxText {
+    let combo = ComboBoxText::new();
+    for (_, label) in choices {
+        combo.append_text(label);
+    }
+    let index = choices.iter().position(|(choice, _)| choice == active).unwrap_or(0);
+    combo.set_active(index as i32);
+    combo
+}
+
+fn active<T: Copy>(combo: &ComboBoxText, choices: &[(T, &str)]) -> T {
+    let index = combo.get_active().max(0) as usize;
+    choices.get(index).unwrap_or(&choices[0]).0
+}
+
+fn add_rule_row(rules_box: &gtk::Box, rows: &Rc<RefCell<Vec<RuleRow>>>, rule: &Rule) {
+    let container = gtk::Box::new(Horizontal, 5);
+    let field = combo(RULE_FIELDS, &rule.field);
+    let operator = combo(OPERATORS, &rule.operator);
+    let value = Entry::new();
+    value.set_text(&rule.value);
+    value.set_hexpand(true);
+    let remove_button = Button::new_from_icon_name("list-remove", ICON_SIZE_BUTTON);
+    container.add(&field);
+    container.add(&operator);
+    container.add(&value);
+    container.add(&remove_button);
+    rules_box.add(&container);
+    container.show_all();
+
+    {
+        let rows = rows.clone();
+        let rules_box = rules_box.clone();
+        let container = container.clone();
+        remove_button.connect_clicked(move |_| {
+            rows.borrow_mut().retain(|row| row.container != container);
+            rules_box.remove(&container);
+        });
+    }
+    rows.borrow_mut().push(RuleRow {
+        container,
+        field,
+        operator,
+        value,
+    });
+}
+
+/// Edits `smart`, or a new smart playlist, and returns the result unless cancelled.
+pub fn show_smart_playlist_dialog(parent: &ApplicationWindow, smart: Option<&SmartPlaylist>) -> Option<SmartPlaylist> {
+    let smart = smart.cloned().unwrap_or_else(|| SmartPlaylist {
+        name: String::new(),
+        match_all: true,
+        rules: vec![Rule {
+            field: RuleField::Genre,
+            operator: Operator::Is,
+            value: String::new(),
+        }],
+        limit: 0,
+        order: Order::Artist,
+    });
+
+    let dialog = Dialog::new_with_buttons(Some("Smart playlist"), Some(parent), DialogFlags::MODAL,
+                                          &[("Cancel", GTK_RESPONSE_CANCEL), ("Save", GTK_RESPONSE_ACCEPT)]);
+    let content = gtk::Box::new(Vertical, 5);
+    content.set_property_margin(10);
+    dialog.get_content_area().add(&content);
+
+    let name_entry = Entry::new();
+    name_entry.set_placeholder_text("Name");
+    name_entry.set_text(&smart.name);
+    content.add(&name_entry);
+
+    let match_box = gtk::Box::new(Horizontal, 5);
+    let match_combo = combo(MATCH_CHOICES, &smart.match_all);
+    match_box.add(&Label::new("Match"));
+    match_box.add(&match_combo);
+    match_box.add(&Label::new("of the following rules:"));
+    content.add(&match_box);
+
+    let rules_box = gtk::Box::new(Vertical, 5);
+    content.add(&rules_box);
+    let rows = Rc::new(RefCell::new(Vec::new()));
+    for rule in &smart.rules {
+        add_rule_row(&rules_box, &rows, rule);
+    }
+
+    let add_rule_button = Button::new_with_label("Add rule");
+    add_rule_button.set_halign(gtk::Align::Start);
+    {
+        let rows = rows.clone();
+        let rules_box = rules_box.clone();
+        add_rule_button.connect_clicked(move |_| {
+            let rule = Rule {
+                field: RuleField::Artist,
+                operator: Operator::Contains,
+                value: String::new(),
+            };
+            add_rule_row(&rules_box, &rows, &rule);
+        });
+    }
+    content.add(&add_rule_button);
+
+    let limit_box = gtk::Box::new(Horizontal, 5);
+    let limit_button = SpinButton::new_with_range(0.0, MAX_LIMIT, 1.0);
+    limit_button.set_value(f64::from(smart.limit));
+    let order_combo = combo(ORDERS, &smart.order);
+    limit_box.add(&Label::new("Limit to"));
+    limit_box.add(&limit_button);
+    limit_box.add(&Label::new("tracks (0 for all), sorted by"));
+    limit_box.add(&order_combo);
+    content.add(&limit_box);
+
+    dialog.show_all();
+    let mut result = None;
+    while dialog.run() == GTK_RESPONSE_ACCEPT {
+        let name = name_entry.get_text().unwrap_or_default();
+        let rules: Vec<_> = rows.borrow().iter()
+            .map(|row| Rule {
+                field: active(&row.field, RULE_FIELDS),
+                operator: active(&row.operator, OPERATORS),
+                value: row.value.get_text().unwrap_or_default(),
+            })
+            .collect();
+        if let Some(rule) = rules.iter().find(|rule| rule.condition().is_none()) {
+            let field = RULE_FIELDS.iter().find(|(field, _)| *field == rule.field).map(|(_, label)| *label).unwrap_or_default();
+            show_error_dialog(parent, &format!("The {} of a rule must be a number, not \"{}\".", field.to_lowercase(), rule.value));
+            continue;
+        }
+        result = Some(SmartPlaylist {
+            name: if name.trim().is_empty() { "Smart playlist".to_string() } else { name },
+            match_all: active(&match_combo, MATCH_CHOICES),
+            rules,
+            limit: limit_button.get_value_as_int().max(0) as u32,
+            order: active(&order_combo, ORDERS),
+        });
+        break;
+    }
+
+    dialog.destroy();
+    result
+}
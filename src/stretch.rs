@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 3.0;
+const WINDOW_MILLIS: u32 = 30;
+/// How far from its nominal place a window may be taken to continue the previous one.
+const SEEK_MILLIS: u32 = 10;
+/// Correlations only look at every other frame and offset, which is plenty for speech and music.
+const SEARCH_STEP: usize = 2;
+
+/// A stereo frame.
+type Frame = [f32; 2];
+
+/// Changes the playback speed while keeping the pitch, by WSOLA (waveform similarity
+/// overlap-add): Hann windows of the input are taken at the pace of the speed and
+/// overlapped at a fixed pace, each one shifted to best continue the previous one.
+pub struct TimeStretch {
+    /// The frames read but not yet played over, from the start of the search range.
+    input: Vec<Frame>,
+    output: VecDeque<[i16; 2]>,
+    /// The synthesis hop, half the window.
+    hop: usize,
+    /// Where the next window would ideally start in `input`, advancing by `hop * speed`.
+    position: f64,
+    /// Where the frames following the previous window start in `input`.
+    continuation: Option<usize>,
+    sample_rate: u32,
+    seek: usize,
+    speed: f64,
+    /// The second half of the previous window, waiting for the next one to overlap it.
+    tail: Vec<Frame>,
+    window: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(sample_rate: u32, speed: f64) -> Self {
+        let hop = (sample_rate * WINDOW_MILLIS / 2000) as usize;
+        // A periodic Hann window, so that windows overlapped by half add up to one.
+        let window = (0..2 * hop)
+            .map(|index| 0.5 - 0.5 * (2.0 * PI * index as f32 / (2 * hop) as f32).cos())
+            .collect();
+        TimeStretch {
+            input: Vec::new(),
+            output: VecDeque::new(),
+            hop,
+            position: 0.0,
+            continuation: None,
+            sample_rate,
+            seek: (sample_rate * SEEK_MILLIS / 1000) as usize,
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            tail: vec![[0.0; 2]; hop],
+            window,
+        }
+    }
+
+    /// Whether the speed is the normal one, which plays the source as is.
+    pub fn is_bypassed(&self) -> bool {
+        self.speed == 1.0
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        // The buffered frames were timed for the old speed, and a bypass would never play
+        // them, leaving `latency` counting them forever.
+        if speed != self.speed {
+            self.speed = speed;
+            self.reset();
+        }
+    }
+
+    /// Drops the buffered frames, e.g. when the source moves.
+    pub fn reset(&mut self) {
+        *self = TimeStretch::new(self.sample_rate, self.speed);
+    }
+
+    /// The media time read from the source but not heard yet, in milliseconds.
+    pub fn latency(&self) -> u64 {
+        if self.is_bypassed() {
+            return 0;
+        }
+        let frames = (self.input.len() as f64 - self.position).max(0.0)
+            + self.output.len() as f64 * self.speed;
+        (frames * 1000.0 / f64::from(self.sample_rate)) as u64
+    }
+
+    /// Fills `buffer` with stretched frames from the interleaved stereo `source` and
+    /// returns how many, fewer only once the source is exhausted.
+    pub fn process<I: Iterator<Item = i16>>(&mut self, source: &mut I, buffer: &mut [[i16; 2]]) -> usize {
+        while self.output.len() < buffer.len() {
+            if !self.step() && !self.read(source) {
+                break;
+            }
+        }
+        let size = self.output.len().min(buffer.len());
+        for (frame, output) in buffer.iter_mut().zip(self.output.drain(..size)) {
+            *frame = output;
+        }
+        size
+    }
+
+    /// Reads a hop of frames, returning whether there were any.
+    fn read<I: Iterator<Item = i16>>(&mut self, source: &mut I) -> bool {
+        let mut read = false;
+        for _ in 0..self.hop {
+            match (source.next(), source.next()) {
+                (Some(left), Some(right)) => {
+                    self.input.push([f32::from(left), f32::from(right)]);
+                    read = true;
+                },
+                _ => break,
+            }
+        }
+        read
+    }
+
+    /// Overlaps the next window, returning false when more input is needed for it.
+    fn step(&mut self) -> bool {
+        let nominal = self.position as usize;
+        let first = nominal.saturating_sub(self.seek);
+        let last = nominal + self.seek;
+        if self.input.len() < last + 2 * self.hop {
+            return false;
+        }
+
+        let start = match self.continuation {
+            Some(continuation) => self.best_start(continuation, first, last),
+            None => nominal,
+        };
+        for index in 0..self.hop {
+            let head = &self.input[start + index];
+            let weight = self.window[index];
+            let frame = [self.tail[index][0] + head[0] * weight, self.tail[index][1] + head[1] * weight];
+            self.output.push_back([to_sample(frame[0]), to_sample(frame[1])]);
+
+            let tail = &self.input[start + self.hop + index];
+            let weight = self.window[self.hop + index];
+            self.tail[index] = [tail[0] * weight, tail[1] * weight];
+        }
+        self.position += self.hop as f64 * self.speed;
+
+        // Neither the next search range nor the continuation of this window needs more.
+        let continuation = start + self.hop;
+        let consumed = (self.position as usize).saturating_sub(self.seek).min(continuation);
+        self.input.drain(..consumed);
+        self.position -= consumed as f64;
+        self.continuation = Some(continuation - consumed);
+        true
+    }
+
+    /// The start within `first..=last` whose frames best match the ones at `continuation`.
+    fn best_start(&self, continuation: usize, first: usize, last: usize) -> usize {
+        let mono = |frame: &Frame| frame[0] + frame[1];
+        let mut best = (f32::MIN, first);
+        for start in (first..=last).step_by(SEARCH_STEP) {
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            for index in (0..self.hop).step_by(SEARCH_STEP) {
+                let candidate = mono(&self.input[start + index]);
+                correlation += candidate * mono(&self.input[continuation + index]);
+                energy += candidate * candidate;
+            }
+            let score = correlation / (energy.sqrt() + 1.0);
+            if score > best.0 {
+                best = (score, start);
+            }
+        }
+        best.1
+    }
+}
+
+fn to_sample(value: f32) -> i16 {
+    value.round().clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+}
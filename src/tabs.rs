@@ -0,0 +1,334 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+
+use gtk::{
+    ApplicationWindow,
+    Button,
+    ButtonExt,
+    ContainerExt,
+    Dialog,
+    DialogExt,
+    DialogFlags,
+    Entry,
+    EntryExt,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    Menu,
+    MenuExtManual,
+    MenuItem,
+    MenuItemExt,
+    MenuShellExt,
+    Notebook,
+    NotebookExt,
+    NotebookExtManual,
+    PackType,
+    WidgetExt,
+};
+
+use gtk::Orientation::Vertical;
+
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use crate::config::Config;
+use crate::player::{Player, State};
+use crate::playlist::Playlist;
+use crate::queue::Queue;
+
+const DEFAULT_NAME: &str = "Playlist";
+const PLAYING_PREFIX: &str = "▶ ";
+
+type AddedCallback = Box<dyn Fn(&Rc<Playlist>)>;
+
+struct Tab {
+    label: Label,
+    name: RefCell<String>,
+    playlist: Rc<Playlist>,
+}
+
+/// The open playlists, as notebook tabs sharing the player. The playing playlist, the one
+/// `next` and `previous` go through, may be another than the shown one.
+///
+/// Right-clicking a tab renames, duplicates or closes it; there is always at least one.
+pub struct PlaylistTabs {
+    added: RefCell<Vec<AddedCallback>>,
+    config: Rc<RefCell<Config>>,
+    menu: Menu,
+    /// The playlist whose tab was right-clicked.
+    menu_playlist: RefCell<Weak<Playlist>>,
+    notebook: Notebook,
+    player: Rc<Player>,
+    playing: RefCell<Weak<Playlist>>,
+    queue: Rc<Queue>,
+    state: Arc<Mutex<State>>,
+    tabs: RefCell<Vec<Tab>>,
+    window: ApplicationWindow,
+}
+
+impl PlaylistTabs {
+    pub(crate) fn new(window: &ApplicationWindow, state: Arc<Mutex<State>>, queue: Rc<Queue>,
+                      config: Rc<RefCell<Config>>) -> Rc<Self> {
+        let notebook = Notebook::new();
+        notebook.set_scrollable(true);
+        notebook.set_hexpand(true);
+
+        let menu = Menu::new();
+        let rename_item = MenuItem::new_with_mnemonic("_Rename…");
+        menu.append(&rename_item);
+        let duplicate_item = MenuItem::new_with_mnemonic("_Duplicate");
+        menu.append(&duplicate_item);
+        let close_item = MenuItem::new_with_mnemonic("_Close");
+        menu.append(&close_item);
+        menu.show_all();
+
+        let tabs = Rc::new(PlaylistTabs {
+            added: RefCell::new(Vec::new()),
+            config,
+            menu,
+            menu_playlist: RefCell::new(Weak::new()),
+            notebook,
+            player: Rc::new(Player::new(state.clone())),
+            playing: RefCell::new(Weak::new()),
+            queue,
+            state,
+            tabs: RefCell::new(Vec::new()),
+            window: window.clone(),
+        });
+
+        let new_button = Button::new_from_icon_name("list-add", 1);
+        new_button.set_tooltip_text("New playlist");
+        new_button.set_relief(gtk::ReliefStyle::None);
+        new_button.show();
+        tabs.notebook.set_action_widget(&new_button, PackType::End);
+        {
+            let tabs = Rc::downgrade(&tabs);
+            new_button.connect_clicked(move |_| {
+                if let Some(tabs) = tabs.upgrade() {
+                    tabs.add(DEFAULT_NAME);
+                    tabs.notebook.set_current_page(Some(tabs.len() as u32 - 1));
+                }
+            });
+        }
+        {
+            let weak = Rc::downgrade(&tabs);
+            tabs.notebook.connect_switch_page(move |_, _, index| {
+                if let Some(tabs) = weak.upgrade() {
+                    if let Some(tab) = tabs.tabs.borrow().get(index as usize) {
+                        tab.playlist.invalidate_summary();
+                    }
+                }
+            });
+        }
+
+        let weak = Rc::downgrade(&tabs);
+        rename_item.connect_activate(move |_| {
+            if let Some((tabs, playlist)) = menu_target(&weak) {
+                let old = tabs.name_of(&playlist).unwrap_or_default();
+                if let Some(name) = show_rename_dialog(&tabs.window, &old) {
+                    tabs.rename(&playlist, &name);
+                }
+            }
+        });
+
+        let weak = Rc::downgrade(&tabs);
+        duplicate_item.connect_activate(move |_| {
+            if let Some((tabs, playlist)) = menu_target(&weak) {
+                let name = format!("{} (copy)", tabs.name_of(&playlist).unwrap_or_default());
+                let copy = tabs.add(&name);
+                copy.copy_rows_from(&playlist);
+                tabs.notebook.set_current_page(Some(tabs.len() as u32 - 1));
+            }
+        });
+
+        let weak = Rc::downgrade(&tabs);
+        close_item.connect_activate(move |_| {
+            if let Some((tabs, playlist)) = menu_target(&weak) {
+                tabs.close(&playlist);
+            }
+        });
+        tabs
+    }
+
+    pub fn view(&self) -> &Notebook {
+        &self.notebook
+    }
+
+    /// Calls `added` with every playlist opened from now on, to connect its events.
+    pub fn connect_added<F: Fn(&Rc<Playlist>) + 'static>(&self, added: F) {
+        self.added.borrow_mut().push(Box::new(added));
+    }
+
+    /// Opens an empty playlist named `name` in a new tab after the others.
+    pub fn add(self: &Rc<Self>, name: &str) -> Rc<Playlist> {
+        let layout = self.config.borrow().columns.clone();
+        let playlist = Rc::new(Playlist::new(self.state.clone(), self.queue.clone(), self.player.clone(), &layout));
+
+        let label = Label::new(name);
+        let event_box = EventBox::new();
+        event_box.add(&label);
+        event_box.show_all();
+        {
+            let tabs = Rc::downgrade(self);
+            let playlist = Rc::downgrade(&playlist);
+            event_box.connect_button_press_event(move |_, event| {
+                if event.get_button() != 3 {
+                    return Inhibit(false);
+                }
+                if let Some(tabs) = tabs.upgrade() {
+                    *tabs.menu_playlist.borrow_mut() = playlist.clone();
+                    tabs.menu.popup_easy(event.get_button(), event.get_time());
+                }
+                Inhibit(true)
+            });
+        }
+
+        playlist.view().show_all();
+        self.notebook.append_page(playlist.view(), Some(&event_box));
+        self.tabs.borrow_mut().push(Tab {
+            label,
+            name: RefCell::new(name.to_string()),
+            playlist: playlist.clone(),
+        });
+        if self.playing.borrow().upgrade().is_none() {
+            *self.playing.borrow_mut() = Rc::downgrade(&playlist);
+        }
+        self.update_labels();
+        for added in self.added.borrow().iter() {
+            added(&playlist);
+        }
+        playlist
+    }
+
+    fn index_of(&self, playlist: &Rc<Playlist>) -> Option<usize> {
+        self.tabs.borrow().iter().position(|tab| Rc::ptr_eq(&tab.playlist, playlist))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tabs.borrow().len()
+    }
+
+    /// The names and playlists of the tabs, in order.
+    pub fn playlists(&self) -> Vec<(String, Rc<Playlist>)> {
+        self.tabs.borrow().iter()
+            .map(|tab| (tab.name.borrow().clone(), tab.playlist.clone()))
+            .collect()
+    }
+
+    pub fn name_of(&self, playlist: &Rc<Playlist>) -> Option<String> {
+        let index = self.index_of(playlist)?;
+        Some(self.tabs.borrow()[index].name.borrow().clone())
+    }
+
+    pub fn rename(&self, playlist: &Rc<Playlist>, name: &str) {
+        if let Some(index) = self.index_of(playlist) {
+            *self.tabs.borrow()[index].name.borrow_mut() = name.to_string();
+            self.update_labels();
+        }
+    }
+
+    /// Closes the tab of `playlist`, stopping it if it plays. The last tab gets replaced by
+    /// an empty one.
+    pub fn close(self: &Rc<Self>, playlist: &Rc<Playlist>) {
+        let index = match self.index_of(playlist) {
+            Some(index) => index,
+            None => return,
+        };
+        if self.is_playing(playlist) {
+            playlist.stop();
+        }
+        self.tabs.borrow_mut().remove(index);
+        self.notebook.remove_page(Some(index as u32));
+        if self.len() == 0 {
+            self.add(DEFAULT_NAME);
+        }
+        if self.playing.borrow().upgrade().is_none() {
+            let first = self.tabs.borrow()[0].playlist.clone();
+            *self.playing.borrow_mut() = Rc::downgrade(&first);
+        }
+        self.update_labels();
+    }
+
+    /// The shown playlist, which edits and imports apply to.
+    pub fn current(&self) -> Rc<Playlist> {
+        let tabs = self.tabs.borrow();
+        let index = self.notebook.get_current_page().unwrap_or(0) as usize;
+        tabs.get(index).unwrap_or(&tabs[0]).playlist.clone()
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.notebook.get_current_page().unwrap_or(0) as usize
+    }
+
+    pub fn set_current_index(&self, index: usize) {
+        if index < self.len() {
+            self.notebook.set_current_page(Some(index as u32));
+        }
+    }
+
+    /// The playlist of the playing, or last played, track.
+    pub fn playing(&self) -> Rc<Playlist> {
+        self.playing.borrow().upgrade().unwrap_or_else(|| self.current())
+    }
+
+    pub fn playing_index(&self) -> Option<usize> {
+        self.index_of(&self.playing.borrow().upgrade()?)
+    }
+
+    pub fn is_playing(&self, playlist: &Rc<Playlist>) -> bool {
+        self.playing.borrow().upgrade().map(|playing| Rc::ptr_eq(&playing, playlist)).unwrap_or(false)
+    }
+
+    /// Makes `playlist` the playing one once it started a track, releasing the previous one.
+    pub fn set_playing(&self, playlist: &Rc<Playlist>) {
+        if self.is_playing(playlist) {
+            return;
+        }
+        if let Some(previous) = self.playing.replace(Rc::downgrade(playlist)).upgrade() {
+            previous.release();
+        }
+        self.update_labels();
+    }
+
+    fn update_labels(&self) {
+        let playing = self.playing.borrow().upgrade();
+        for tab in self.tabs.borrow().iter() {
+            let is_playing = playing.as_ref().map(|playing| Rc::ptr_eq(playing, &tab.playlist)).unwrap_or(false);
+            let prefix = if is_playing && self.len() > 1 { PLAYING_PREFIX } else { "" };
+            tab.label.set_text(&format!("{}{}", prefix, tab.name.borrow()));
+        }
+    }
+}
+
+fn menu_target(tabs: &Weak<PlaylistTabs>) -> Option<(Rc<PlaylistTabs>, Rc<Playlist>)> {
+    let tabs = tabs.upgrade()?;
+    let playlist = tabs.menu_playlist.borrow().upgrade()?;
+    Some((tabs, playlist))
+}
+
+fn show_rename_dialog(parent: &ApplicationWindow, name: &str) -> Option<String> {
+    let dialog = Dialog::new_with_buttons(Some("Rename playlist"), Some(parent), DialogFlags::MODAL,
+                                          &[("Cancel", GTK_RESPONSE_CANCEL), ("Rename", GTK_RESPONSE_ACCEPT)]);
+    dialog.set_default_response(GTK_RESPONSE_ACCEPT);
+    let content = gtk::Box::new(Vertical, 5);
+    content.set_property_margin(10);
+    dialog.get_content_area().add(&content);
+
+    let name_entry = Entry::new();
+    name_entry.set_text(name);
+    name_entry.set_activates_default(true);
+    content.add(&name_entry);
+
+    dialog.show_all();
+    let mut result = None;
+    if dialog.run() == GTK_RESPONSE_ACCEPT {
+        let name = name_entry.get_text().unwrap_or_default();
+        if !name.trim().is_empty() {
+            result = Some(name);
+        }
+    }
+
+    dialog.destroy();
+    result
+}
@@ -0,0 +1,347 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use gtk::{
+    ApplicationWindow,
+    CheckButton,
+    ContainerExt,
+    Dialog,
+    DialogExt,
+    DialogFlags,
+    Entry,
+    EntryExt,
+    FileChooserAction,
+    FileChooserButton,
+    FileChooserExt,
+    Grid,
+    GridExt,
+    Label,
+    ToggleButtonExt,
+    WidgetExt,
+};
+
+use gtk::Align;
+
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use id3::frame::{Comment, Picture, PictureType};
+use id3::{ErrorKind, Tag, Version};
+
+use crate::cover::read_picture;
+use crate::metadata::id3v2_year;
+use crate::rating::set_rating;
+
+const COMMENT_LANGUAGE: &str = "eng";
+
+#[derive(Clone, Copy, PartialEq)]
+enum TagField {
+    Title,
+    Artist,
+    AlbumArtist,
+    Album,
+    Genre,
+    Year,
+    Track,
+    TotalTracks,
+    Disc,
+    Comment,
+}
+
+const TAG_FIELDS: &[(TagField, &str)] = &[
+    (TagField::Title, "Title"),
+    (TagField::Artist, "Artist"),
+    (TagField::AlbumArtist, "Album artist"),
+    (TagField::Album, "Album"),
+    (TagField::Genre, "Genre"),
+    (TagField::Year, "Year"),
+    (TagField::Track, "Track"),
+    (TagField::TotalTracks, "Total tracks"),
+    (TagField::Disc, "Disc"),
+    (TagField::Comment, "Comment"),
+];
+
+fn read_field(tag: &Tag, field: TagField) -> String {
+    let number = |value: Option<u32>| value.map(|value| value.to_string()).unwrap_or_default();
+    match field {
+        TagField::Title => tag.title().unwrap_or_default().to_string(),
+        TagField::Artist => tag.artist().unwrap_or_default().to_string(),
+        TagField::AlbumArtist => tag.album_artist().unwrap_or_default().to_string(),
+        TagField::Album => tag.album().unwrap_or_default().to_string(),
+        TagField::Genre => tag.genre().unwrap_or_default().to_string(),
+        TagField::Year => id3v2_year(tag).map(|year| year.to_string()).unwrap_or_default(),
+        TagField::Track => number(tag.track()),
+        TagField::TotalTracks => number(tag.total_tracks()),
+        TagField::Disc => number(tag.disc()),
+        TagField::Comment => tag.comments()
+            .find(|comment| comment.description.is_empty())
+            .map(|comment| comment.text.clone())
+            .unwrap_or_default(),
+    }
+}
+
+/// Sets `field` to `value`, an empty or, for numbers, unparsable value removing it. The
+/// year goes in the frame of the `version` the tag gets written in.
+fn write_field(tag: &mut Tag, field: TagField, value: &str, version: Version) {
+    let value = value.trim();
+    let number = value.parse::<u32>().ok();
+    match field {
+        TagField::Title if value.is_empty() => tag.remove_title(),
+        TagField::Title => tag.set_title(value),
+        TagField::Artist if value.is_empty() => tag.remove_artist(),
+        TagField::Artist => tag.set_artist(value),
+        TagField::AlbumArtist if value.is_empty() => tag.remove_album_artist(),
+        TagField::AlbumArtist => tag.set_album_artist(value),
+        TagField::Album if value.is_empty() => tag.remove_album(),
+        TagField::Album => tag.set_album(value),
+        TagField::Genre if value.is_empty() => tag.remove_genre(),
+        TagField::Genre => tag.set_genre(value),
+        TagField::Year => {
+            tag.remove("TYER");
+            tag.remove("TDRC");
+            match value.parse::<i32>() {
+                Ok(year) if version == Version::Id3v24 => tag.set_text("TDRC", format!("{:04}", year)),
+                Ok(year) => tag.set_year(year),
+                Err(_) => (),
+            }
+        },
+        TagField::Track => match number {
+            Some(track) => tag.set_track(track),
+            None => tag.remove_track(),
+        },
+        TagField::TotalTracks => match number {
+            Some(total_tracks) => tag.set_total_tracks(total_tracks),
+            None => tag.remove_total_tracks(),
+        },
+        TagField::Disc => match number {
+            Some(disc) => tag.set_disc(disc),
+            None => tag.remove_disc(),
+        },
+        TagField::Comment => {
+            tag.remove_comment(Some(""), None);
+            if !value.is_empty() {
+                tag.add_comment(Comment {
+                    lang: COMMENT_LANGUAGE.to_string(),
+                    description: String::new(),
+                    text: value.to_string(),
+                });
+            }
+        },
+    }
+}
+
+enum CoverChange {
+    Set(Picture),
+    Remove,
+}
+
+/// The edits made in the tag dialog, applied to every edited file.
+pub struct TagChanges {
+    fields: Vec<(TagField, String)>,
+    cover: Option<CoverChange>,
+    /// The stars to rate the files with, 0 to clear their rating.
+    rating: Option<u8>,
+}
+
+impl TagChanges {
+    /// Changes that only set the front cover.
+    pub fn cover(picture: Picture) -> Self {
+        TagChanges {
+            fields: Vec::new(),
+            cover: Some(CoverChange::Set(picture)),
+            rating: None,
+        }
+    }
+
+    /// Changes that only rate the files, see `set_rating`.
+    pub fn rating(stars: u8) -> Self {
+        TagChanges {
+            fields: Vec::new(),
+            cover: None,
+            rating: Some(stars),
+        }
+    }
+
+    fn apply(&self, tag: &mut Tag, version: Version) {
+        for (field, value) in &self.fields {
+            write_field(tag, *field, value, version);
+        }
+        match self.cover {
+            Some(CoverChange::Set(ref picture)) => {
+                tag.remove_picture_by_type(PictureType::CoverFront);
+                tag.add_picture(picture.clone());
+            },
+            Some(CoverChange::Remove) => tag.remove_picture_by_type(PictureType::CoverFront),
+            None => (),
+        }
+        if let Some(stars) = self.rating {
+            set_rating(tag, stars);
+        }
+    }
+}
+
+/// A file written by a tag edit: its tag before, if it had one, and after.
+struct EditedFile {
+    path: PathBuf,
+    before: Option<Tag>,
+    after: Tag,
+    /// The ID3v2 version of the file, which both tags are written in.
+    version: Version,
+}
+
+/// The tags of files before and after a write, so that it can be undone and redone.
+pub struct TagEdit {
+    files: Vec<EditedFile>,
+}
+
+impl TagEdit {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Restores the tags replaced by the write, returning the restored files and the errors.
+    pub fn undo(&self) -> (Vec<PathBuf>, Vec<String>) {
+        self.restore(|file| file.before.as_ref())
+    }
+
+    /// Writes the tags again after an undo, returning the rewritten files and the errors.
+    pub fn redo(&self) -> (Vec<PathBuf>, Vec<String>) {
+        self.restore(|file| Some(&file.after))
+    }
+
+    fn restore<F: Fn(&EditedFile) -> Option<&Tag>>(&self, tag_of: F) -> (Vec<PathBuf>, Vec<String>) {
+        let mut restored = Vec::new();
+        let mut errors = Vec::new();
+        for file in &self.files {
+            let path = &file.path;
+            let result = match tag_of(file) {
+                Some(tag) => tag.write_to_path(path, file.version).map_err(|error| error.to_string()),
+                None => OpenOptions::new().read(true).write(true).open(path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|mut file| Tag::remove_from(&mut file).map_err(|error| error.to_string()))
+                    .map(|_| ()),
+            };
+            match result {
+                Ok(()) => restored.push(path.clone()),
+                Err(error) => errors.push(format!("{}: {}", path.display(), error)),
+            }
+        }
+        (restored, errors)
+    }
+}
+
+/// The version of the ID3v2 tag at the start of `path`, ID3v2.4 for a file without one.
+fn tag_version(path: &Path) -> Version {
+    let mut header = [0; 4];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    match (read, &header[..3], header[3]) {
+        (Ok(()), b"ID3", 2) => Version::Id3v22,
+        (Ok(()), b"ID3", 3) => Version::Id3v23,
+        _ => Version::Id3v24,
+    }
+}
+
+/// Applies `changes` to `paths`, keeping the tag version of each file, and returns the
+/// edit, to undo it, and the errors, one line per failed file. A tag that cannot be read
+/// is left alone rather than replaced by one holding only the changes.
+pub fn write_tags(paths: &[PathBuf], changes: &TagChanges) -> (TagEdit, Vec<String>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        let before = match Tag::read_from_path(path) {
+            Ok(tag) => Some(tag),
+            Err(ref error) if matches!(error.kind, ErrorKind::NoTag) => None,
+            Err(error) => {
+                errors.push(format!("{}: cannot read the tag: {}", path.display(), error));
+                continue;
+            },
+        };
+        let version = tag_version(path);
+        let mut after = before.clone().unwrap_or_default();
+        changes.apply(&mut after, version);
+        match after.write_to_path(path, version) {
+            Ok(()) => files.push(EditedFile {
+                path: path.clone(),
+                before,
+                after,
+                version,
+            }),
+            Err(error) => errors.push(format!("{}: {}", path.display(), error)),
+        }
+    }
+    (TagEdit { files }, errors)
+}
+
+/// Shows the tags of `paths` for editing. With several files, fields whose values differ
+/// start empty and are only written if something is typed in them.
+pub fn show_tag_dialog(parent: &ApplicationWindow, paths: &[PathBuf]) -> Option<TagChanges> {
+    let tags: Vec<Tag> = paths.iter()
+        .map(|path| Tag::read_from_path(path).unwrap_or_default())
+        .collect();
+    let title = if paths.len() == 1 {
+        "Edit tags".to_string()
+    } else {
+        format!("Edit tags of {} files", paths.len())
+    };
+
+    let dialog = Dialog::new_with_buttons(Some(title.as_str()), Some(parent), DialogFlags::MODAL,
+                                          &[("Cancel", GTK_RESPONSE_CANCEL), ("Save", GTK_RESPONSE_ACCEPT)]);
+    let grid = Grid::new();
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(10);
+    grid.set_property_margin(10);
+    dialog.get_content_area().add(&grid);
+
+    let mut entries = Vec::new();
+    for (row, (field, label)) in TAG_FIELDS.iter().enumerate() {
+        let label = Label::new(*label);
+        label.set_halign(Align::End);
+        grid.attach(&label, 0, row as i32, 1, 1);
+
+        let mut values = tags.iter().map(|tag| read_field(tag, *field));
+        let first = values.next().unwrap_or_default();
+        let initial = if values.all(|value| value == first) { first } else { String::new() };
+        let entry = Entry::new();
+        entry.set_hexpand(true);
+        if initial.is_empty() && paths.len() > 1 {
+            entry.set_placeholder_text("(keep values)");
+        }
+        entry.set_text(&initial);
+        grid.attach(&entry, 1, row as i32, 1, 1);
+        entries.push((*field, entry, initial));
+    }
+
+    let cover_row = TAG_FIELDS.len() as i32;
+    let cover_label = Label::new("Cover art");
+    cover_label.set_halign(Align::End);
+    grid.attach(&cover_label, 0, cover_row, 1, 1);
+    let cover_button = FileChooserButton::new("Choose a cover image", FileChooserAction::Open);
+    grid.attach(&cover_button, 1, cover_row, 1, 1);
+    let remove_cover_button = CheckButton::new_with_label("Remove the cover art");
+    grid.attach(&remove_cover_button, 1, cover_row + 1, 1, 1);
+
+    dialog.show_all();
+    let mut changes = None;
+    if dialog.run() == GTK_RESPONSE_ACCEPT {
+        let fields = entries.iter()
+            .map(|(field, entry, initial)| (*field, entry.get_text().unwrap_or_default(), initial))
+            .filter(|(_, text, initial)| text != *initial)
+            .map(|(field, text, _)| (field, text))
+            .collect();
+        let cover = if remove_cover_button.get_active() {
+            Some(CoverChange::Remove)
+        } else {
+            cover_button.get_filename()
+                .and_then(|path| read_picture(&path))
+                .map(CoverChange::Set)
+        };
+        changes = Some(TagChanges {
+            fields,
+            cover,
+            rating: None,
+        });
+    }
+
+    dialog.destroy();
+    changes
+}
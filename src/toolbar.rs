@@ -5,33 +5,41 @@ use gtk::{
     SeparatorToolItem,
     Toolbar,
     ToolButton,
+    ToggleToolButton,
     Image,
 };
 
 use gtk::{
     ImageExt,
+    ToolButtonExt,
 };
 
 use crate::playlist::Playlist;
 
-use gtk::{FileChooserAction, FileChooserDialog, FileFilter};
+use gtk::{ButtonsType, DialogFlags, FileChooserAction, FileChooserDialog, FileFilter, MessageDialog, MessageType};
 use gtk::{FileFilterExt, FileChooserExt, DialogExt, WidgetExt};
 use std::path::PathBuf;
 
 use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
 
 const PLAY_STOCK: &str = "gtk-media-play";
-const RESPONSE_ACCEPT: i32 = GTK_RESPONSE_ACCEPT as i32;
-const RESPONSE_CANCEL: i32 = GTK_RESPONSE_CANCEL as i32;
+const RESPONSE_ACCEPT: i32 = GTK_RESPONSE_ACCEPT;
+const RESPONSE_CANCEL: i32 = GTK_RESPONSE_CANCEL;
 
 pub struct MusicToolbar {
+    pub add_folder_button: ToolButton,
+    pub audiobook_button: ToggleToolButton,
     pub open_button: ToolButton,
     pub next_button: ToolButton,
+    pub next_chapter_button: ToolButton,
     pub play_button: ToolButton,
     pub previous_button: ToolButton,
+    pub previous_chapter_button: ToolButton,
     pub quit_button: ToolButton,
     pub remove_button: ToolButton,
+    pub repeat_button: ToggleToolButton,
     pub save_button: ToolButton,
+    pub shuffle_button: ToggleToolButton,
     pub stop_button: ToolButton,
     pub toolbar: Toolbar,
 }
@@ -42,6 +50,9 @@ impl MusicToolbar {
         let open_button = ToolButton::new_from_stock("gtk-open");
         toolbar.add(&open_button);
 
+        let add_folder_button = ToolButton::new_from_stock("gtk-directory");
+        toolbar.add(&add_folder_button);
+
         let save_button = ToolButton::new_from_stock("gtk-save");
         toolbar.add(&save_button);
 
@@ -59,6 +70,29 @@ impl MusicToolbar {
         let next_button = ToolButton::new_from_stock("gtk-media-next");
         toolbar.add(&next_button);
 
+        let previous_chapter_button = ToolButton::new_from_stock("gtk-media-rewind");
+        previous_chapter_button.set_tooltip_text("Previous chapter");
+        toolbar.add(&previous_chapter_button);
+
+        let next_chapter_button = ToolButton::new_from_stock("gtk-media-forward");
+        next_chapter_button.set_tooltip_text("Next chapter");
+        toolbar.add(&next_chapter_button);
+
+        let shuffle_button = ToggleToolButton::new();
+        shuffle_button.set_icon_name("media-playlist-shuffle");
+        shuffle_button.set_tooltip_text("Shuffle");
+        toolbar.add(&shuffle_button);
+
+        let repeat_button = ToggleToolButton::new();
+        repeat_button.set_icon_name("media-playlist-repeat");
+        repeat_button.set_tooltip_text("Repeat");
+        toolbar.add(&repeat_button);
+
+        let audiobook_button = ToggleToolButton::new();
+        audiobook_button.set_icon_name("user-bookmarks");
+        audiobook_button.set_tooltip_text("Audiobook mode");
+        toolbar.add(&audiobook_button);
+
         toolbar.add(&SeparatorToolItem::new());
 
         let remove_button = ToolButton::new_from_stock("gtk-remove");
@@ -70,13 +104,19 @@ impl MusicToolbar {
         toolbar.add(&quit_button);
 
         MusicToolbar{
+            add_folder_button,
+            audiobook_button,
             open_button,
             next_button,
+            next_chapter_button,
             play_button,
             previous_button,
+            previous_chapter_button,
             quit_button,
             remove_button,
+            repeat_button,
             save_button,
+            shuffle_button,
             stop_button,
             toolbar,
 
@@ -88,10 +128,11 @@ impl MusicToolbar {
     }
 }
 
-pub fn show_open_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
-    let mut file = None;
+pub fn show_open_dialog(parent: &ApplicationWindow) -> Vec<PathBuf> {
+    let mut files = Vec::new();
 
-    let dialog = FileChooserDialog::new(Some("Select an MP3 Audio file"), Some(parent), FileChooserAction::Open);
+    let dialog = FileChooserDialog::new(Some("Select MP3 Audio files"), Some(parent), FileChooserAction::Open);
+    dialog.set_select_multiple(true);
     let filter = FileFilter::new();
     filter.add_mime_type("audio/mp3");
     filter.set_name("MP3 audio file");
@@ -107,12 +148,26 @@ pub fn show_open_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
 
     let result = dialog.run();
     if result == RESPONSE_ACCEPT {
-        file = dialog.get_filename();
+        files = dialog.get_filenames();
     }
 
     dialog.destroy();
     
-    file
+    files
+}
+
+pub fn show_folder_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
+    let mut folder = None;
+    let dialog = FileChooserDialog::new(Some("Select a music folder"), Some(parent), FileChooserAction::SelectFolder);
+    dialog.add_button("Cancel", RESPONSE_CANCEL);
+    dialog.add_button("Add", RESPONSE_ACCEPT);
+    let result = dialog.run();
+    if result == RESPONSE_ACCEPT {
+        folder = dialog.get_filename();
+    }
+
+    dialog.destroy();
+    folder
 }
 
 pub fn show_save_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
@@ -134,6 +189,31 @@ pub fn show_save_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
     file
 }
 
+pub fn show_image_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
+    let mut file = None;
+    let dialog = FileChooserDialog::new(Some("Select a cover image"), Some(parent), FileChooserAction::Open);
+    let filter = FileFilter::new();
+    filter.add_mime_type("image/jpeg");
+    filter.add_mime_type("image/png");
+    filter.set_name("JPEG or PNG image");
+    dialog.add_filter(&filter);
+    dialog.add_button("Cancel", RESPONSE_CANCEL);
+    dialog.add_button("Set cover", RESPONSE_ACCEPT);
+    let result = dialog.run();
+    if result == RESPONSE_ACCEPT {
+        file = dialog.get_filename();
+    }
+
+    dialog.destroy();
+    file
+}
+
+pub fn show_error_dialog(parent: &ApplicationWindow, message: &str) {
+    let dialog = MessageDialog::new(Some(parent), DialogFlags::empty(), MessageType::Error, ButtonsType::Ok, message);
+    dialog.run();
+    dialog.destroy();
+}
+
 pub fn set_cover(cover: &Image, playlist: &Playlist) {
     cover.set_from_pixbuf(playlist.pixbuf().as_ref());
     cover.show();
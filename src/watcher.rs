@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crossbeam::sync::SegQueue;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::library::{content_hash, Library};
+use crate::scanner::{collect_files, is_supported};
+
+const EVENT_BUFFER_SIZE: usize = 4096;
+
+pub enum Change {
+    /// A file was added or rewritten, e.g. retagged.
+    Updated(PathBuf),
+    /// A file, or a folder with everything in it, is gone.
+    Removed(PathBuf),
+    /// A file or folder moved. Moves through an unwatched place are recognized by content.
+    Renamed(PathBuf, PathBuf),
+}
+
+/// Watches the music folders with inotify and keeps the library up to date, so that only
+/// the changed files are read again.
+///
+/// Each folder has its own thread, which updates the library before queuing the change
+/// for the GTK thread to apply to the playlist.
+#[derive(Clone)]
+pub struct Watcher {
+    changes: Arc<SegQueue<Change>>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Watcher {
+            changes: Arc::new(SegQueue::new()),
+        }
+    }
+
+    pub fn watch(&self, folder: PathBuf) {
+        let changes = self.changes.clone();
+        thread::spawn(move || watch_folder(&folder, &changes));
+    }
+
+    pub fn next_change(&self) -> Option<Change> {
+        self.changes.try_pop()
+    }
+}
+
+/// Where `path` ends up when `from`, the path itself or one of its folders, moves to `to`.
+pub fn moved_path(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(from).ok()?;
+    if relative.as_os_str().is_empty() {
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(relative))
+    }
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE | WatchMask::CLOSE_WRITE | WatchMask::DELETE | WatchMask::MOVE
+}
+
+fn add_watches(inotify: &mut Inotify, dir: &Path, dirs: &mut HashMap<WatchDescriptor, PathBuf>) {
+    if let Ok(descriptor) = inotify.add_watch(dir, watch_mask()) {
+        dirs.insert(descriptor, dir.to_path_buf());
+    }
+    if let Ok(entries) = dir.read_dir() {
+        for entry in entries.filter_map(Result::ok) {
+            if entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false) {
+                add_watches(inotify, &entry.path(), dirs);
+            }
+        }
+    }
+}
+
+/// Lists the supported files of a file or folder that just appeared.
+fn added_files(path: PathBuf) -> Vec<PathBuf> {
+    if path.is_dir() {
+        let mut files = Vec::new();
+        collect_files(&path, &mut files);
+        files
+    } else if is_supported(&path) {
+        vec![path]
+    } else {
+        Vec::new()
+    }
+}
+
+fn watch_folder(folder: &Path, changes: &SegQueue<Change>) {
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(_) => return,
+    };
+    let library = Library::open();
+    let mut dirs = HashMap::new();
+    add_watches(&mut inotify, folder, &mut dirs);
+
+    // Content hashes of removed tracks, so that a file moved out and back in is seen as a move.
+    let mut vanished: HashMap<i64, PathBuf> = HashMap::new();
+    let mut buffer = [0; EVENT_BUFFER_SIZE];
+    loop {
+        let events = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events,
+            Err(_) => return,
+        };
+
+        let mut batch = Vec::new();
+        let mut moved_from = HashMap::new();
+        let mut new_dirs = Vec::new();
+        for event in events {
+            if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let path = match (dirs.get(&event.wd), event.name) {
+                (Some(dir), Some(name)) => dir.join(name),
+                _ => continue,
+            };
+            let is_dir = event.mask.contains(EventMask::ISDIR);
+
+            if event.mask.contains(EventMask::MOVED_FROM) {
+                moved_from.insert(event.cookie, path);
+            } else if event.mask.contains(EventMask::MOVED_TO) {
+                if is_dir {
+                    new_dirs.push(path.clone());
+                }
+                match moved_from.remove(&event.cookie) {
+                    Some(from) => batch.push(Change::Renamed(from, path)),
+                    None => batch.extend(added_files(path).into_iter().map(Change::Updated)),
+                }
+            } else if event.mask.contains(EventMask::CREATE) {
+                // Files are read once written, but a new folder may already have content.
+                if is_dir {
+                    new_dirs.push(path.clone());
+                    batch.extend(added_files(path).into_iter().map(Change::Updated));
+                }
+            } else if event.mask.contains(EventMask::CLOSE_WRITE) {
+                if is_supported(&path) {
+                    batch.push(Change::Updated(path));
+                }
+            } else if event.mask.contains(EventMask::DELETE) {
+                batch.push(Change::Removed(path));
+            }
+        }
+        // The other half of these moves is outside the watched folder.
+        batch.extend(moved_from.into_values().map(Change::Removed));
+
+        for change in batch {
+            let change = match change {
+                Change::Updated(path) => {
+                    match content_hash(&path).and_then(|hash| vanished.remove(&hash)) {
+                        Some(from) => {
+                            let _ = library.update_file(&path);
+                            Change::Renamed(from, path)
+                        },
+                        None => {
+                            let _ = library.update_file(&path);
+                            Change::Updated(path)
+                        },
+                    }
+                },
+                Change::Removed(path) => {
+                    vanished.extend(library.remove_path(&path));
+                    Change::Removed(path)
+                },
+                Change::Renamed(from, to) => {
+                    let _ = library.rename_path(&from, &to);
+                    for dir in dirs.values_mut() {
+                        if let Some(moved) = moved_path(dir, &from, &to) {
+                            *dir = moved;
+                        }
+                    }
+                    Change::Renamed(from, to)
+                },
+            };
+            changes.push(change);
+        }
+        for dir in new_dirs {
+            add_watches(&mut inotify, &dir, &mut dirs);
+        }
+    }
+}
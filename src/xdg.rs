@@ -0,0 +1,36 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const APP_NAME: &str = "rusic";
+
+/// Resolves an XDG base directory, falling back to `~/<fallback>` when the variable is unset
+/// or not absolute as the specification requires, and creates rusic's subdirectory in it.
+fn app_dir(variable: &str, fallback: &str) -> PathBuf {
+    let base = env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| {
+            let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+            home.join(fallback)
+        });
+    let dir = base.join(APP_NAME);
+    let _ = fs::create_dir_all(&dir);
+    dir
+}
+
+pub fn data_file(name: &str) -> PathBuf {
+    app_dir("XDG_DATA_HOME", ".local/share").join(name)
+}
+
+pub fn cache_file(name: &str) -> PathBuf {
+    app_dir("XDG_CACHE_HOME", ".cache").join(name)
+}
+
+pub fn config_file(name: &str) -> PathBuf {
+    app_dir("XDG_CONFIG_HOME", ".config").join(name)
+}
+
+pub fn state_file(name: &str) -> PathBuf {
+    app_dir("XDG_STATE_HOME", ".local/state").join(name)
+}